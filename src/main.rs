@@ -15,7 +15,10 @@ fn main() {
         ..Default::default()
     };
     //let filename = &String::from("/Users/thomas/code/nes_tests/nmi_interrupt/build/build.nes");
-    let filename = &String::from("/Users/thomas/Downloads/donkeykong.nes");
+    // ROM or NSF from the command line, e.g. `rustynes game.nes` or `rustynes music.nsf`
+    let filename = &std::env::args()
+        .nth(1)
+        .unwrap_or(String::from("/Users/thomas/Downloads/donkeykong.nes"));
     let debug_file = &String::from("/Users/thomas/code/nes_tests/nmi_interrupt/build/build.dbg");
     let filename_owned = filename.clone();
     //let debug_file_owned = debug_file.clone();
@@ -1,8 +1,22 @@
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use crate::nes::apu_debug::{ApuScope, ChannelDebugState};
+use crate::nes::audio_filter::OutputFilter;
+use crate::nes::audio_recorder::AudioRecorder;
+use crate::nes::audio_sync::AudioStats;
+use crate::nes::blip::BlipBuffer;
+use crate::nes::expansion_audio::ExpansionAudio;
+use crate::nes::ring_buffer::AudioRingBuffer;
+use crate::nes::wav::WavSampleFormat;
+use std::sync::Arc;
 
-const CPU_FREQUENCY: f32 = 1_789_773.0; // NES CPU clock frequency in Hz
-const SAMPLE_RATE: f32 = 44100.0; // Standard audio sample rate
+pub const CPU_FREQUENCY: f64 = 1_789_773.0; // NES CPU clock frequency in Hz
+pub const CPU_FREQUENCY_PAL: f64 = 1_662_607.0;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+// Samples past this are dropped, covers the maximum latency at 96kHz
+const MAX_BUFFERED_SAMPLES: usize = 16384;
+
+// CPU cycles between flushes of the band-limited buffer into the audio buffer (~1.1ms)
+const BLIP_FRAME_CLOCKS: u32 = 2048;
 
 // Square wave duty cycle patterns (8 steps each)
 const DUTY_CYCLES: [[u8; 8]; 4] = [
@@ -12,14 +26,141 @@ const DUTY_CYCLES: [[u8; 8]; 4] = [
     [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
 ];
 
-pub struct PulseChannel {
+// Envelope generator shared by the pulse and noise channels
+pub struct Envelope {
+    loop_flag: bool, // same bit as the length counter halt flag
+    constant_volume: bool,
+    volume: u8, // also envelope period
+    start: bool,
+    divider: u8,
+    decay_level: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Self {
+            loop_flag: false,
+            constant_volume: false,
+            volume: 0,
+            start: false,
+            divider: 0,
+            decay_level: 0,
+        }
+    }
+
+    // --LC VVVV part of $4000/$4004/$400C
+    pub fn write_control(&mut self, value: u8) {
+        self.loop_flag = (value & 0x20) != 0;
+        self.constant_volume = (value & 0x10) != 0;
+        self.volume = value & 0x0F;
+    }
+
+    // Writing the length counter load register restarts the envelope on the next quarter frame
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay_level = 15;
+            self.divider = self.volume;
+            return;
+        }
+
+        if self.divider == 0 {
+            self.divider = self.volume;
+
+            if self.decay_level > 0 {
+                self.decay_level -= 1;
+            } else if self.loop_flag {
+                self.decay_level = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay_level
+        }
+    }
+}
+
+// Length counter shared by the pulse, triangle and noise channels
+pub struct LengthCounter {
     enabled: bool,
+    halt: bool,
+    counter: u8,
+
+    // Register writes land after the frame counter clock of the same cycle, see apply_pending_writes
+    pending_halt: bool,
+    pending_reload: u8,
+    counter_before_reload: u8,
+}
+
+impl LengthCounter {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            halt: false,
+            counter: 0,
+            pending_halt: false,
+            pending_reload: 0,
+            counter_before_reload: 0,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halt(&mut self, halt: bool) {
+        self.pending_halt = halt;
+    }
+
+    // LLLL L--- part of $4003/$4007/$400B/$400F, only loads when the channel is enabled
+    pub fn load(&mut self, value: u8) {
+        if self.enabled {
+            let length_index = (value >> 3) & 0x1F;
+            self.pending_reload = LENGTH_TABLE[length_index as usize];
+            self.counter_before_reload = self.counter;
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    // A halt written on the same cycle as a length clock only takes effect after the clock, and a
+    // reload on that cycle is ignored if the clock decremented a non-zero counter
+    pub fn apply_pending_writes(&mut self) {
+        if self.pending_reload != 0 {
+            if self.counter == self.counter_before_reload {
+                self.counter = self.pending_reload;
+            }
+            self.pending_reload = 0;
+        }
+        self.halt = self.pending_halt;
+    }
 
+    pub fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+pub struct PulseChannel {
     // $4000/$4004 - Duty, envelope
     duty: u8,
-    length_counter_halt: bool, // also acts as envelope loop flag
-    constant_volume: bool,
-    volume: u8, // also envelope period
+    envelope: Envelope,
 
     // $4001/$4005 - Sweep
     sweep_enabled: bool,
@@ -32,13 +173,10 @@ pub struct PulseChannel {
     timer: u16,
     timer_counter: u16,
 
-    length_counter: u8,
+    length: LengthCounter,
 
     // Internal state
     sequence_position: u8,
-    envelope_counter: u8,
-    envelope_divider: u8,
-    envelope_volume: u8,
 
     // Sweep internal state
     sweep_divider: u8,
@@ -48,22 +186,16 @@ pub struct PulseChannel {
 impl PulseChannel {
     pub fn new() -> Self {
         Self {
-            enabled: false,
             duty: 0,
-            length_counter_halt: false,
-            constant_volume: false,
-            volume: 0,
+            envelope: Envelope::new(),
             sweep_enabled: false,
             sweep_period: 0,
             sweep_negate: false,
             sweep_shift: 0,
             timer: 0,
             timer_counter: 0,
-            length_counter: 0,
+            length: LengthCounter::new(),
             sequence_position: 0,
-            envelope_counter: 0,
-            envelope_divider: 0,
-            envelope_volume: 0,
             sweep_divider: 0,
             sweep_reload: false,
         }
@@ -74,9 +206,8 @@ impl PulseChannel {
             0 => {
                 // $4000/$4004 - DDLC VVVV
                 self.duty = (value >> 6) & 0x03;
-                self.length_counter_halt = (value & 0x20) != 0;
-                self.constant_volume = (value & 0x10) != 0;
-                self.volume = value & 0x0F;
+                self.length.set_halt((value & 0x20) != 0);
+                self.envelope.write_control(value);
             }
             1 => {
                 // $4001/$4005 - EPPP NSSS
@@ -94,29 +225,31 @@ impl PulseChannel {
                 // $4003/$4007 - LLLL LTTT
                 self.timer = (self.timer & 0x00FF) | (((value & 0x07) as u16) << 8);
 
-                // Load length counter if enabled
-                if self.enabled {
-                    let length_index = (value >> 3) & 0x1F;
-                    self.length_counter = LENGTH_TABLE[length_index as usize];
-                }
+                self.length.load(value);
 
                 // Reset sequence and envelope
                 self.sequence_position = 0;
-                self.envelope_counter = 15;
+                self.envelope.restart();
             }
             _ => {}
         }
     }
 
     pub fn set_enabled(&mut self, enabled: bool) {
-        self.enabled = enabled;
-        if !enabled {
-            self.length_counter = 0;
-        }
+        self.length.set_enabled(enabled);
     }
 
     pub fn is_active(&self) -> bool {
-        self.enabled && self.length_counter > 0 && self.timer >= 8 && !self.is_sweep_muted()
+        self.length.is_active() && self.timer >= 8 && !self.is_sweep_muted()
+    }
+
+    // For the MMC5 copies of the pulse channel, the APU handles its own length counters directly
+    pub fn length_active(&self) -> bool {
+        self.length.is_active()
+    }
+
+    pub fn apply_pending_writes(&mut self) {
+        self.length.apply_pending_writes();
     }
 
     pub fn clock(&mut self) {
@@ -129,29 +262,11 @@ impl PulseChannel {
     }
 
     pub fn clock_length_counter(&mut self) {
-        if !self.length_counter_halt && self.length_counter > 0 {
-            self.length_counter -= 1;
-        }
+        self.length.clock();
     }
 
     pub fn clock_envelope(&mut self) {
-        if self.envelope_divider == 0 {
-            self.envelope_divider = self.volume;
-
-            if self.envelope_counter > 0 {
-                self.envelope_counter -= 1;
-            } else if self.length_counter_halt {
-                self.envelope_counter = 15;
-            }
-        } else {
-            self.envelope_divider -= 1;
-        }
-
-        self.envelope_volume = if self.constant_volume {
-            self.volume
-        } else {
-            self.envelope_counter
-        };
+        self.envelope.clock();
     }
 
     pub fn clock_sweep(&mut self, is_pulse1: bool) {
@@ -202,20 +317,497 @@ impl PulseChannel {
         self.timer < 8 || target_period > 0x7FF
     }
 
+    pub fn debug_state(&self, cpu_frequency: f64) -> ChannelDebugState {
+        let sweep = if self.sweep_enabled {
+            format!(
+                "Sweep: period {}, shift {}{}",
+                self.sweep_period,
+                self.sweep_shift,
+                if self.sweep_negate { ", negate" } else { "" }
+            )
+        } else {
+            "Sweep: off".to_string()
+        };
+        let muted = if self.is_sweep_muted() { " (muted)" } else { "" };
+
+        ChannelDebugState {
+            enabled: self.length.is_active(),
+            duty: Some(self.duty),
+            period: self.timer,
+            frequency: (self.timer >= 8)
+                .then(|| (cpu_frequency / (16.0 * (self.timer as f64 + 1.0))) as f32),
+            volume: self.envelope.output(),
+            length_counter: self.length.counter,
+            detail: format!("{}{}", sweep, muted),
+        }
+    }
+
     pub fn output(&self) -> u8 {
         if !self.is_active() {
             return 0;
         }
+        self.sequencer_output()
+    }
 
+    // The MMC5 copies have no sweep unit, so short periods aren't muted like on the APU
+    pub fn mmc5_output(&self) -> u8 {
+        if !self.length.is_active() {
+            return 0;
+        }
+        self.sequencer_output()
+    }
+
+    fn sequencer_output(&self) -> u8 {
         let duty_pattern = DUTY_CYCLES[self.duty as usize];
         if duty_pattern[self.sequence_position as usize] == 1 {
-            self.envelope_volume
+            self.envelope.output()
         } else {
             0
         }
     }
 }
 
+// Triangle 32-step sequence (15 down to 0, then 0 up to 15)
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+pub struct TriangleChannel {
+    // $4008 - CRRR RRRR
+    control_flag: bool, // also acts as length counter halt
+    linear_counter_reload: u8,
+
+    // $400A/$400B - Timer, length counter load
+    timer: u16,
+    timer_counter: u16,
+
+    length: LengthCounter,
+
+    // Internal state
+    linear_counter: u8,
+    linear_counter_reload_flag: bool,
+    sequence_position: u8,
+}
+
+impl TriangleChannel {
+    pub fn new() -> Self {
+        Self {
+            control_flag: false,
+            linear_counter_reload: 0,
+            timer: 0,
+            timer_counter: 0,
+            length: LengthCounter::new(),
+            linear_counter: 0,
+            linear_counter_reload_flag: false,
+            sequence_position: 0,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u8, value: u8) {
+        match addr {
+            0 => {
+                // $4008 - CRRR RRRR
+                self.control_flag = (value & 0x80) != 0;
+                self.length.set_halt(self.control_flag);
+                self.linear_counter_reload = value & 0x7F;
+            }
+            2 => {
+                // $400A - Timer low 8 bits
+                self.timer = (self.timer & 0xFF00) | (value as u16);
+            }
+            3 => {
+                // $400B - LLLL LTTT
+                self.timer = (self.timer & 0x00FF) | (((value & 0x07) as u16) << 8);
+                self.length.load(value);
+                self.linear_counter_reload_flag = true;
+            }
+            _ => {} // $4009 is unused
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    // Unlike the pulse channels the triangle timer is clocked every CPU cycle
+    pub fn clock(&mut self) {
+        if self.timer_counter == 0 {
+            self.timer_counter = self.timer;
+
+            // Periods below 2 are ultrasonic, real hardware outputs a ~7.5 average that pops
+            // when it starts/stops, so just hold the current step instead
+            if self.linear_counter > 0 && self.length.is_active() && self.timer >= 2 {
+                self.sequence_position = (self.sequence_position + 1) % 32;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_counter_reload_flag {
+            self.linear_counter = self.linear_counter_reload;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control_flag {
+            self.linear_counter_reload_flag = false;
+        }
+    }
+
+    pub fn clock_length_counter(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn debug_state(&self, cpu_frequency: f64) -> ChannelDebugState {
+        ChannelDebugState {
+            enabled: self.length.is_active(),
+            duty: None,
+            period: self.timer,
+            frequency: (self.timer >= 2)
+                .then(|| (cpu_frequency / (32.0 * (self.timer as f64 + 1.0))) as f32),
+            volume: TRIANGLE_SEQUENCE[self.sequence_position as usize],
+            length_counter: self.length.counter,
+            detail: format!(
+                "Linear counter: {} (reload {})",
+                self.linear_counter, self.linear_counter_reload
+            ),
+        }
+    }
+
+    // Silencing the triangle just stops the sequencer, it keeps outputting the current step
+    pub fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.sequence_position as usize]
+    }
+}
+
+// Noise timer periods in CPU cycles, https://www.nesdev.org/wiki/APU_Noise
+const NOISE_PERIOD_TABLE_NTSC: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const NOISE_PERIOD_TABLE_PAL: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+pub struct NoiseChannel {
+    // $400C - --LC VVVV
+    envelope: Envelope,
+
+    // $400E - M--- PPPP
+    mode: bool, // short mode, feedback from bit 6 instead of bit 1
+    period_index: u8,
+    pal_timing: bool,
+
+    // $400F - LLLL L---
+    length: LengthCounter,
+
+    // Internal state
+    timer_counter: u16,
+    shift_register: u16, // 15-bit LFSR
+}
+
+impl NoiseChannel {
+    pub fn new() -> Self {
+        Self {
+            envelope: Envelope::new(),
+            mode: false,
+            period_index: 0,
+            pal_timing: false,
+            length: LengthCounter::new(),
+            timer_counter: 0,
+            shift_register: 1, // loaded with 1 on power up
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u8, value: u8) {
+        match addr {
+            0 => {
+                // $400C - --LC VVVV
+                self.length.set_halt((value & 0x20) != 0);
+                self.envelope.write_control(value);
+            }
+            2 => {
+                // $400E - M--- PPPP
+                self.mode = (value & 0x80) != 0;
+                self.period_index = value & 0x0F;
+            }
+            3 => {
+                // $400F - LLLL L---
+                self.length.load(value);
+                self.envelope.restart();
+            }
+            _ => {} // $400D is unused
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn set_pal_timing(&mut self, pal_timing: bool) {
+        self.pal_timing = pal_timing;
+    }
+
+    fn period(&self) -> u16 {
+        if self.pal_timing {
+            NOISE_PERIOD_TABLE_PAL[self.period_index as usize]
+        } else {
+            NOISE_PERIOD_TABLE_NTSC[self.period_index as usize]
+        }
+    }
+
+    // The period table is in CPU cycles so this is clocked every CPU cycle
+    pub fn clock(&mut self) {
+        if self.timer_counter == 0 {
+            self.timer_counter = self.period() - 1;
+
+            let feedback_bit = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> feedback_bit)) & 0x01;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_length_counter(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn debug_state(&self) -> ChannelDebugState {
+        ChannelDebugState {
+            enabled: self.length.is_active(),
+            duty: None,
+            period: self.period(),
+            frequency: None,
+            volume: self.envelope.output(),
+            length_counter: self.length.counter,
+            detail: format!(
+                "Mode: {}",
+                if self.mode {
+                    "short (93 step)"
+                } else {
+                    "long (32767 step)"
+                }
+            ),
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active() || (self.shift_register & 0x01) != 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+}
+
+// DMC output rates in CPU cycles, https://www.nesdev.org/wiki/APU_DMC
+const DMC_RATE_TABLE_NTSC: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const DMC_RATE_TABLE_PAL: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+// CPU cycles the DMC sample fetch takes from the CPU, 3 or 4 depending on what the CPU is doing
+pub const DMC_DMA_CYCLES: u16 = 4;
+
+pub struct DmcChannel {
+    // $4010 - IL-- RRRR
+    irq_enabled: bool,
+    loop_flag: bool,
+    rate_index: u8,
+    pal_timing: bool,
+
+    // $4012/$4013
+    sample_address: u16,
+    sample_length: u16,
+
+    pub irq_flag: bool,
+
+    // Memory reader
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+
+    // Output unit
+    timer_counter: u16,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    output_level: u8,
+}
+
+impl DmcChannel {
+    pub fn new() -> Self {
+        Self {
+            irq_enabled: false,
+            loop_flag: false,
+            rate_index: 0,
+            pal_timing: false,
+            sample_address: 0xC000,
+            sample_length: 1,
+            irq_flag: false,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            timer_counter: 0,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            output_level: 0,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u8, value: u8) {
+        match addr {
+            0 => {
+                // $4010 - IL-- RRRR
+                self.irq_enabled = (value & 0x80) != 0;
+                self.loop_flag = (value & 0x40) != 0;
+                self.rate_index = value & 0x0F;
+                if !self.irq_enabled {
+                    self.irq_flag = false;
+                }
+            }
+            1 => {
+                // $4011 - -DDD DDDD, direct load of the output level
+                self.output_level = value & 0x7F;
+            }
+            2 => {
+                // $4012 - AAAA AAAA, sample address = %11AAAAAA.AA000000
+                self.sample_address = 0xC000 | ((value as u16) << 6);
+            }
+            3 => {
+                // $4013 - LLLL LLLL, sample length = %LLLL.LLLL0001
+                self.sample_length = ((value as u16) << 4) | 0x0001;
+            }
+            _ => {}
+        }
+    }
+
+    // Writing $4015 always clears the DMC IRQ flag
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart_sample();
+        }
+    }
+
+    pub fn set_pal_timing(&mut self, pal_timing: bool) {
+        self.pal_timing = pal_timing;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    fn restart_sample(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    fn rate(&self) -> u16 {
+        if self.pal_timing {
+            DMC_RATE_TABLE_PAL[self.rate_index as usize]
+        } else {
+            DMC_RATE_TABLE_NTSC[self.rate_index as usize]
+        }
+    }
+
+    /// Address the memory reader wants to fetch, the bus performs the read and stalls the CPU
+    pub fn dma_request(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    pub fn load_sample_byte(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+
+        // Address wraps around to $8000, not $0000
+        self.current_address = if self.current_address == 0xFFFF {
+            0x8000
+        } else {
+            self.current_address + 1
+        };
+
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart_sample();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    // The rate table is in CPU cycles so this is clocked every CPU cycle
+    pub fn clock(&mut self) {
+        if self.timer_counter > 0 {
+            self.timer_counter -= 1;
+            return;
+        }
+        self.timer_counter = self.rate() - 1;
+
+        if !self.silence {
+            if self.shift_register & 0x01 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift_register = sample;
+                }
+                None => {
+                    self.silence = true;
+                }
+            }
+        }
+    }
+
+    pub fn debug_state(&self) -> ChannelDebugState {
+        ChannelDebugState {
+            enabled: self.is_active(),
+            duty: None,
+            period: self.rate(),
+            frequency: None,
+            volume: self.output_level,
+            length_counter: 0,
+            detail: format!(
+                "Sample ${:04X}, {} bytes left{}{}",
+                self.current_address,
+                self.bytes_remaining,
+                if self.loop_flag { ", loop" } else { "" },
+                if self.irq_enabled { ", IRQ" } else { "" }
+            ),
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
 // Pre-calculated nonlinear mixing lookup table
 // Formula: pulse_out = 95.88 / ((8128 / (pulse1 + pulse2)) + 100)
 // Index is pulse1 + pulse2 (range 0-30)
@@ -226,30 +818,151 @@ const PULSE_MIXING_TABLE: [f32; 31] = [
     0.230745, 0.238578, 0.246296, 0.253901, 0.261396, 0.268784, 0.276066,
 ];
 
+// One pulse channel at full volume, the reference level for expansion audio
+pub const APU_PULSE_LEVEL: f32 = PULSE_MIXING_TABLE[15];
+
+// Triangle/noise/DMC group of the nonlinear mixer, https://www.nesdev.org/wiki/APU_Mixer
+// Formula: tnd_out = 163.67 / (24329 / (3 * triangle + 2 * noise + dmc) + 100)
+// Index is 3 * triangle + 2 * noise + dmc (range 0-202)
+const TND_MIXING_TABLE: [f32; 203] = build_tnd_mixing_table();
+
+const fn build_tnd_mixing_table() -> [f32; 203] {
+    let mut table = [0.0; 203];
+    let mut i = 1;
+    while i < table.len() {
+        table[i] = 163.67 / (24329.0 / (i as f32) + 100.0);
+        i += 1;
+    }
+    table
+}
+
+// Same formulas as the mixing tables for fractional channel levels, 0.0 - 1.0
+pub fn mix_levels(levels: [f32; AUDIO_CHANNEL_COUNT]) -> f32 {
+    let [pulse1, pulse2, triangle, noise, dmc] = levels;
+
+    let pulse_sum = pulse1 + pulse2;
+    let pulse_out = if pulse_sum > 0.0 {
+        95.88 / (8128.0 / pulse_sum + 100.0)
+    } else {
+        0.0
+    };
+
+    let tnd_sum = 3.0 * triangle + 2.0 * noise + dmc;
+    let tnd_out = if tnd_sum > 0.0 {
+        163.67 / (24329.0 / tnd_sum + 100.0)
+    } else {
+        0.0
+    };
+
+    pulse_out + tnd_out
+}
+
+// The filtered mixer output peaks around +-0.5
+const OUTPUT_GAIN: f32 = 2.0;
+
 // NES length counter lookup table
 const LENGTH_TABLE: [u8; 32] = [
     10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
     12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
 ];
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AudioChannel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+pub const AUDIO_CHANNEL_COUNT: usize = 5;
+
+impl AudioChannel {
+    pub const ALL: [AudioChannel; AUDIO_CHANNEL_COUNT] = [
+        AudioChannel::Pulse1,
+        AudioChannel::Pulse2,
+        AudioChannel::Triangle,
+        AudioChannel::Noise,
+        AudioChannel::Dmc,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AudioChannel::Pulse1 => "Pulse 1",
+            AudioChannel::Pulse2 => "Pulse 2",
+            AudioChannel::Triangle => "Triangle",
+            AudioChannel::Noise => "Noise",
+            AudioChannel::Dmc => "DMC",
+        }
+    }
+}
+
+// Per channel mixer settings, for isolating channels when debugging or transcribing
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ChannelMix {
+    pub muted: bool,
+    pub solo: bool,
+    pub volume: f32, // 0.0 - 1.0
+}
+
+impl ChannelMix {
+    pub fn new() -> Self {
+        Self {
+            muted: false,
+            solo: false,
+            volume: 1.0,
+        }
+    }
+}
+
 pub struct Apu {
     pub pulse1: PulseChannel,
     pub pulse2: PulseChannel,
+    pub triangle: TriangleChannel,
+    pub noise: NoiseChannel,
+    pub dmc: DmcChannel,
 
     // Frame counter
     frame_counter_mode: bool, // false = 4-step, true = 5-step
     irq_inhibit: bool,
+    pub frame_irq_flag: bool,
 
     // Audio output
-    pub audio_buffer: Arc<Mutex<VecDeque<f32>>>,
+    pub audio_buffer: Arc<AudioRingBuffer>,
+    pub audio_stats: Arc<AudioStats>,
 
     // Cycle tracking for audio sample generation
     cpu_cycles: u64,
-    sample_counter: f32,
+    pal_timing: bool,
+    sample_rate: u32,
+    rate_adjustment: f64, // from dynamic rate control
+    blip: BlipBuffer,
+    blip_clock: u32,
+    blip_samples: Vec<f32>,
+    pub output_filter: OutputFilter,
+    last_channel_outputs: [u8; AUDIO_CHANNEL_COUNT],
+    last_amplitude: f32,
+
+    // Mixer controls, indexed by AudioChannel
+    pub channel_mix: [ChannelMix; AUDIO_CHANNEL_COUNT],
+    channel_gains: [f32; AUDIO_CHANNEL_COUNT],
 
-    // Frame counter tracking
-    apu_cycles: u64,
-    frame_counter_reset: bool,
+    // Cartridge sound chips, mixed linearly on top of the APU with one mixer entry per chip
+    expansion_audio: Vec<Box<dyn ExpansionAudio>>,
+    pub expansion_mix: Vec<ChannelMix>,
+    expansion_gains: Vec<f32>,
+    last_expansion_output: f32,
+
+    // WAV recording, a write error stops the recording and is kept for the UI
+    recorder: Option<AudioRecorder>,
+    pub recording_error: Option<String>,
+
+    pub scope: ApuScope,
+
+    // Frame counter tracking, in CPU cycles since the last reset
+    frame_cycles: u32,
+    pending_frame_counter_mode: bool,
+    frame_counter_reset_delay: u8, // CPU cycles until a $4017 write takes effect, 0 = none pending
 }
 
 impl Apu {
@@ -257,20 +970,164 @@ impl Apu {
         Self {
             pulse1: PulseChannel::new(),
             pulse2: PulseChannel::new(),
+            triangle: TriangleChannel::new(),
+            noise: NoiseChannel::new(),
+            dmc: DmcChannel::new(),
             frame_counter_mode: false,
             irq_inhibit: false,
-            audio_buffer: Arc::new(Mutex::new(VecDeque::with_capacity(2048))),
+            frame_irq_flag: false,
+            audio_buffer: Arc::new(AudioRingBuffer::new(MAX_BUFFERED_SAMPLES)),
+            audio_stats: Arc::new(AudioStats::new()),
             cpu_cycles: 0,
-            sample_counter: 0.0,
-            apu_cycles: 0,
-            frame_counter_reset: false,
+            pal_timing: false,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            rate_adjustment: 1.0,
+            blip: BlipBuffer::new(CPU_FREQUENCY, DEFAULT_SAMPLE_RATE as f64),
+            blip_clock: 0,
+            blip_samples: Vec::with_capacity(128),
+            output_filter: OutputFilter::new(DEFAULT_SAMPLE_RATE),
+            last_channel_outputs: [0; AUDIO_CHANNEL_COUNT],
+            last_amplitude: 0.0,
+            channel_mix: [ChannelMix::new(); AUDIO_CHANNEL_COUNT],
+            channel_gains: [1.0; AUDIO_CHANNEL_COUNT],
+            expansion_audio: Vec::new(),
+            expansion_mix: Vec::new(),
+            expansion_gains: Vec::new(),
+            last_expansion_output: 0.0,
+            recorder: None,
+            recording_error: None,
+            scope: ApuScope::new(),
+            frame_cycles: 0,
+            pending_frame_counter_mode: false,
+            frame_counter_reset_delay: 0,
+        }
+    }
+
+    pub fn set_pal_timing(&mut self, pal_timing: bool) {
+        self.noise.set_pal_timing(pal_timing);
+        self.dmc.set_pal_timing(pal_timing);
+        self.pal_timing = pal_timing;
+        self.update_blip_rates();
+    }
+
+    /// Output rate of the audio device, the band-limited buffer resamples to it
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.update_blip_rates();
+        self.output_filter.set_sample_rate(sample_rate);
+    }
+
+    /// Small multiplier on the output rate used to keep the audio buffer near its target fill
+    pub fn set_rate_adjustment(&mut self, rate_adjustment: f64) {
+        self.rate_adjustment = rate_adjustment;
+        self.update_blip_rates();
+    }
+
+    fn cpu_frequency(&self) -> f64 {
+        if self.pal_timing {
+            CPU_FREQUENCY_PAL
+        } else {
+            CPU_FREQUENCY
         }
     }
 
-    pub fn get_audio_buffer(&self) -> Arc<Mutex<VecDeque<f32>>> {
+    fn update_blip_rates(&mut self) {
+        let cpu_frequency = self.cpu_frequency();
+        let sample_rate = self.sample_rate as f64 * self.rate_adjustment;
+        self.blip.set_rates(cpu_frequency, sample_rate);
+        if let Some(recorder) = &mut self.recorder {
+            recorder.set_clock_rate(cpu_frequency);
+        }
+    }
+
+    pub fn set_expansion_audio(&mut self, chips: Vec<Box<dyn ExpansionAudio>>) {
+        self.expansion_mix = vec![ChannelMix::new(); chips.len()];
+        self.expansion_gains = vec![1.0; chips.len()];
+        self.expansion_audio = chips;
+    }
+
+    pub fn expansion_audio_names(&self) -> Vec<&'static str> {
+        self.expansion_audio.iter().map(|chip| chip.name()).collect()
+    }
+
+    pub fn reset_expansion_audio(&mut self) {
+        for chip in self.expansion_audio.iter_mut() {
+            chip.reset();
+        }
+    }
+
+    /// Cartridge space reads, $4020-$5FFF
+    pub fn read_expansion_register(&mut self, addr: u16) -> Option<u8> {
+        self.expansion_audio
+            .iter_mut()
+            .find_map(|chip| chip.read_register(addr))
+    }
+
+    /// Cartridge space writes, $4020-$FFFF
+    pub fn write_expansion_register(&mut self, addr: u16, value: u8) {
+        for chip in self.expansion_audio.iter_mut() {
+            chip.write_register(addr, value);
+        }
+    }
+
+    /// Register state of every channel for the visualizer, in AudioChannel order
+    pub fn channel_debug_states(&self) -> [ChannelDebugState; AUDIO_CHANNEL_COUNT] {
+        let cpu_frequency = self.cpu_frequency();
+        [
+            self.pulse1.debug_state(cpu_frequency),
+            self.pulse2.debug_state(cpu_frequency),
+            self.triangle.debug_state(cpu_frequency),
+            self.noise.debug_state(),
+            self.dmc.debug_state(),
+        ]
+    }
+
+    /// Records the mixed output to `path`, optionally every channel to its own file next to it
+    pub fn start_recording(
+        &mut self,
+        path: &str,
+        format: WavSampleFormat,
+        per_channel: bool,
+    ) -> Result<(), String> {
+        self.stop_recording()?;
+        self.recording_error = None;
+
+        let recorder = AudioRecorder::start(
+            path,
+            self.cpu_frequency(),
+            self.sample_rate,
+            format,
+            self.output_filter.mode,
+            OUTPUT_GAIN,
+            per_channel,
+        )?;
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<(), String> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    pub fn get_audio_buffer(&self) -> Arc<AudioRingBuffer> {
         Arc::clone(&self.audio_buffer)
     }
 
+    pub fn buffered_samples(&self) -> usize {
+        self.audio_buffer.available()
+    }
+
+    pub fn get_audio_stats(&self) -> Arc<AudioStats> {
+        Arc::clone(&self.audio_stats)
+    }
+
     pub fn write_register(&mut self, addr: u16, value: u8) {
         match addr {
             0x4000..=0x4003 => {
@@ -279,79 +1136,210 @@ impl Apu {
             0x4004..=0x4007 => {
                 self.pulse2.write_register((addr & 0x03) as u8, value);
             }
+            0x4008..=0x400B => {
+                self.triangle.write_register((addr & 0x03) as u8, value);
+            }
+            0x400C..=0x400F => {
+                self.noise.write_register((addr & 0x03) as u8, value);
+            }
+            0x4010..=0x4013 => {
+                self.dmc.write_register((addr & 0x03) as u8, value);
+            }
             0x4015 => {
                 // Status register - enable/disable channels
                 self.pulse1.set_enabled((value & 0x01) != 0);
                 self.pulse2.set_enabled((value & 0x02) != 0);
+                self.triangle.set_enabled((value & 0x04) != 0);
+                self.noise.set_enabled((value & 0x08) != 0);
+                self.dmc.set_enabled((value & 0x10) != 0);
             }
             0x4017 => {
                 // Frame counter
-                self.frame_counter_mode = (value & 0x80) != 0;
+                self.pending_frame_counter_mode = (value & 0x80) != 0;
                 self.irq_inhibit = (value & 0x40) != 0;
-                self.frame_counter_reset = true;
+                if self.irq_inhibit {
+                    self.frame_irq_flag = false;
+                }
+
+                // The reset happens 3 CPU cycles after a write during an APU cycle, 4 otherwise.
+                // This write happens on the CPU cycle that the next clock() call will count.
+                let on_apu_cycle = (self.cpu_cycles + 1).is_multiple_of(2);
+                self.frame_counter_reset_delay = if on_apu_cycle { 3 } else { 4 };
             }
             _ => {}
         }
     }
 
-    pub fn read_register(&self, addr: u16) -> u8 {
+    pub fn read_register(&mut self, addr: u16) -> u8 {
         match addr {
             0x4015 => {
-                // Status register
+                // Status register, reading it acknowledges the frame IRQ
                 let mut status = 0;
-                if self.pulse1.length_counter > 0 {
+                if self.pulse1.length.is_active() {
                     status |= 0x01;
                 }
-                if self.pulse2.length_counter > 0 {
+                if self.pulse2.length.is_active() {
                     status |= 0x02;
                 }
+                if self.triangle.length.is_active() {
+                    status |= 0x04;
+                }
+                if self.noise.length.is_active() {
+                    status |= 0x08;
+                }
+                if self.dmc.is_active() {
+                    status |= 0x10;
+                }
+                if self.frame_irq_flag {
+                    status |= 0x40;
+                }
+                if self.dmc.irq_flag {
+                    status |= 0x80;
+                }
+                self.frame_irq_flag = false;
                 status
             }
             _ => 0,
         }
     }
 
+    pub fn irq_pending(&self) -> bool {
+        self.frame_irq_flag || self.dmc.irq_flag
+    }
+
     // Called once per CPU cycle
     pub fn clock(&mut self) {
         self.cpu_cycles += 1;
 
+        self.triangle.clock();
+        self.noise.clock();
+        self.dmc.clock();
+
         // The APU runs at half the CPU speed for the pulse channels
         if self.cpu_cycles % 2 == 0 {
-            self.apu_cycles += 1;
             self.pulse1.clock();
             self.pulse2.clock();
+        }
 
-            // Clock the frame counter
-            self.clock_frame_counter();
+        for chip in self.expansion_audio.iter_mut() {
+            chip.clock();
         }
 
-        // Generate audio samples
-        // We need to generate a sample every (CPU_FREQUENCY / SAMPLE_RATE) CPU cycles
-        self.sample_counter += SAMPLE_RATE / CPU_FREQUENCY;
+        self.clock_frame_counter();
 
-        if self.sample_counter >= 1.0 {
-            self.sample_counter -= 1.0;
+        // Length counter register writes from this cycle land after the frame counter clock
+        self.pulse1.length.apply_pending_writes();
+        self.pulse2.length.apply_pending_writes();
+        self.triangle.length.apply_pending_writes();
+        self.noise.length.apply_pending_writes();
 
-            // Get raw outputs (0-15 range)
-            let pulse1_out = self.pulse1.output() as usize;
-            let pulse2_out = self.pulse2.output() as usize;
+        self.update_audio();
+    }
 
-            // Apply nonlinear mixing using lookup table
-            let pulse_sum = pulse1_out + pulse2_out;
-            let mixed = PULSE_MIXING_TABLE[pulse_sum];
+    // Feeds amplitude changes to the band-limited buffer at CPU cycle precision
+    fn update_audio(&mut self) {
+        // Raw outputs (0-15 range, DMC 0-127)
+        let channel_outputs = [
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        ];
 
-            // Convert from 0.0-0.276 range to -1.0 to 1.0 for audio output
-            let mixed = (mixed - 0.138) * 7.25;
+        if self.scope.enabled {
+            self.scope.clock(channel_outputs);
+        }
 
-            // Add to buffer
-            if let Ok(mut buffer) = self.audio_buffer.lock() {
-                buffer.push_back(mixed);
+        let expansion_output: f32 = self
+            .expansion_audio
+            .iter()
+            .zip(self.expansion_gains.iter())
+            .map(|(chip, gain)| chip.output() * gain)
+            .sum();
 
-                // Prevent buffer from growing too large
-                while buffer.len() > 4096 {
-                    buffer.pop_front();
-                }
+        if channel_outputs != self.last_channel_outputs
+            || expansion_output != self.last_expansion_output
+        {
+            self.last_channel_outputs = channel_outputs;
+            self.last_expansion_output = expansion_output;
+            let amplitude = self.mix(channel_outputs) + expansion_output;
+
+            if let Some(recorder) = &mut self.recorder {
+                recorder.add_outputs(self.blip_clock, amplitude, channel_outputs);
             }
+
+            self.blip.add_delta(self.blip_clock, amplitude - self.last_amplitude);
+            self.last_amplitude = amplitude;
+        }
+
+        self.blip_clock += 1;
+        if self.blip_clock == BLIP_FRAME_CLOCKS {
+            self.update_channel_gains();
+
+            self.blip.end_frame(self.blip_clock, &mut self.blip_samples);
+            self.blip_clock = 0;
+
+            for sample in self.blip_samples.iter_mut() {
+                // The filters also remove the DC offset of the mixer
+                *sample = (self.output_filter.process(*sample) * OUTPUT_GAIN).clamp(-1.0, 1.0);
+            }
+
+            // Recording resamples on its own, before the audio device can drop anything
+            if let Some(recorder) = &mut self.recorder
+                && let Err(e) = recorder.end_frame(BLIP_FRAME_CLOCKS)
+            {
+                self.recording_error = Some(e);
+                self.recorder = None;
+            }
+
+            // Add to buffer in one batch, whatever doesn't fit is dropped
+            let pushed = self.audio_buffer.push_slice(&self.blip_samples);
+            if pushed < self.blip_samples.len() {
+                self.audio_stats
+                    .record_overrun((self.blip_samples.len() - pushed) as u64);
+            }
+            self.blip_samples.clear();
+        }
+    }
+
+    // Nonlinear mixer, 0.0 - 1.0
+    fn mix(&self, channel_outputs: [u8; AUDIO_CHANNEL_COUNT]) -> f32 {
+        let [pulse1_out, pulse2_out, triangle_out, noise_out, dmc_out] = channel_outputs;
+
+        // Lookup tables when nothing is muted or scaled
+        if self.channel_gains.iter().all(|gain| *gain == 1.0) {
+            let pulse_sum = (pulse1_out + pulse2_out) as usize;
+            let tnd_sum = 3 * triangle_out as usize + 2 * noise_out as usize + dmc_out as usize;
+            return PULSE_MIXING_TABLE[pulse_sum] + TND_MIXING_TABLE[tnd_sum];
+        }
+
+        mix_levels(std::array::from_fn(|i| {
+            channel_outputs[i] as f32 * self.channel_gains[i]
+        }))
+    }
+
+    // Soloed channels silence every channel that isn't soloed
+    fn update_channel_gains(&mut self) {
+        let any_solo = self
+            .channel_mix
+            .iter()
+            .chain(self.expansion_mix.iter())
+            .any(|mix| mix.solo);
+        let gain = |mix: &ChannelMix| {
+            if mix.muted || (any_solo && !mix.solo) {
+                0.0
+            } else {
+                mix.volume
+            }
+        };
+        let gains = self.channel_mix.map(|mix| gain(&mix));
+        self.expansion_gains = self.expansion_mix.iter().map(gain).collect();
+
+        if gains != self.channel_gains {
+            self.channel_gains = gains;
+            // Force a remix on the next cycle
+            self.last_channel_outputs = [u8::MAX; AUDIO_CHANNEL_COUNT];
         }
     }
 
@@ -359,60 +1347,82 @@ impl Apu {
     pub fn clock_quarter_frame(&mut self) {
         self.pulse1.clock_envelope();
         self.pulse2.clock_envelope();
+        self.triangle.clock_linear_counter();
+        self.noise.clock_envelope();
     }
 
     pub fn clock_half_frame(&mut self) {
         self.clock_quarter_frame();
         self.pulse1.clock_length_counter();
         self.pulse2.clock_length_counter();
+        self.triangle.clock_length_counter();
+        self.noise.clock_length_counter();
         self.pulse1.clock_sweep(true);   // true = is pulse 1
         self.pulse2.clock_sweep(false);  // false = is pulse 2
     }
 
+    // Frame counter sequencer, https://www.nesdev.org/wiki/APU_Frame_Counter
+    // Step timings are in CPU cycles, the APU cycle steps land on x.5 so they are rounded up
     fn clock_frame_counter(&mut self) {
-        // Handle frame counter reset (write to $4017)
-        if self.frame_counter_reset {
-            self.apu_cycles = 0;
-            self.frame_counter_reset = false;
+        // Handle a delayed frame counter reset from a $4017 write
+        if self.frame_counter_reset_delay > 0 {
+            self.frame_counter_reset_delay -= 1;
+            if self.frame_counter_reset_delay == 0 {
+                self.frame_counter_mode = self.pending_frame_counter_mode;
+                self.frame_cycles = 0;
 
-            // In 5-step mode, immediately clock half frame
-            if self.frame_counter_mode {
-                self.clock_half_frame();
+                // In 5-step mode, immediately clock half frame
+                if self.frame_counter_mode {
+                    self.clock_half_frame();
+                }
+                return;
             }
-            return;
         }
 
+        self.frame_cycles += 1;
+
         if !self.frame_counter_mode {
-            // 4-step mode: quarter frames at 3728, 7456, 11185; half frames at 7456, 14914
-            match self.apu_cycles {
-                3728 => {
+            // 4-step mode: quarter frames at 7457, 22371; half frames at 14913, 29829
+            match self.frame_cycles {
+                7457 | 22371 => {
                     self.clock_quarter_frame();
                 }
-                7456 => {
+                14913 => {
                     self.clock_half_frame(); // This also calls quarter frame
                 }
-                11185 => {
-                    self.clock_quarter_frame();
+                29828 => {
+                    self.set_frame_irq();
                 }
-                14914 => {
+                29829 => {
                     self.clock_half_frame();
-                    self.apu_cycles = 0; // Reset cycle counter
-                    // TODO: Generate IRQ if !self.irq_inhibit
+                    self.set_frame_irq();
+                }
+                29830 => {
+                    self.set_frame_irq();
+                    self.frame_cycles = 0; // 29830 is also cycle 0 of the next sequence
                 }
                 _ => {}
             }
         } else {
-            // 5-step mode: quarter frames at 3728, 7456, 11185, 18640; half frame at 18640
-            match self.apu_cycles {
-                3728 | 7456 | 11185 => {
+            // 5-step mode: quarter frames at 7457, 22371; half frames at 14913, 37281, no IRQ
+            match self.frame_cycles {
+                7457 | 22371 => {
                     self.clock_quarter_frame();
                 }
-                18640 => {
+                14913 | 37281 => {
                     self.clock_half_frame();
-                    self.apu_cycles = 0; // Reset cycle counter
+                }
+                37282 => {
+                    self.frame_cycles = 0;
                 }
                 _ => {}
             }
         }
     }
+
+    fn set_frame_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.frame_irq_flag = true;
+        }
+    }
 }
@@ -0,0 +1,92 @@
+use crate::nes::apu::AUDIO_CHANNEL_COUNT;
+
+/*
+Debug views of the APU for the visualizer window: a snapshot of each channel's register state and
+a short history of every channel's output for the oscilloscope.
+*/
+
+// CPU cycles between oscilloscope samples, ~44.7kHz
+const SCOPE_SAMPLE_CYCLES: u32 = 40;
+
+// ~46ms of history per channel
+pub const SCOPE_LENGTH: usize = 2048;
+
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+pub struct ChannelDebugState {
+    pub enabled: bool,          // length counter (or DMC bytes) still running
+    pub duty: Option<u8>,       // pulse only, duty cycle index
+    pub period: u16,            // timer period / rate
+    pub frequency: Option<f32>, // Hz, only for tonal channels
+    pub volume: u8,             // envelope volume, or the DMC output level
+    pub length_counter: u8,
+    pub detail: String, // channel specific state, sweep, linear counter...
+}
+
+// Per channel output history, sampled at a fixed rate
+pub struct ApuScope {
+    pub enabled: bool, // only sampled while the visualizer is open
+    history: [[u8; SCOPE_LENGTH]; AUDIO_CHANNEL_COUNT],
+    position: usize,
+    cycle_counter: u32,
+}
+
+impl ApuScope {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            history: [[0; SCOPE_LENGTH]; AUDIO_CHANNEL_COUNT],
+            position: 0,
+            cycle_counter: 0,
+        }
+    }
+
+    // Called every CPU cycle
+    pub fn clock(&mut self, channel_outputs: [u8; AUDIO_CHANNEL_COUNT]) {
+        self.cycle_counter += 1;
+        if self.cycle_counter < SCOPE_SAMPLE_CYCLES {
+            return;
+        }
+        self.cycle_counter = 0;
+
+        for (history, output) in self.history.iter_mut().zip(channel_outputs) {
+            history[self.position] = output;
+        }
+        self.position = (self.position + 1) % SCOPE_LENGTH;
+    }
+
+    /// Oldest to newest output of a channel
+    pub fn samples(&self, channel: usize) -> impl Iterator<Item = u8> + '_ {
+        let history = &self.history[channel];
+        history[self.position..]
+            .iter()
+            .chain(history[..self.position].iter())
+            .copied()
+    }
+}
+
+/// Closest note to a frequency, e.g. "A4 +3c"
+pub fn note_name(frequency: f32) -> String {
+    if frequency <= 0.0 {
+        return "-".to_string();
+    }
+
+    // MIDI note numbering, A4 = 69 = 440Hz
+    let note = 69.0 + 12.0 * (frequency / 440.0).log2();
+    let nearest = note.round();
+    let cents = ((note - nearest) * 100.0).round() as i32;
+    let nearest = nearest as i32;
+    if nearest < 0 {
+        return "-".to_string();
+    }
+
+    let octave = nearest / 12 - 1;
+    format!(
+        "{}{} {:+}c",
+        NOTE_NAMES[(nearest % 12) as usize],
+        octave,
+        cents
+    )
+}
@@ -0,0 +1,114 @@
+/*
+Output filter chain of the console, https://www.nesdev.org/wiki/APU_Mixer
+The NES runs the mixer output through two first-order high-pass filters (90Hz and 440Hz) and a
+first-order low-pass (14kHz) before it reaches the TV. Filters run at the output sample rate.
+*/
+
+const HIGH_PASS_90_HZ: f32 = 90.0;
+const HIGH_PASS_440_HZ: f32 = 440.0;
+const LOW_PASS_14_KHZ: f32 = 14000.0;
+
+// Clean mode still needs the DC offset of the mixer removed, low enough to not be audible
+const DC_BLOCKER_HZ: f32 = 5.0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FilterMode {
+    Console, // the filter chain of the console
+    Clean,   // only removes the DC offset
+}
+
+impl FilterMode {
+    pub const ALL: [FilterMode; 2] = [FilterMode::Console, FilterMode::Clean];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterMode::Console => "Console (90Hz/440Hz high-pass, 14kHz low-pass)",
+            FilterMode::Clean => "Clean (unfiltered)",
+        }
+    }
+}
+
+struct HighPassFilter {
+    alpha: f32,
+    previous_input: f32,
+    previous_output: f32,
+}
+
+impl HighPassFilter {
+    fn new(cutoff: f32, sample_rate: f32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        Self {
+            alpha: rc / (rc + 1.0 / sample_rate),
+            previous_input: 0.0,
+            previous_output: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = self.alpha * (self.previous_output + input - self.previous_input);
+        self.previous_input = input;
+        self.previous_output = output;
+        output
+    }
+}
+
+struct LowPassFilter {
+    alpha: f32,
+    previous_output: f32,
+}
+
+impl LowPassFilter {
+    fn new(cutoff: f32, sample_rate: f32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        Self {
+            alpha: dt / (rc + dt),
+            previous_output: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        self.previous_output += self.alpha * (input - self.previous_output);
+        self.previous_output
+    }
+}
+
+pub struct OutputFilter {
+    pub mode: FilterMode,
+    high_pass_90: HighPassFilter,
+    high_pass_440: HighPassFilter,
+    low_pass_14k: LowPassFilter,
+    dc_blocker: HighPassFilter,
+}
+
+impl OutputFilter {
+    pub fn new(sample_rate: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        Self {
+            mode: FilterMode::Console,
+            high_pass_90: HighPassFilter::new(HIGH_PASS_90_HZ, sample_rate),
+            high_pass_440: HighPassFilter::new(HIGH_PASS_440_HZ, sample_rate),
+            // The low-pass can't go past the output Nyquist frequency
+            low_pass_14k: LowPassFilter::new(LOW_PASS_14_KHZ.min(sample_rate * 0.45), sample_rate),
+            dc_blocker: HighPassFilter::new(DC_BLOCKER_HZ, sample_rate),
+        }
+    }
+
+    /// Rebuilds the filters for a new output rate, keeps the mode
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let mode = self.mode;
+        *self = OutputFilter::new(sample_rate);
+        self.mode = mode;
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        match self.mode {
+            FilterMode::Console => {
+                let sample = self.high_pass_90.process(sample);
+                let sample = self.high_pass_440.process(sample);
+                self.low_pass_14k.process(sample)
+            }
+            FilterMode::Clean => self.dc_blocker.process(sample),
+        }
+    }
+}
@@ -1,53 +1,210 @@
+use crate::nes::audio_sync::{AudioStats, DEFAULT_LATENCY_MS};
+use crate::nes::ring_buffer::AudioRingBuffer;
+use crate::nes::settings::Settings;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{Device, Stream, StreamConfig};
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+
+// Offered in the UI, 0 = whatever the device defaults to
+pub const SAMPLE_RATES: [u32; 6] = [0, 22050, 32000, 44100, 48000, 96000];
+pub const MIN_LATENCY_MS: u32 = 20;
+pub const MAX_LATENCY_MS: u32 = 150;
+
+// Scratch buffer size when the device picks its own buffer size, bigger callbacks are done in parts
+const DEFAULT_CALLBACK_FRAMES: usize = 4096;
+
+// Output settings, persisted in the global settings file
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioConfig {
+    pub device_name: String, // empty = default device
+    pub sample_rate: u32,    // 0 = device default
+    pub latency_ms: u32,     // target amount of buffered audio
+}
+
+impl AudioConfig {
+    pub fn new() -> Self {
+        Self {
+            device_name: String::new(),
+            sample_rate: 0,
+            latency_ms: DEFAULT_LATENCY_MS,
+        }
+    }
+
+    pub fn load(settings: &Settings) -> Self {
+        let defaults = AudioConfig::new();
+        Self {
+            device_name: settings.get("audio_device", defaults.device_name),
+            sample_rate: settings.get("audio_sample_rate", defaults.sample_rate),
+            latency_ms: settings
+                .get("audio_latency_ms", defaults.latency_ms)
+                .clamp(MIN_LATENCY_MS, MAX_LATENCY_MS),
+        }
+    }
+
+    pub fn save(&self, settings: &mut Settings) {
+        settings.set("audio_device", &self.device_name);
+        settings.set("audio_sample_rate", self.sample_rate);
+        settings.set("audio_latency_ms", self.latency_ms);
+    }
+}
 
 pub struct AudioOutput {
     _stream: Stream,
+    sample_rate: u32,
+    device_name: String,
 }
 
 impl AudioOutput {
-    pub fn new(audio_buffer: Arc<Mutex<VecDeque<f32>>>) -> Result<Self, String> {
+    /// Names of the output devices of the default host
+    pub fn device_names() -> Vec<String> {
         let host = cpal::default_host();
+        match host.output_devices() {
+            Ok(devices) => devices.filter_map(|device| device.name().ok()).collect(),
+            Err(e) => {
+                eprintln!("Failed to list audio devices: {}", e);
+                Vec::new()
+            }
+        }
+    }
 
+    pub fn new(
+        audio_buffer: Arc<AudioRingBuffer>,
+        audio_stats: Arc<AudioStats>,
+        audio_config: &AudioConfig,
+    ) -> Result<Self, String> {
+        let host = cpal::default_host();
+
+        // Fall back to the default device if the configured one is gone
         let device = host
-            .default_output_device()
+            .output_devices()
+            .ok()
+            .and_then(|mut devices| {
+                devices.find(|device| {
+                    !audio_config.device_name.is_empty()
+                        && device.name().ok().as_ref() == Some(&audio_config.device_name)
+                })
+            })
+            .or_else(|| host.default_output_device())
             .ok_or("No output device available")?;
 
-        let config = device
-            .default_output_config()
-            .map_err(|e| format!("Failed to get default output config: {}", e))?;
+        let config = Self::find_config(&device, audio_config.sample_rate)?;
+        let sample_rate = config.sample_rate().0;
+        let device_name = device.name().unwrap_or_default();
 
-        let stream = match config.sample_format() {
-            cpal::SampleFormat::F32 => Self::build_stream::<f32>(&device, &config.into(), audio_buffer),
-            cpal::SampleFormat::I16 => Self::build_stream::<i16>(&device, &config.into(), audio_buffer),
-            cpal::SampleFormat::U16 => Self::build_stream::<u16>(&device, &config.into(), audio_buffer),
-            _ => Err("Unsupported sample format".to_string()),
-        }?;
+        let sample_format = config.sample_format();
+        let buffer_size = Self::buffer_size(&config, audio_config.latency_ms);
+        let mut stream_config: StreamConfig = config.into();
+        stream_config.buffer_size = buffer_size;
+
+        // Not every backend takes a fixed buffer size, let the device pick one then
+        let stream = match Self::build_stream_for_format(&device, sample_format, &stream_config, &audio_buffer, &audio_stats) {
+            Ok(stream) => stream,
+            Err(e) if stream_config.buffer_size != cpal::BufferSize::Default => {
+                eprintln!("{}, using the default buffer size", e);
+                stream_config.buffer_size = cpal::BufferSize::Default;
+                Self::build_stream_for_format(&device, sample_format, &stream_config, &audio_buffer, &audio_stats)?
+            }
+            Err(e) => return Err(e),
+        };
 
         stream
             .play()
             .map_err(|e| format!("Failed to play stream: {}", e))?;
 
-        Ok(Self { _stream: stream })
+        Ok(Self {
+            _stream: stream,
+            sample_rate,
+            device_name,
+        })
+    }
+
+    // First supported config that can run at `sample_rate`, the device default otherwise
+    fn find_config(device: &Device, sample_rate: u32) -> Result<cpal::SupportedStreamConfig, String> {
+        let default_config = device
+            .default_output_config()
+            .map_err(|e| format!("Failed to get default output config: {}", e))?;
+
+        if sample_rate == 0 || sample_rate == default_config.sample_rate().0 {
+            return Ok(default_config);
+        }
+
+        let rate = cpal::SampleRate(sample_rate);
+        let supported = device
+            .supported_output_configs()
+            .map_err(|e| format!("Failed to get supported output configs: {}", e))?
+            .filter(|range| range.min_sample_rate() <= rate && rate <= range.max_sample_rate())
+            .max_by_key(|range| range.sample_format() == default_config.sample_format());
+
+        match supported {
+            Some(range) => Ok(range.with_sample_rate(rate)),
+            None => {
+                eprintln!(
+                    "Device does not support {} Hz, using {} Hz",
+                    sample_rate,
+                    default_config.sample_rate().0
+                );
+                Ok(default_config)
+            }
+        }
+    }
+
+    // Half the target latency per callback, the ring buffer queues the other half
+    fn buffer_size(config: &cpal::SupportedStreamConfig, latency_ms: u32) -> cpal::BufferSize {
+        let frames = (config.sample_rate().0 * latency_ms / 1000 / 2).max(1);
+        match *config.buffer_size() {
+            cpal::SupportedBufferSize::Range { min, max } => cpal::BufferSize::Fixed(frames.clamp(min, max)),
+            cpal::SupportedBufferSize::Unknown => cpal::BufferSize::Fixed(frames),
+        }
+    }
+
+    fn build_stream_for_format(
+        device: &Device,
+        sample_format: cpal::SampleFormat,
+        config: &StreamConfig,
+        audio_buffer: &Arc<AudioRingBuffer>,
+        audio_stats: &Arc<AudioStats>,
+    ) -> Result<Stream, String> {
+        let (audio_buffer, audio_stats) = (audio_buffer.clone(), audio_stats.clone());
+        match sample_format {
+            cpal::SampleFormat::F32 => Self::build_stream::<f32>(device, config, audio_buffer, audio_stats),
+            cpal::SampleFormat::I16 => Self::build_stream::<i16>(device, config, audio_buffer, audio_stats),
+            cpal::SampleFormat::U16 => Self::build_stream::<u16>(device, config, audio_buffer, audio_stats),
+            _ => Err("Unsupported sample format".to_string()),
+        }
+    }
+
+    // Rate the device runs at, usually 44100 or 48000
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn device_name(&self) -> &str {
+        &self.device_name
     }
 
     fn build_stream<T>(
         device: &Device,
         config: &StreamConfig,
-        audio_buffer: Arc<Mutex<VecDeque<f32>>>,
+        audio_buffer: Arc<AudioRingBuffer>,
+        audio_stats: Arc<AudioStats>,
     ) -> Result<Stream, String>
     where
         T: cpal::Sample + cpal::SizedSample + cpal::FromSample<f32>,
     {
         let channels = config.channels as usize;
 
+        // Mono samples for one callback, allocated here as the callback runs on the real-time thread
+        let callback_frames = match config.buffer_size {
+            cpal::BufferSize::Fixed(frames) => frames as usize,
+            cpal::BufferSize::Default => DEFAULT_CALLBACK_FRAMES,
+        };
+        let mut samples = vec![0.0f32; callback_frames.max(1)];
+
         let stream = device
             .build_output_stream(
                 config,
                 move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                    Self::write_data(data, channels, &audio_buffer);
+                    Self::write_data(data, channels, &audio_buffer, &audio_stats, &mut samples);
                 },
                 |err| eprintln!("Audio stream error: {}", err),
                 None,
@@ -57,24 +214,37 @@ impl AudioOutput {
         Ok(stream)
     }
 
-    fn write_data<T>(output: &mut [T], channels: usize, audio_buffer: &Arc<Mutex<VecDeque<f32>>>)
-    where
+    fn write_data<T>(
+        output: &mut [T],
+        channels: usize,
+        audio_buffer: &AudioRingBuffer,
+        audio_stats: &AudioStats,
+        samples: &mut [f32],
+    ) where
         T: cpal::Sample + cpal::FromSample<f32>,
     {
-        if let Ok(mut buffer) = audio_buffer.lock() {
-            for frame in output.chunks_mut(channels) {
-                let sample = buffer.pop_front().unwrap_or(0.0);
+        // In scratch buffer sized parts, the device doesn't always keep to the requested size
+        let mut underrun = false;
+        for block in output.chunks_mut(samples.len() * channels) {
+            let frames = block.len() / channels;
+            let samples = &mut samples[..frames];
+
+            // Pop the whole block at once, output silence for anything missing
+            let popped = audio_buffer.pop_slice(samples);
+            if popped < frames {
+                underrun = true;
+                samples[popped..].fill(0.0);
+            }
 
+            for (frame, sample) in block.chunks_mut(channels).zip(samples.iter()) {
                 // Write the same sample to all channels (mono to stereo/multi-channel)
                 for channel_sample in frame.iter_mut() {
-                    *channel_sample = T::from_sample(sample);
+                    *channel_sample = T::from_sample(*sample);
                 }
             }
-        } else {
-            // If we can't lock the buffer, output silence
-            for sample in output.iter_mut() {
-                *sample = T::from_sample(0.0);
-            }
+        }
+        if underrun {
+            audio_stats.record_underrun();
         }
     }
 }
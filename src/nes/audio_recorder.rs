@@ -0,0 +1,154 @@
+use crate::nes::apu::{AUDIO_CHANNEL_COUNT, AudioChannel, mix_levels};
+use crate::nes::audio_filter::{FilterMode, OutputFilter};
+use crate::nes::blip::BlipBuffer;
+use crate::nes::wav::{WavSampleFormat, WavWriter};
+use std::path::Path;
+
+/*
+Records the APU output to WAV. The recorder gets the same amplitude changes as the APU's own
+band-limited buffer but resamples them itself at the nominal output rate, so the dynamic rate
+control steering the live output doesn't end up in the file, and it doesn't care whether the audio
+device keeps up.
+Per channel recordings run every channel through its own band-limited buffer and filters, they
+are the raw channel output and ignore the mute/solo/volume mixer controls.
+*/
+
+struct TrackRecording {
+    wav: WavWriter,
+    blip: BlipBuffer,
+    filter: OutputFilter,
+    last_amplitude: f32,
+}
+
+impl TrackRecording {
+    fn new(
+        path: &str,
+        clock_rate: f64,
+        sample_rate: u32,
+        format: WavSampleFormat,
+        filter_mode: FilterMode,
+    ) -> Result<Self, String> {
+        let mut filter = OutputFilter::new(sample_rate);
+        filter.mode = filter_mode;
+        Ok(Self {
+            wav: WavWriter::create(path, sample_rate, format)?,
+            blip: BlipBuffer::new(clock_rate, sample_rate as f64),
+            filter,
+            last_amplitude: 0.0,
+        })
+    }
+
+    fn add_amplitude(&mut self, clock: u32, amplitude: f32) {
+        if amplitude != self.last_amplitude {
+            self.blip.add_delta(clock, amplitude - self.last_amplitude);
+            self.last_amplitude = amplitude;
+        }
+    }
+
+    fn end_frame(
+        &mut self,
+        clocks: u32,
+        output_gain: f32,
+        samples: &mut Vec<f32>,
+    ) -> Result<(), String> {
+        self.blip.end_frame(clocks, samples);
+        for sample in samples.iter_mut() {
+            *sample = (self.filter.process(*sample) * output_gain).clamp(-1.0, 1.0);
+        }
+        let result = self.wav.write_samples(samples);
+        samples.clear();
+        result
+    }
+}
+
+pub struct AudioRecorder {
+    mixed: TrackRecording,
+    channels: Vec<TrackRecording>,
+    sample_rate: f64,
+    samples: Vec<f32>,
+    output_gain: f32,
+}
+
+impl AudioRecorder {
+    /// Per channel files are named after the mixed one, e.g. `song.wav` -> `song_triangle.wav`
+    pub fn start(
+        path: &str,
+        clock_rate: f64,
+        sample_rate: u32,
+        format: WavSampleFormat,
+        filter_mode: FilterMode,
+        output_gain: f32,
+        per_channel: bool,
+    ) -> Result<Self, String> {
+        let mixed = TrackRecording::new(path, clock_rate, sample_rate, format, filter_mode)?;
+
+        let mut channels = Vec::new();
+        if per_channel {
+            for channel in AudioChannel::ALL {
+                let channel_path = Self::channel_path(path, channel);
+                channels.push(TrackRecording::new(
+                    &channel_path,
+                    clock_rate,
+                    sample_rate,
+                    format,
+                    filter_mode,
+                )?);
+            }
+        }
+
+        Ok(Self {
+            mixed,
+            channels,
+            sample_rate: sample_rate as f64,
+            samples: Vec::with_capacity(128),
+            output_gain,
+        })
+    }
+
+    fn channel_path(path: &str, channel: AudioChannel) -> String {
+        let path = Path::new(path);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let suffix = channel.name().to_lowercase().replace(' ', "");
+        path.with_file_name(format!("{}_{}.wav", stem, suffix))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// The CPU clock, changes with PAL timing. The sample rate stays what the recording started with
+    pub fn set_clock_rate(&mut self, clock_rate: f64) {
+        for track in std::iter::once(&mut self.mixed).chain(self.channels.iter_mut()) {
+            track.blip.set_rates(clock_rate, self.sample_rate);
+        }
+    }
+
+    /// `amplitude` is the mixed output and `outputs` the raw channel outputs at `clock` cycles into
+    /// the current frame
+    pub fn add_outputs(&mut self, clock: u32, amplitude: f32, outputs: [u8; AUDIO_CHANNEL_COUNT]) {
+        self.mixed.add_amplitude(clock, amplitude);
+
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            // Each channel through the nonlinear mixer on its own
+            let mut levels = [0.0; AUDIO_CHANNEL_COUNT];
+            levels[i] = outputs[i] as f32;
+            channel.add_amplitude(clock, mix_levels(levels));
+        }
+    }
+
+    /// `clocks` is the length of the frame
+    pub fn end_frame(&mut self, clocks: u32) -> Result<(), String> {
+        self.mixed
+            .end_frame(clocks, self.output_gain, &mut self.samples)?;
+        for channel in self.channels.iter_mut() {
+            channel.end_frame(clocks, self.output_gain, &mut self.samples)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        self.mixed.wav.finish()?;
+        for channel in self.channels {
+            channel.wav.finish()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/*
+Audio/video sync with dynamic rate control (the technique used by higan/bsnes).
+The emulator is paced by the video frame timer, which never matches the audio device clock
+exactly. Instead of letting the audio buffer slowly run dry or overflow, the APU output rate is
+nudged by a fraction of a percent to keep the buffer near a target fill level. The pitch change
+is far too small to hear.
+*/
+
+// Default target amount of buffered audio, enough to cover a late frame
+pub const DEFAULT_LATENCY_MS: u32 = 50;
+
+// Maximum rate adjustment, 0.5% is inaudible
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+
+// Counters shared between the APU (producer) and the audio callback (consumer)
+pub struct AudioStats {
+    pub underruns: AtomicU64,       // audio callbacks that ran out of samples
+    pub overrun_samples: AtomicU64, // samples dropped because the buffer was full
+    pub active: AtomicBool,         // only count underruns while the emulator is running
+}
+
+impl AudioStats {
+    pub fn new() -> Self {
+        Self {
+            underruns: AtomicU64::new(0),
+            overrun_samples: AtomicU64::new(0),
+            active: AtomicBool::new(false),
+        }
+    }
+
+    pub fn record_underrun(&self) {
+        if self.active.load(Ordering::Relaxed) {
+            self.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_overrun(&self, samples: u64) {
+        self.overrun_samples.fetch_add(samples, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        self.underruns.store(0, Ordering::Relaxed);
+        self.overrun_samples.store(0, Ordering::Relaxed);
+    }
+}
+
+pub struct RateControl {
+    pub target_fill: usize,
+    pub ratio: f64, // multiplier applied to the output sample rate
+}
+
+impl RateControl {
+    pub fn new(sample_rate: u32, latency_ms: u32) -> Self {
+        Self {
+            target_fill: Self::target_for(sample_rate, latency_ms),
+            ratio: 1.0,
+        }
+    }
+
+    fn target_for(sample_rate: u32, latency_ms: u32) -> usize {
+        (sample_rate * latency_ms / 1000) as usize
+    }
+
+    pub fn configure(&mut self, sample_rate: u32, latency_ms: u32) {
+        self.target_fill = Self::target_for(sample_rate, latency_ms);
+    }
+
+    /// Produce fewer samples when the buffer is above the target and more when it is below
+    pub fn update(&mut self, buffer_fill: usize) -> f64 {
+        let target = self.target_fill as f64;
+        let error = ((target - buffer_fill as f64) / target).clamp(-1.0, 1.0);
+        self.ratio = 1.0 + MAX_RATE_ADJUSTMENT * error;
+        self.ratio
+    }
+}
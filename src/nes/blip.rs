@@ -0,0 +1,115 @@
+/*
+Band-limited step synthesis, the same idea as blargg's blip_buf.
+Instead of point sampling the APU output we record every change in amplitude at the CPU cycle it
+happened. Each change is spread over the output samples around it with a windowed sinc impulse,
+summing those impulses and integrating gives a band-limited version of the square waves with no
+aliasing, at whatever output sample rate the audio device wants.
+*/
+
+// Impulse length in output samples, longer is a sharper low-pass but more work per delta
+const KERNEL_WIDTH: usize = 16;
+
+// Sub-sample positions the impulse is precomputed for
+const KERNEL_PHASES: usize = 64;
+
+// Cutoff relative to the output Nyquist frequency, leaves room for the window roll-off
+const KERNEL_CUTOFF: f64 = 0.9;
+
+pub struct BlipBuffer {
+    samples_per_clock: f64,
+
+    // Output sample position of clock 0 of the current frame, the fraction carries between frames
+    frame_start: f64,
+
+    // Summed impulses, integrating them gives the output samples
+    deltas: Vec<f32>,
+    integrator: f32,
+
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: f64, sample_rate: f64) -> Self {
+        Self {
+            samples_per_clock: sample_rate / clock_rate,
+            frame_start: 0.0,
+            deltas: vec![0.0; KERNEL_WIDTH],
+            integrator: 0.0,
+            kernel: Self::build_kernel(),
+        }
+    }
+
+    fn build_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+        let mut kernel = vec![[0.0f32; KERNEL_WIDTH]; KERNEL_PHASES];
+        let center = (KERNEL_WIDTH / 2) as f64;
+
+        for (phase, taps) in kernel.iter_mut().enumerate() {
+            let offset = phase as f64 / KERNEL_PHASES as f64;
+            let mut values = [0.0f64; KERNEL_WIDTH];
+            for (k, value) in values.iter_mut().enumerate() {
+                let x = k as f64 - center - offset;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    let t = std::f64::consts::PI * KERNEL_CUTOFF * x;
+                    t.sin() / t
+                };
+
+                // Blackman window over the kernel width
+                let w = (x + center) / KERNEL_WIDTH as f64;
+                let window = 0.42 - 0.5 * (2.0 * std::f64::consts::PI * w).cos()
+                    + 0.08 * (4.0 * std::f64::consts::PI * w).cos();
+                *value = sinc * window.max(0.0);
+            }
+
+            // Each impulse has to sum to 1 so the integrated step ends at exactly the delta
+            let sum: f64 = values.iter().sum();
+            for (tap, value) in taps.iter_mut().zip(values.iter()) {
+                *tap = (value / sum) as f32;
+            }
+        }
+
+        kernel
+    }
+
+    /// Changes the rates, pending deltas are kept
+    pub fn set_rates(&mut self, clock_rate: f64, sample_rate: f64) {
+        self.samples_per_clock = sample_rate / clock_rate;
+    }
+
+    /// Adds an amplitude change at `clock` cycles into the current frame
+    pub fn add_delta(&mut self, clock: u32, delta: f32) {
+        let time = self.frame_start + clock as f64 * self.samples_per_clock;
+        let index = time as usize;
+        let phase = ((time - index as f64) * KERNEL_PHASES as f64) as usize;
+
+        let end = index + KERNEL_WIDTH;
+        if self.deltas.len() < end {
+            self.deltas.resize(end, 0.0);
+        }
+
+        for (slot, tap) in self.deltas[index..end]
+            .iter_mut()
+            .zip(self.kernel[phase].iter())
+        {
+            *slot += delta * tap;
+        }
+    }
+
+    /// Ends the current frame after `clocks` cycles and appends the finished samples to `out`
+    pub fn end_frame(&mut self, clocks: u32, out: &mut Vec<f32>) {
+        let end = self.frame_start + clocks as f64 * self.samples_per_clock;
+        let available = end as usize;
+
+        // Samples before `available` can no longer receive deltas from later frames
+        if self.deltas.len() < available + KERNEL_WIDTH {
+            self.deltas.resize(available + KERNEL_WIDTH, 0.0);
+        }
+        for delta in self.deltas.drain(..available) {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+
+        self.frame_start = end - available as f64;
+    }
+}
@@ -1,5 +1,6 @@
 use crate::nes::apu::Apu;
 use crate::nes::controller::Controller;
+use crate::nes::nsf::NsfMemory;
 use crate::nes::ppu::Ppu;
 use crate::nes::ram2k::Ram2k;
 use crate::nes::ram2k::WorkRam;
@@ -13,6 +14,7 @@ pub struct Bus {
     pub ppu: Ppu,
     pub apu: Apu,
     pub dma_cycles: u16,
+    pub nsf: Option<NsfMemory>, // replaces the cartridge when playing an NSF
 }
 
 impl Bus {
@@ -27,6 +29,7 @@ impl Bus {
             ppu: Ppu::new(),
             apu: Apu::new(),
             dma_cycles: 0,
+            nsf: None,
         }
     }
 
@@ -34,7 +37,6 @@ impl Bus {
     This function does not have any side effects
     */
     pub fn read_ram_immutable_debug(&self, location: u16) -> u8 {
-        // mapper 0
         match location {
             0x0000..=0x1FFF => {
                 return self.ram2k.memory[(location & 0x7FF) as usize];
@@ -43,19 +45,32 @@ impl Bus {
                 return self.ppu.cpuReadImmutable(&self.rom, (location & 0x7) as u8);
             }
             0x4000..=0x5FFF => {
+                if let Some(nsf) = &self.nsf {
+                    return nsf.read(location);
+                }
                 return 0;
             }
             0x6000..=0x7FFF => {
+                if let Some(nsf) = &self.nsf
+                    && nsf.maps_prg_low()
+                {
+                    return nsf.read(location);
+                }
+                if let Some(value) = self.rom.read_prg_low(location) {
+                    return value;
+                }
                 return self.workram.memory[(location & 0x1FFF) as usize];
             }
             0x8000..=0xFFFF => {
+                if let Some(nsf) = &self.nsf {
+                    return nsf.read(location);
+                }
                 return self.rom.read_prg(location);
             }
         }
     }
 
     pub fn read_ram(&mut self, location: u16) -> u8 {
-        // mapper 0
         match location {
             0x0000..=0x1FFF => {
                 return self.ram2k.memory[(location & 0x7FF) as usize];
@@ -88,20 +103,40 @@ impl Bus {
                 return 0;
             }
             0x4020..=0x5FFF => {
+                // Sound chip ports first, NSFs use them too
+                if let Some(value) = self.apu.read_expansion_register(location) {
+                    return value;
+                }
+                if let Some(nsf) = &self.nsf {
+                    return nsf.read(location);
+                }
+                if let Some(value) = self.rom.read_register(location) {
+                    return value;
+                }
                 //empty on mapper 0?
                 return 0;
             }
             0x6000..=0x7FFF => {
+                if let Some(nsf) = &self.nsf
+                    && nsf.maps_prg_low()
+                {
+                    return nsf.read(location);
+                }
+                if let Some(value) = self.rom.read_prg_low(location) {
+                    return value;
+                }
                 return self.workram.memory[(location & 0x1FFF) as usize];
             }
             0x8000..=0xFFFF => {
+                if let Some(nsf) = &self.nsf {
+                    return nsf.read(location);
+                }
                 return self.rom.read_prg(location);
             }
         }
     }
 
     pub fn write_ram(&mut self, location: u16, value: u8) {
-        // Mapper 0
         match location {
             0x0000..=0x1FFF => {
                 self.ram2k.memory[(location & 0x7FF) as usize] = value;
@@ -109,6 +144,8 @@ impl Bus {
             0x2000..=0x3FFF => {
                 self.ppu
                     .cpuWrite(&mut self.rom, (location & 0x7) as u8, value);
+                // Some boards watch PPUCTRL and PPUMASK
+                self.rom.write_ppu_register(location, value);
             }
             0x4000..=0x4013 => {
                 // APU
@@ -133,14 +170,32 @@ impl Bus {
                 //Unused APU and I/O functionality
             }
             0x4020..=0x5FFF => {
-                //empty on mapper 0?
+                self.apu.write_expansion_register(location, value);
+                // NSF bank registers at $5FF6-$5FFF
+                if let Some(nsf) = &mut self.nsf {
+                    nsf.write(location, value);
+                } else {
+                    self.rom.write_register(location, value);
+                }
             }
             0x6000..=0x7FFF => {
-                self.workram.memory[(location & 0x1FFF) as usize] = value;
+                if let Some(nsf) = &mut self.nsf
+                    && nsf.maps_prg_low()
+                {
+                    nsf.write(location, value);
+                } else if !self.rom.write_prg_ram(location, value) {
+                    self.workram.memory[(location & 0x1FFF) as usize] = value;
+                }
             }
-            // TODO extract into a mapper
             0x8000..=0xFFFF => {
-                self.rom.write_prg(location, value);
+                // Sound chips on the cartridge share the mapper's register space
+                self.apu.write_expansion_register(location, value);
+                // FDS NSFs have RAM up to $DFFF
+                if let Some(nsf) = &mut self.nsf {
+                    nsf.write(location, value);
+                } else {
+                    self.rom.write_register(location, value);
+                }
             }
         }
     }
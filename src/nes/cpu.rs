@@ -1,3 +1,4 @@
+use crate::nes::apu::DMC_DMA_CYCLES;
 use crate::nes::bus::Bus;
 use crate::nes::cpu_flag;
 use crate::nes::debugger::Debugger;
@@ -1613,16 +1614,28 @@ impl Cpu {
         } else {
             if self.bus.ppu.get_and_reset_nmi_triggered() {
                 self.trigger_nmi(); // applies nmi instantly, but adds the clock cost
+            } else if self.cycles == 0 && (self.bus.apu.irq_pending() || self.bus.rom.irq_pending())
+            {
+                // IRQ is level triggered, only taken between instructions
+                self.cycles += self.irq();
             }
             self.tick();
         }
 
-        self.bus.ppu.tick(&self.bus.rom);
-        self.bus.ppu.tick(&self.bus.rom);
-        self.bus.ppu.tick(&self.bus.rom);
+        self.bus.ppu.tick(&mut self.bus.rom);
+        self.bus.ppu.tick(&mut self.bus.rom);
+        self.bus.ppu.tick(&mut self.bus.rom);
 
-        // Clock the APU once per CPU cycle
+        // Clock the APU and the mapper once per CPU cycle
         self.bus.apu.clock();
+        self.bus.rom.clock();
+
+        // DMC sample fetch, like OAMDMA the read happens right away and the CPU is stalled after
+        if let Some(address) = self.bus.apu.dmc.dma_request() {
+            let sample = self.bus.read_ram(address);
+            self.bus.apu.dmc.load_sample_byte(sample);
+            self.bus.dma_cycles += DMC_DMA_CYCLES;
+        }
     }
 
     fn write_value(&mut self, value: u8) {
@@ -1694,28 +1707,28 @@ impl Cpu {
     }
 
     pub fn nmi(&mut self) -> u8 {
-        self.flag.set_flag_i(true);
         self.flag.set_flag_b(false);
 
+        // The pushed status keeps the old I flag so RTI restores it
         self.push_stack_u16(self.pc); // TODO verify this is right
         self.push_stack_u8(self.flag.get_sr());
+        self.flag.set_flag_i(true);
 
         self.pc = self.bus.read_ram(0xFFFA) as u16 | (self.bus.read_ram(0xFFFB) as u16) << 8;
         return 8;
     }
 
-    #[allow(dead_code)]
     pub fn irq(&mut self) -> u8 {
         if self.flag.get_flag_i() {
             return 0;
         }
         // flag.i must be 0
 
-        self.flag.set_flag_i(true);
         self.flag.set_flag_b(false);
 
         self.push_stack_u16(self.pc); // TODO verify this is right
         self.push_stack_u8(self.flag.get_sr());
+        self.flag.set_flag_i(true);
 
         // Same as BRK
         self.pc = self.bus.read_ram(0xFFFE) as u16 | (self.bus.read_ram(0xFFFF) as u16) << 8;
@@ -1877,7 +1890,7 @@ impl Cpu {
         return 0;
     }
 
-    fn push_stack_u16(&mut self, value: u16) {
+    pub fn push_stack_u16(&mut self, value: u16) {
         self.bus
             .write_ram(0x100 + self.reg_sp as u16, ((value >> 8) & 0xFF) as u8);
         self.reg_sp = decrement_u8(self.reg_sp);
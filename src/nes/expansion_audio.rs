@@ -0,0 +1,28 @@
+/*
+Sound chips on the cartridge, https://www.nesdev.org/wiki/Expansion_audio
+The cartridge's audio goes through the console's EXP pins and is mixed in after the APU. The APU
+clocks every chip once per CPU cycle and adds their output to its own, the bus forwards cartridge
+register writes to the chips.
+*/
+
+pub trait ExpansionAudio {
+    fn name(&self) -> &'static str;
+
+    /// Back to the power on state, registers cleared and silent
+    fn reset(&mut self);
+
+    /// CPU writes to $4020-$FFFF, addresses that aren't the chip's are ignored
+    fn write_register(&mut self, address: u16, value: u8);
+
+    /// CPU reads of $4020-$5FFF, None when the chip doesn't drive the address
+    fn read_register(&mut self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    /// Called once per CPU cycle
+    fn clock(&mut self);
+
+    /// Current output in the units of the APU mixer, where APU_PULSE_LEVEL is one 2A03 pulse
+    /// channel at full volume. Linear, the chips don't share the APU's nonlinear DAC.
+    fn output(&self) -> f32;
+}
@@ -0,0 +1,739 @@
+use crate::nes::apu::APU_PULSE_LEVEL;
+use crate::nes::expansion_audio::ExpansionAudio;
+use crate::nes::mapper::Mapper;
+use crate::nes::rom::Mirroring;
+use std::fs;
+use std::path::Path;
+
+/*
+Famicom Disk System, https://www.nesdev.org/wiki/Family_Computer_Disk_System
+The RAM adapter plugs into the cartridge slot. It has 32KB of PRG RAM at $6000-$DFFF, 8KB of CHR
+RAM, the BIOS at $E000-$FFFF, a CPU cycle IRQ timer and the disk drive interface at $4020-$4033.
+
+.fds images only hold the blocks of each side. The drive sees gaps, block start marks and CRCs
+between them, so every side is expanded into that raw form when loaded and the BIOS reads it one
+byte at a time like it would from the disk. CRCs aren't checked, any value reads as good.
+Writes go to the raw side and are turned back into blocks when saving, the original image is
+never written, the changes go to a sidecar next to it that is loaded in its place next time.
+
+The audio is one 64 step wavetable channel with a frequency modulation unit,
+https://www.nesdev.org/wiki/FDS_audio
+*/
+
+// Looked for in the working directory unless the fds_bios global setting says otherwise
+pub const FDS_BIOS_FILE: &str = "disksys.rom";
+pub const FDS_BIOS_SIZE: usize = 0x2000;
+const PRG_RAM_SIZE: usize = 0x8000;
+const FDS_SIDE_SIZE: usize = 65500;
+const FDS_HEADER_SIZE: usize = 16;
+
+// Gap before the first block and between blocks, in bytes
+const LEAD_IN_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+const BLOCK_START_MARK: u8 = 0x80;
+const FAKE_CRC: [u8; 2] = [0x4D, 0x62];
+
+// CPU cycles per byte under the head, the head returning to the start of the disk and the time
+// a side stays out when switching, so the BIOS notices the disk was changed
+const BYTE_CYCLES: u32 = 150;
+const REWIND_CYCLES: u32 = 50000;
+const SIDE_SWITCH_CYCLES: u32 = 1_000_000;
+
+pub struct Fds {
+    ram: Vec<u8>,
+    sides: Vec<Vec<u8>>, // raw, with gaps, marks and CRCs
+    save_path: String,
+    current_side: Option<usize>,
+    next_side: Option<usize>,
+    insert_delay: u32,
+    modified: bool,
+
+    disk_registers_enabled: bool,
+    mirroring: Mirroring,
+
+    // Timer IRQ
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_repeat: bool,
+    irq_enabled: bool,
+    timer_irq: bool,
+
+    // Drive
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    previous_crc_control: bool,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+    disk_irq: bool,
+    position: usize,
+    delay: u32,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    transfer_complete: bool,
+    read_data: u8,
+    write_data: u8,
+}
+
+impl Fds {
+    pub fn is_fds_file(filename: &str) -> bool {
+        filename.to_lowercase().ends_with(".fds")
+    }
+
+    /// Loads the disk image, or the sidecar with earlier writes to it when there is one
+    pub fn load(filename: &str) -> Result<Fds, String> {
+        let save_path = Path::new(filename).with_extension("fds.sav");
+        let path = if save_path.exists() {
+            println!("Loading disk writes from {}", save_path.display());
+            save_path.as_path()
+        } else {
+            Path::new(filename)
+        };
+        let bytes =
+            fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+        // fwNES header, optional
+        let data = if bytes.starts_with(b"FDS\x1A") {
+            &bytes[FDS_HEADER_SIZE.min(bytes.len())..]
+        } else {
+            &bytes[..]
+        };
+        if data.is_empty() || data.len() % FDS_SIDE_SIZE != 0 {
+            return Err(format!(
+                "{} isn't a disk image, {} bytes isn't a whole number of sides",
+                path.display(),
+                data.len()
+            ));
+        }
+
+        let sides: Vec<Vec<u8>> = data.chunks(FDS_SIDE_SIZE).map(side_to_raw).collect();
+        println!("Disk sides: {}", sides.len());
+
+        Ok(Fds {
+            ram: vec![0; PRG_RAM_SIZE],
+            sides,
+            save_path: save_path.to_string_lossy().into_owned(),
+            current_side: Some(0),
+            next_side: None,
+            insert_delay: 0,
+            modified: false,
+            disk_registers_enabled: true,
+            mirroring: Mirroring::HORIZONTAL,
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_repeat: false,
+            irq_enabled: false,
+            timer_irq: false,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            crc_control: false,
+            previous_crc_control: false,
+            disk_ready: false,
+            disk_irq_enabled: false,
+            disk_irq: false,
+            position: 0,
+            delay: 0,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            transfer_complete: false,
+            read_data: 0,
+            write_data: 0,
+        })
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    /// None while the drive is empty
+    pub fn current_side(&self) -> Option<usize> {
+        self.current_side
+    }
+
+    pub fn eject(&mut self) {
+        self.current_side = None;
+        self.next_side = None;
+    }
+
+    /// Ejects the current side first, the new one goes in after a delay like a real swap
+    pub fn insert(&mut self, side: usize) {
+        if side >= self.sides.len() {
+            return;
+        }
+        self.current_side = None;
+        self.next_side = Some(side);
+        self.insert_delay = SIDE_SWITCH_CYCLES;
+    }
+
+    /// Written to and the BIOS is done writing
+    pub fn needs_saving(&self) -> bool {
+        self.modified && (self.read_mode || !self.motor_on)
+    }
+
+    /// Writes every side back as an .fds image to the sidecar file
+    pub fn save(&mut self) -> Result<(), String> {
+        let mut bytes = Vec::with_capacity(FDS_HEADER_SIZE + self.sides.len() * FDS_SIDE_SIZE);
+        bytes.extend_from_slice(b"FDS\x1A");
+        bytes.push(self.sides.len() as u8);
+        bytes.resize(FDS_HEADER_SIZE, 0);
+        for side in &self.sides {
+            bytes.extend(raw_to_side(side));
+        }
+        fs::write(&self.save_path, bytes)
+            .map_err(|e| format!("Failed to write {}: {}", self.save_path, e))?;
+        println!("Saved disk writes to {}", self.save_path);
+        self.modified = false;
+        Ok(())
+    }
+
+    fn disk_inserted(&self) -> bool {
+        self.current_side.is_some()
+    }
+
+    fn write_disk_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4024 => {
+                self.write_data = value;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 => {
+                self.motor_on = value & 0x01 != 0;
+                self.reset_transfer = value & 0x02 != 0;
+                self.read_mode = value & 0x04 != 0;
+                self.mirroring = if value & 0x08 != 0 {
+                    Mirroring::HORIZONTAL
+                } else {
+                    Mirroring::VERTICAL
+                };
+                self.crc_control = value & 0x10 != 0;
+                self.disk_ready = value & 0x40 != 0;
+                self.disk_irq_enabled = value & 0x80 != 0;
+                self.disk_irq = false;
+            }
+            _ => {} // $4026 is the expansion port
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.irq_enabled {
+            return;
+        }
+        if self.irq_counter == 0 {
+            self.timer_irq = true;
+            self.irq_counter = self.irq_reload;
+            if !self.irq_repeat {
+                self.irq_enabled = false;
+            }
+        } else {
+            self.irq_counter -= 1;
+        }
+    }
+
+    // One byte passes under the head every BYTE_CYCLES while the motor runs
+    fn clock_drive(&mut self) {
+        if self.insert_delay > 0 {
+            self.insert_delay -= 1;
+            if self.insert_delay == 0 {
+                self.current_side = self.next_side.take();
+            }
+        }
+
+        let Some(side) = self.current_side else {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        };
+        if !self.motor_on {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        }
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = REWIND_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let mut irq = self.disk_irq_enabled;
+        if self.read_mode {
+            let data = self.sides[side].get(self.position).copied().unwrap_or(0);
+            if !self.disk_ready {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // The start mark ends the gap, the transfer begins with the next byte
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                if irq {
+                    self.disk_irq = true;
+                }
+            }
+        } else {
+            let mut data = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                data = self.write_data;
+                if irq {
+                    self.disk_irq = true;
+                }
+            }
+            // Until the BIOS says it's ready it is writing the gap
+            if !self.disk_ready {
+                data = 0;
+            }
+            if self.crc_control {
+                data = FAKE_CRC[self.previous_crc_control as usize];
+            }
+            if let Some(byte) = self.sides[side].get_mut(self.position) {
+                *byte = data;
+                self.modified = true;
+            }
+            self.gap_ended = false;
+        }
+        self.previous_crc_control = self.crc_control;
+
+        self.position += 1;
+        if self.position >= self.sides[side].len() {
+            self.motor_on = false;
+            self.end_of_head = true;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+}
+
+impl Mapper for Fds {
+    // The BIOS, $6000-$DFFF is RAM
+    fn map_prg(&self, address: u16) -> usize {
+        (address & 0x1FFF) as usize
+    }
+
+    fn map_chr(&self, address: u16) -> usize {
+        (address & 0x1FFF) as usize
+    }
+
+    fn read_prg_ram(&self, address: u16) -> Option<u8> {
+        match address {
+            0x6000..=0xDFFF => Some(self.ram[(address - 0x6000) as usize]),
+            _ => None,
+        }
+    }
+
+    fn write_prg_ram(&mut self, address: u16, value: u8) -> bool {
+        match address {
+            0x6000..=0xDFFF => {
+                self.ram[(address - 0x6000) as usize] = value;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4020 => self.irq_reload = (self.irq_reload & 0xFF00) | value as u16,
+            0x4021 => self.irq_reload = (self.irq_reload & 0x00FF) | (value as u16) << 8,
+            0x4022 => {
+                self.irq_repeat = value & 0x01 != 0;
+                self.irq_enabled = value & 0x02 != 0 && self.disk_registers_enabled;
+                if self.irq_enabled {
+                    self.irq_counter = self.irq_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_registers_enabled = value & 0x01 != 0;
+                if !self.disk_registers_enabled {
+                    self.irq_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024..=0x4026 if self.disk_registers_enabled => {
+                self.write_disk_register(address, value)
+            }
+            _ => {}
+        }
+    }
+
+    fn read_register(&mut self, address: u16) -> Option<u8> {
+        if !self.disk_registers_enabled {
+            return None;
+        }
+        match address {
+            0x4030 => {
+                let value = self.timer_irq as u8
+                    | (self.transfer_complete as u8) << 1
+                    | (self.end_of_head as u8) << 6;
+                self.transfer_complete = false;
+                self.timer_irq = false;
+                self.disk_irq = false;
+                Some(value)
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+                Some(self.read_data)
+            }
+            0x4032 => {
+                let inserted = self.disk_inserted();
+                Some(
+                    0x40 | !inserted as u8
+                        | ((!inserted || !self.scanning) as u8) << 1
+                        | (!inserted as u8) << 2,
+                )
+            }
+            0x4033 => Some(0x80), // battery good
+            _ => None,
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn clock(&mut self) {
+        self.clock_timer();
+        self.clock_drive();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn expansion_audio(&self) -> Option<Box<dyn ExpansionAudio>> {
+        Some(Box::new(FdsAudio::new()))
+    }
+
+    fn as_fds(&self) -> Option<&Fds> {
+        Some(self)
+    }
+
+    fn as_fds_mut(&mut self) -> Option<&mut Fds> {
+        Some(self)
+    }
+}
+
+// Block 3 is a file header with the size of the file data in block 4 at bytes 13-14
+fn block_size(block_type: u8, file_size: usize) -> Option<usize> {
+    match block_type {
+        1 => Some(56),
+        2 => Some(2),
+        3 => Some(16),
+        4 => Some(1 + file_size),
+        _ => None,
+    }
+}
+
+fn side_to_raw(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEAD_IN_GAP];
+    let mut position = 0;
+    let mut file_size = 0;
+    while let Some(size) = side.get(position).and_then(|&t| block_size(t, file_size)) {
+        let Some(block) = side.get(position..position + size) else {
+            break;
+        };
+        if block[0] == 3 {
+            file_size = block[13] as usize | (block[14] as usize) << 8;
+        }
+        raw.push(BLOCK_START_MARK);
+        raw.extend_from_slice(block);
+        raw.extend_from_slice(&FAKE_CRC);
+        raw.resize(raw.len() + BLOCK_GAP, 0);
+        position += size;
+    }
+    raw.resize(raw.len().max(FDS_SIDE_SIZE + LEAD_IN_GAP), 0);
+    raw
+}
+
+fn raw_to_side(raw: &[u8]) -> Vec<u8> {
+    let mut side = Vec::with_capacity(FDS_SIDE_SIZE);
+    let mut position = 0;
+    let mut file_size = 0;
+    loop {
+        // Skip the gap and the start mark
+        while raw.get(position) == Some(&0) {
+            position += 1;
+        }
+        position += 1;
+        let Some(size) = raw.get(position).and_then(|&t| block_size(t, file_size)) else {
+            break;
+        };
+        let Some(block) = raw.get(position..position + size) else {
+            break;
+        };
+        if block[0] == 3 {
+            file_size = block[13] as usize | (block[14] as usize) << 8;
+        }
+        side.extend_from_slice(block);
+        position += size + FAKE_CRC.len();
+    }
+    side.resize(FDS_SIDE_SIZE, 0);
+    side
+}
+
+// 64 sample wave at full volume and the loudest master volume against an APU pulse
+const FDS_LEVEL: f32 = APU_PULSE_LEVEL * 2.4;
+const MAX_OUTPUT: f32 = 63.0 * 32.0;
+
+// $4089 bits 0-1
+const MASTER_VOLUME: [f32; 4] = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+
+// The RC filter on the output, about 2kHz, for a one pole filter clocked at the CPU rate
+const LOWPASS_ALPHA: f32 = 0.007;
+
+// The mod counter changes by these for each table entry, 4 resets it
+const MOD_ADJUSTMENTS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+
+// 16 bit fraction and a 6 bit table position
+const PHASE_MASK: u32 = 0x3FFFFF;
+
+struct FdsEnvelope {
+    speed: u8,
+    gain: u8,
+    increase: bool,
+    disabled: bool,
+    counter: u32,
+}
+
+impl FdsEnvelope {
+    fn new() -> Self {
+        Self {
+            speed: 0,
+            gain: 0,
+            increase: false,
+            disabled: true,
+            counter: 0,
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.speed = value & 0x3F;
+        self.increase = value & 0x40 != 0;
+        self.disabled = value & 0x80 != 0;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.counter = 0;
+    }
+
+    fn clock(&mut self, master_speed: u8) {
+        if self.disabled {
+            return;
+        }
+        self.counter += 1;
+        if self.counter < 8 * (self.speed as u32 + 1) * master_speed as u32 {
+            return;
+        }
+        self.counter = 0;
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+pub struct FdsAudio {
+    sound_registers_enabled: bool,
+    wave_table: [u8; 64],
+    wave_write_enabled: bool,
+    wave_halted: bool,
+    wave_frequency: u16,
+    wave_phase: u32,
+    wave_sample: u8,
+    master_volume: usize,
+
+    envelopes_halted: bool,
+    envelope_speed: u8,
+    volume_envelope: FdsEnvelope,
+    mod_envelope: FdsEnvelope,
+
+    mod_table: [u8; 64],
+    mod_halted: bool,
+    mod_frequency: u16,
+    mod_phase: u32,
+    mod_counter: i8, // 7 bit signed
+
+    filtered_output: f32,
+}
+
+impl FdsAudio {
+    pub fn new() -> Self {
+        Self {
+            sound_registers_enabled: true,
+            wave_table: [0; 64],
+            wave_write_enabled: false,
+            wave_halted: true,
+            wave_frequency: 0,
+            wave_phase: 0,
+            wave_sample: 0,
+            master_volume: 0,
+            envelopes_halted: false,
+            envelope_speed: 0xE8,
+            volume_envelope: FdsEnvelope::new(),
+            mod_envelope: FdsEnvelope::new(),
+            mod_table: [0; 64],
+            mod_halted: true,
+            mod_frequency: 0,
+            mod_phase: 0,
+            mod_counter: 0,
+            filtered_output: 0.0,
+        }
+    }
+
+    fn set_mod_counter(&mut self, value: i32) {
+        // Wraps within 7 bits, -64 to 63
+        self.mod_counter = (((value + 64) & 0x7F) - 64) as i8;
+    }
+
+    // The pitch after modulation, straight from the nesdev wiki's description of the hardware
+    fn modulated_frequency(&self) -> u32 {
+        let counter = self.mod_counter as i32;
+        let mut temp = counter * self.mod_envelope.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+
+        let pitch = self.wave_frequency as i32;
+        temp *= pitch;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        (pitch + temp).max(0) as u32
+    }
+
+    fn clock_modulator(&mut self) {
+        if self.mod_halted || self.mod_frequency == 0 {
+            return;
+        }
+        let previous_step = self.mod_phase >> 16;
+        self.mod_phase = (self.mod_phase + self.mod_frequency as u32) & PHASE_MASK;
+        let step = self.mod_phase >> 16;
+        if step != previous_step {
+            let entry = self.mod_table[step as usize];
+            if entry == 4 {
+                self.mod_counter = 0;
+            } else {
+                self.set_mod_counter(
+                    self.mod_counter as i32 + MOD_ADJUSTMENTS[entry as usize] as i32,
+                );
+            }
+        }
+    }
+}
+
+impl ExpansionAudio for FdsAudio {
+    fn name(&self) -> &'static str {
+        "FDS"
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        if address == 0x4023 {
+            self.sound_registers_enabled = value & 0x02 != 0;
+            return;
+        }
+        if !self.sound_registers_enabled {
+            return;
+        }
+        match address {
+            0x4040..=0x407F if self.wave_write_enabled => {
+                self.wave_table[(address - 0x4040) as usize] = value & 0x3F;
+            }
+            0x4080 => self.volume_envelope.write(value),
+            0x4082 => self.wave_frequency = (self.wave_frequency & 0x0F00) | value as u16,
+            0x4083 => {
+                self.wave_frequency = (self.wave_frequency & 0x00FF) | ((value & 0x0F) as u16) << 8;
+                self.envelopes_halted = value & 0x40 != 0;
+                self.wave_halted = value & 0x80 != 0;
+                if self.wave_halted {
+                    self.wave_phase = 0;
+                }
+            }
+            0x4084 => self.mod_envelope.write(value),
+            0x4085 => self.set_mod_counter((value & 0x7F) as i32),
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0F00) | value as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | ((value & 0x0F) as u16) << 8;
+                self.mod_halted = value & 0x80 != 0;
+            }
+            // Each entry is used for two steps, writes fill both and move on
+            0x4088 if self.mod_halted => {
+                let step = (self.mod_phase >> 16) as usize & 0x3E;
+                self.mod_table[step] = value & 0x07;
+                self.mod_table[step + 1] = value & 0x07;
+                self.mod_phase = (self.mod_phase + (2 << 16)) & PHASE_MASK;
+            }
+            0x4089 => {
+                self.wave_write_enabled = value & 0x80 != 0;
+                self.master_volume = (value & 0x03) as usize;
+            }
+            0x408A => self.envelope_speed = value,
+            _ => {}
+        }
+    }
+
+    fn read_register(&mut self, address: u16) -> Option<u8> {
+        match address {
+            0x4040..=0x407F => Some(self.wave_table[(address - 0x4040) as usize] | 0x40),
+            0x4090 => Some(self.volume_envelope.gain | 0x40),
+            0x4092 => Some(self.mod_envelope.gain | 0x40),
+            _ => None,
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.envelopes_halted && !self.wave_halted && self.envelope_speed != 0 {
+            self.volume_envelope.clock(self.envelope_speed);
+            self.mod_envelope.clock(self.envelope_speed);
+        }
+
+        self.clock_modulator();
+
+        // The output holds while the wave is halted or being written
+        if !self.wave_halted && !self.wave_write_enabled {
+            self.wave_phase = (self.wave_phase + self.modulated_frequency()) & PHASE_MASK;
+            self.wave_sample = self.wave_table[(self.wave_phase >> 16) as usize];
+        }
+
+        let gain = self.volume_envelope.gain.min(32) as f32;
+        let level = self.wave_sample as f32 * gain * MASTER_VOLUME[self.master_volume];
+        self.filtered_output += (level / MAX_OUTPUT - self.filtered_output) * LOWPASS_ALPHA;
+    }
+
+    fn output(&self) -> f32 {
+        self.filtered_output * FDS_LEVEL
+    }
+}
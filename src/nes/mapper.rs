@@ -0,0 +1,127 @@
+use crate::nes::expansion_audio::ExpansionAudio;
+use crate::nes::fds::Fds;
+use crate::nes::mmc5::Mmc5;
+use crate::nes::n163::Namco163;
+use crate::nes::rom::Mirroring;
+use crate::nes::sunsoft5b::Fme7;
+use crate::nes::vrc4::Vrc4;
+use crate::nes::vrc6::Vrc6;
+
+/*
+Cartridge boards, https://www.nesdev.org/wiki/Mapper
+The ROM keeps the PRG/CHR data, a mapper only translates CPU and PPU addresses into offsets in it
+and handles writes to its registers. Offsets past the end of the data wrap around, so mappers
+don't need to mask bank numbers to the ROM size.
+*/
+
+/// What the PPU is reading while it renders, some boards bank differently for each
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PpuFetch {
+    Nametable,
+    Attribute,
+    BackgroundPattern,
+    SpritePattern,
+}
+
+pub trait Mapper {
+    /// Offset into PRG ROM for a CPU read of $8000-$FFFF
+    fn map_prg(&self, address: u16) -> usize;
+
+    /// Offset into CHR for a PPU read of $0000-$1FFF
+    fn map_chr(&self, address: u16) -> usize;
+
+    /// ROM mapped into $6000-$7FFF in place of the work RAM
+    fn map_prg_low(&self, _address: u16) -> Option<usize> {
+        None
+    }
+
+    /// RAM on the board at $6000-$FFFF, read before the ROM mappings
+    fn read_prg_ram(&self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    /// False when the board has no RAM at the address
+    fn write_prg_ram(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+
+    /// CPU write to $4020-$5FFF or $8000-$FFFF
+    fn write_register(&mut self, _address: u16, _value: u8) {}
+
+    /// CPU read of $4020-$5FFF, None for open bus
+    fn read_register(&mut self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    /// CPU write to the PPU registers at $2000-$3FFF, for boards that watch them
+    fn write_ppu_register(&mut self, _address: u16, _value: u8) {}
+
+    /// Called by the PPU before each read it makes for rendering
+    fn ppu_fetch(&mut self, _fetch: PpuFetch, _address: u16) {}
+
+    /// PPU read of $2000-$2FFF, None for the console VRAM
+    fn read_nametable(&self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    /// False when the console VRAM takes the write
+    fn write_nametable(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+
+    /// Nametable mirroring, None when it is fixed by the header
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    /// Called once per CPU cycle
+    fn clock(&mut self) {}
+
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    /// Sound chip on the board, handed to the APU when the ROM is loaded
+    fn expansion_audio(&self) -> Option<Box<dyn ExpansionAudio>> {
+        None
+    }
+
+    /// The disk drive, for the UI, when the cartridge is the FDS RAM adapter
+    fn as_fds(&self) -> Option<&Fds> {
+        None
+    }
+
+    fn as_fds_mut(&mut self) -> Option<&mut Fds> {
+        None
+    }
+}
+
+pub fn create_mapper(
+    mapper_number: u8,
+    submapper: u8,
+    prg_size: usize,
+) -> Result<Box<dyn Mapper>, String> {
+    match mapper_number {
+        0 => Ok(Box::new(Nrom {})),
+        5 => Ok(Box::new(Mmc5::new())),
+        19 => Ok(Box::new(Namco163::new(prg_size))),
+        21 | 22 | 23 | 25 => Ok(Box::new(Vrc4::new(mapper_number, submapper, prg_size))),
+        24 => Ok(Box::new(Vrc6::new(prg_size, false))),
+        26 => Ok(Box::new(Vrc6::new(prg_size, true))),
+        69 => Ok(Box::new(Fme7::new(prg_size))),
+        _ => Err(format!("Unsupported mapper: {}", mapper_number)),
+    }
+}
+
+// Mapper 0, no banking. 16KB PRG is mirrored into $C000 by the offset wrapping around.
+pub struct Nrom {}
+
+impl Mapper for Nrom {
+    fn map_prg(&self, address: u16) -> usize {
+        (address & 0x7FFF) as usize
+    }
+
+    fn map_chr(&self, address: u16) -> usize {
+        (address & 0x1FFF) as usize
+    }
+}
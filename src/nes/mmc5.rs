@@ -0,0 +1,519 @@
+use crate::nes::apu::{APU_PULSE_LEVEL, PulseChannel};
+use crate::nes::expansion_audio::ExpansionAudio;
+use crate::nes::mapper::{Mapper, PpuFetch};
+use crate::nes::rom::Mirroring;
+
+/*
+Nintendo MMC5, mapper 5, https://www.nesdev.org/wiki/MMC5
+PRG ROM and RAM banking in four modes, CHR banking in four modes with a second set of registers for
+the background when 8x16 sprites are on, 1KB of ExRAM usable as a nametable, for extended
+attributes or as plain RAM, fill mode, a vertical split, a scanline IRQ and a multiplier.
+
+The MMC5 has no scanline input, it watches the PPU's reads. The PPU tells us what each of its
+rendering reads is for, three reads of the same nametable address in a row only happen at the
+start of a scanline and reads stopping for a few CPU cycles means the frame is over.
+
+The audio is two pulse channels like the APU's without the sweep unit, and an 8 bit PCM channel,
+https://www.nesdev.org/wiki/MMC5_audio
+*/
+
+const PRG_BANK_8K: usize = 0x2000;
+const CHR_BANK_1K: usize = 0x400;
+const CHR_BANK_4K: usize = 0x1000;
+const EXRAM_SIZE: usize = 0x400;
+// iNES 1.0 doesn't say, this covers every board
+const PRG_RAM_SIZE: usize = 0x10000;
+
+// CPU cycles without a PPU read before the MMC5 decides rendering stopped
+const IDLE_CYCLES_OUT_OF_FRAME: u8 = 3;
+
+pub struct Mmc5 {
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2], // $5102 has to be 2 and $5103 1 for writes
+    prg_ram_bank: u8,         // $5113, for $6000-$7FFF
+    prg_banks: [u8; 4],       // $5114-$5117, bit 7 selects ROM
+    prg_ram: Vec<u8>,
+    chr_banks: [u16; 12], // $5120-$512B with the $5130 upper bits, 8-11 are the background set
+    chr_upper_bits: u8,
+    background_set_written_last: bool,
+    sprites_8x16: bool,
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    exram_mode: u8,
+    exram: [u8; EXRAM_SIZE],
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    multiplicand: u8,
+    multiplier: u8,
+
+    // What the PPU is doing, worked out from its reads
+    fetch: PpuFetch,
+    last_fetch_address: u16,
+    matching_fetches: u8,
+    idle_cycles: u8,
+    in_frame: bool,
+    scanline: u8,
+    tile: u8, // 0-33 along the scanline, 0 and 1 are fetched at the end of the one before
+    next_tile: u8,
+
+    // The tile being fetched
+    in_split: bool,
+    split_y: u16,
+    extended_attribute: u8,
+}
+
+impl Mmc5 {
+    pub fn new() -> Self {
+        Self {
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            prg_ram_bank: 0,
+            prg_banks: [0xFF; 4], // the last bank at power on
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            chr_banks: [0; 12],
+            chr_upper_bits: 0,
+            background_set_written_last: false,
+            sprites_8x16: false,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            exram_mode: 0,
+            exram: [0; EXRAM_SIZE],
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            fetch: PpuFetch::Nametable,
+            last_fetch_address: 0,
+            matching_fetches: 0,
+            idle_cycles: IDLE_CYCLES_OUT_OF_FRAME,
+            in_frame: false,
+            scanline: 0,
+            tile: 0,
+            next_tile: 0,
+            in_split: false,
+            split_y: 0,
+            extended_attribute: 0,
+        }
+    }
+
+    // 8KB bank for $8000-$FFFF and whether it's ROM, bigger banks ignore the low bits of their
+    // register. $E000-$FFFF is always ROM.
+    fn prg_bank(&self, address: u16) -> (usize, bool) {
+        let slot = ((address - 0x8000) / 0x2000) as usize;
+        let (register, bank_size) = match self.prg_mode {
+            0 => (3, 4),
+            1 => (1 + (slot / 2) * 2, 2),
+            2 if slot < 2 => (1, 2),
+            _ => (slot, 1),
+        };
+        let value = self.prg_banks[register];
+        let bank = (value & 0x7F) as usize;
+        let rom = register == 3 || value & 0x80 != 0;
+        ((bank & !(bank_size - 1)) + slot % bank_size, rom)
+    }
+
+    fn prg_ram_offset(&self, address: u16) -> Option<usize> {
+        let bank = match address {
+            0x6000..=0x7FFF => self.prg_ram_bank as usize,
+            0x8000..=0xDFFF => match self.prg_bank(address) {
+                (_, true) => return None,
+                (bank, false) => bank,
+            },
+            _ => return None,
+        };
+        Some(((bank & 0x07) * PRG_BANK_8K + (address & 0x1FFF) as usize) % self.prg_ram.len())
+    }
+
+    // 1KB bank from the sprite set, or the background set which only covers 4KB and repeats
+    fn chr_bank(&self, address: u16, background_set: bool) -> usize {
+        let slot = ((address >> 10) & 0x07) as usize;
+        let (register, bank_size, slot) = match (self.chr_mode, background_set) {
+            (0, false) => (7, 8, slot),
+            (1, false) => (3 + (slot / 4) * 4, 4, slot),
+            (2, false) => (1 + (slot / 2) * 2, 2, slot),
+            (_, false) => (slot, 1, slot),
+            (0, true) => (11, 8, slot),
+            (1, true) => (11, 4, slot & 0x03),
+            (2, true) => (9 + ((slot & 0x03) / 2) * 2, 2, slot & 0x03),
+            (_, true) => (8 + (slot & 0x03), 1, slot & 0x03),
+        };
+        self.chr_banks[register] as usize * bank_size + slot % bank_size
+    }
+
+    // Rendering reads keep coming every few CPU cycles while the PPU is drawing
+    fn ppu_rendering(&self) -> bool {
+        self.idle_cycles < IDLE_CYCLES_OUT_OF_FRAME
+    }
+
+    // $5105, 0 and 1 are the console VRAM pages, 2 ExRAM and 3 fill mode
+    fn nametable_source(&self, address: u16) -> u8 {
+        let nametable = (address >> 10) & 0x03;
+        (self.nametable_mapping >> (nametable * 2)) & 0x03
+    }
+
+    fn start_scanline(&mut self) {
+        if !self.in_frame {
+            self.in_frame = true;
+            self.scanline = 0;
+            self.irq_pending = false;
+        } else {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_compare {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    // Works out the split and extended attribute for the tile a nametable read is for
+    fn update_tile(&mut self, address: u16) {
+        let split_enabled = self.split_control & 0x80 != 0 && self.exram_mode <= 1;
+        let threshold = self.split_control & 0x1F;
+        self.in_split = split_enabled
+            && if self.split_control & 0x40 != 0 {
+                self.tile >= threshold
+            } else {
+                self.tile < threshold
+            };
+
+        if self.in_split {
+            // The first two tiles are fetched at the end of the scanline before
+            let scanline = match (self.in_frame, self.tile < 2) {
+                (false, _) => 0,
+                (true, true) => self.scanline as u16 + 1,
+                (true, false) => self.scanline as u16,
+            };
+            self.split_y = (self.split_scroll as u16 + scanline) % 240;
+        } else if self.exram_mode == 1 {
+            self.extended_attribute = self.exram[(address & 0x3FF) as usize];
+        }
+    }
+
+    fn read_split(&self, attribute: bool) -> u8 {
+        let column = (self.tile & 0x1F) as usize;
+        let row = (self.split_y / 8) as usize;
+        if !attribute {
+            return self.exram[row * 32 + column];
+        }
+        let attribute = self.exram[0x3C0 + (row / 4) * 8 + column / 4];
+        let shift = ((row & 0x02) << 1) | (column & 0x02);
+        ((attribute >> shift) & 0x03) * 0x55
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn map_prg(&self, address: u16) -> usize {
+        self.prg_bank(address).0 * PRG_BANK_8K + (address & 0x1FFF) as usize
+    }
+
+    fn map_chr(&self, address: u16) -> usize {
+        if self.ppu_rendering() && self.fetch == PpuFetch::BackgroundPattern {
+            if self.in_split {
+                // 4KB bank and the split's own fine Y
+                let offset = (address & 0xFF8) as usize | (self.split_y & 0x07) as usize;
+                return self.split_bank as usize * CHR_BANK_4K + offset;
+            }
+            if self.exram_mode == 1 {
+                let bank =
+                    (self.chr_upper_bits as usize) << 6 | (self.extended_attribute & 0x3F) as usize;
+                return bank * CHR_BANK_4K + (address & 0xFFF) as usize;
+            }
+        }
+
+        // The background set is only used with 8x16 sprites, for the background or when the
+        // CPU reads CHR after writing to it last
+        let background_set = self.sprites_8x16
+            && if self.ppu_rendering() {
+                self.fetch != PpuFetch::SpritePattern
+            } else {
+                self.background_set_written_last
+            };
+        self.chr_bank(address, background_set) * CHR_BANK_1K + (address & 0x3FF) as usize
+    }
+
+    fn read_prg_ram(&self, address: u16) -> Option<u8> {
+        self.prg_ram_offset(address)
+            .map(|offset| self.prg_ram[offset])
+    }
+
+    fn write_prg_ram(&mut self, address: u16, value: u8) -> bool {
+        let Some(offset) = self.prg_ram_offset(address) else {
+            return false;
+        };
+        if self.prg_ram_protect == [0x02, 0x01] {
+            self.prg_ram[offset] = value;
+        }
+        true
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x5100 => self.prg_mode = value & 0x03,
+            0x5101 => self.chr_mode = value & 0x03,
+            0x5102 => self.prg_ram_protect[0] = value & 0x03,
+            0x5103 => self.prg_ram_protect[1] = value & 0x03,
+            0x5104 => self.exram_mode = value & 0x03,
+            0x5105 => self.nametable_mapping = value,
+            0x5106 => self.fill_tile = value,
+            0x5107 => self.fill_attribute = value & 0x03,
+            0x5113 => self.prg_ram_bank = value,
+            0x5114..=0x5117 => self.prg_banks[(address - 0x5114) as usize] = value,
+            0x5120..=0x512B => {
+                self.chr_banks[(address - 0x5120) as usize] =
+                    value as u16 | (self.chr_upper_bits as u16) << 8;
+                self.background_set_written_last = address >= 0x5128;
+            }
+            0x5130 => self.chr_upper_bits = value & 0x03,
+            0x5200 => self.split_control = value,
+            0x5201 => self.split_scroll = value,
+            0x5202 => self.split_bank = value,
+            0x5203 => self.irq_compare = value,
+            0x5204 => self.irq_enabled = value & 0x80 != 0,
+            0x5205 => self.multiplicand = value,
+            0x5206 => self.multiplier = value,
+            // Mode 3 is read only
+            0x5C00..=0x5FFF if self.exram_mode != 3 => {
+                self.exram[(address - 0x5C00) as usize] = value;
+            }
+            _ => {}
+        }
+    }
+
+    fn read_register(&mut self, address: u16) -> Option<u8> {
+        let product = self.multiplicand as u16 * self.multiplier as u16;
+        match address {
+            0x5204 => {
+                let value = (self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6;
+                self.irq_pending = false;
+                Some(value)
+            }
+            0x5205 => Some(product as u8),
+            0x5206 => Some((product >> 8) as u8),
+            // Modes 0 and 1 are for the PPU, the CPU reads open bus
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => {
+                Some(self.exram[(address - 0x5C00) as usize])
+            }
+            _ => None,
+        }
+    }
+
+    // PPUCTRL bit 5, the sprite size decides which CHR registers the background uses
+    fn write_ppu_register(&mut self, address: u16, value: u8) {
+        if address & 0x07 == 0 {
+            self.sprites_8x16 = value & 0x20 != 0;
+        }
+    }
+
+    fn ppu_fetch(&mut self, fetch: PpuFetch, address: u16) {
+        self.idle_cycles = 0;
+        self.fetch = fetch;
+
+        if fetch == PpuFetch::Nametable && address == self.last_fetch_address {
+            self.matching_fetches += 1;
+            if self.matching_fetches == 2 {
+                self.start_scanline();
+            }
+        } else {
+            self.matching_fetches = 0;
+        }
+        self.last_fetch_address = address;
+
+        match fetch {
+            // The sprite fetches come before the first tiles of the next scanline
+            PpuFetch::SpritePattern => self.next_tile = 0,
+            PpuFetch::Nametable => {
+                // The two reads at the end of a scanline are for the same tile as the first
+                // read of the next
+                if self.matching_fetches == 0 {
+                    self.tile = self.next_tile;
+                    self.next_tile += 1;
+                }
+                self.update_tile(address);
+            }
+            _ => {}
+        }
+    }
+
+    fn read_nametable(&self, address: u16) -> Option<u8> {
+        let offset = (address & 0x3FF) as usize;
+        let attribute = offset >= 0x3C0;
+
+        if self.ppu_rendering() {
+            match self.fetch {
+                PpuFetch::Nametable | PpuFetch::Attribute if self.in_split => {
+                    return Some(self.read_split(attribute));
+                }
+                // Bits 6-7 are the palette for the tile, in every quadrant
+                PpuFetch::Attribute if self.exram_mode == 1 => {
+                    return Some((self.extended_attribute >> 6) * 0x55);
+                }
+                _ => {}
+            }
+        }
+
+        match self.nametable_source(address) {
+            0 | 1 => None,
+            2 if self.exram_mode <= 1 => Some(self.exram[offset]),
+            2 => Some(0),
+            _ if attribute => Some(self.fill_attribute * 0x55),
+            _ => Some(self.fill_tile),
+        }
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8) -> bool {
+        match self.nametable_source(address) {
+            0 | 1 => false,
+            2 => {
+                if self.exram_mode <= 1 {
+                    self.exram[(address & 0x3FF) as usize] = value;
+                }
+                true
+            }
+            _ => true, // fill mode
+        }
+    }
+
+    // The console VRAM pages in use, ExRAM and fill mode nametables fit any arrangement
+    fn mirroring(&self) -> Option<Mirroring> {
+        let pages: Vec<Option<u8>> = (0..4)
+            .map(|nametable| {
+                let source = self.nametable_source(0x2000 | (nametable << 10));
+                (source < 2).then_some(source)
+            })
+            .collect();
+        let fits = |pattern: [u8; 4]| {
+            pages
+                .iter()
+                .zip(pattern)
+                .all(|(page, expected)| page.is_none_or(|page| page == expected))
+        };
+        if fits([0, 1, 0, 1]) {
+            Some(Mirroring::VERTICAL)
+        } else if fits([0, 0, 1, 1]) {
+            Some(Mirroring::HORIZONTAL)
+        } else if fits([0, 0, 0, 0]) {
+            Some(Mirroring::SingleScreenLower)
+        } else if fits([1, 1, 1, 1]) {
+            Some(Mirroring::SingleScreenUpper)
+        } else {
+            None
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.ppu_rendering() {
+            self.idle_cycles += 1;
+            if !self.ppu_rendering() {
+                self.in_frame = false;
+                self.matching_fetches = 0;
+            }
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending && self.irq_enabled
+    }
+
+    fn expansion_audio(&self) -> Option<Box<dyn ExpansionAudio>> {
+        Some(Box::new(Mmc5Audio::new()))
+    }
+}
+
+// The MMC5 clocks the envelopes and length counters itself at a fixed 240Hz
+const FRAME_SEQUENCER_CYCLES: u16 = 7457;
+
+// PCM steps sized like the DMC's at low levels, 8 bits instead of 7
+const MMC5_PCM_STEP: f32 = 163.67 / 24329.0 / 2.0;
+
+pub struct Mmc5Audio {
+    pulse1: PulseChannel,
+    pulse2: PulseChannel,
+    pcm: u8,
+    cycles: u16,
+    odd_cycle: bool, // the pulse timers run at half the CPU clock
+}
+
+impl Mmc5Audio {
+    pub fn new() -> Self {
+        Self {
+            pulse1: PulseChannel::new(),
+            pulse2: PulseChannel::new(),
+            pcm: 0,
+            cycles: 0,
+            odd_cycle: false,
+        }
+    }
+}
+
+impl ExpansionAudio for Mmc5Audio {
+    fn name(&self) -> &'static str {
+        "MMC5"
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x5001 | 0x5005 => {} // no sweep unit
+            0x5000..=0x5003 => self.pulse1.write_register((address & 0x03) as u8, value),
+            0x5004..=0x5007 => self.pulse2.write_register((address & 0x03) as u8, value),
+            // Writes of 0 are ignored. The $5010 read mode isn't emulated, no game is known to use it
+            0x5011 if value != 0 => self.pcm = value,
+            0x5015 => {
+                self.pulse1.set_enabled(value & 0x01 != 0);
+                self.pulse2.set_enabled(value & 0x02 != 0);
+            }
+            _ => {}
+        }
+    }
+
+    fn read_register(&mut self, address: u16) -> Option<u8> {
+        match address {
+            0x5015 => {
+                Some(self.pulse1.length_active() as u8 | (self.pulse2.length_active() as u8) << 1)
+            }
+            _ => None,
+        }
+    }
+
+    fn clock(&mut self) {
+        // 7457 is odd, so the frame sequencer count can't tell which cycle the timers run on
+        self.odd_cycle = !self.odd_cycle;
+        if !self.odd_cycle {
+            self.pulse1.clock();
+            self.pulse2.clock();
+        }
+
+        self.cycles += 1;
+
+        if self.cycles == FRAME_SEQUENCER_CYCLES {
+            self.cycles = 0;
+            self.pulse1.clock_envelope();
+            self.pulse2.clock_envelope();
+            self.pulse1.clock_length_counter();
+            self.pulse2.clock_length_counter();
+        }
+
+        self.pulse1.apply_pending_writes();
+        self.pulse2.apply_pending_writes();
+    }
+
+    // Mixed linearly, the pulses at about the level of the APU's
+    fn output(&self) -> f32 {
+        let pulses = (self.pulse1.mmc5_output() + self.pulse2.mmc5_output()) as f32;
+        pulses * (APU_PULSE_LEVEL / 15.0) + self.pcm as f32 * MMC5_PCM_STEP
+    }
+}
@@ -1,13 +1,38 @@
 mod apu;
+mod apu_debug;
+mod audio_filter;
 mod audio_output;
+mod audio_recorder;
+mod audio_sync;
+mod blip;
 mod bus;
 mod controller;
 mod cpu;
 mod cpu_flag;
 mod debugger;
+mod expansion_audio;
+mod fds;
+mod mapper;
+mod mmc5;
+mod n163;
+mod nsf;
+mod ntsc;
+mod overscan;
+mod palette;
+mod png;
 mod ppu;
 mod ram2k;
+mod ring_buffer;
 mod rom;
+mod rom_database;
+mod scaler;
+mod settings;
+mod sunsoft5b;
+mod vrc4;
+mod vrc6;
+mod vrc_irq;
+mod wav;
+mod y4m;
 
 mod nes;
 pub use nes::*;
@@ -0,0 +1,255 @@
+use crate::nes::apu::APU_PULSE_LEVEL;
+use crate::nes::expansion_audio::ExpansionAudio;
+use crate::nes::mapper::Mapper;
+use crate::nes::rom::Mirroring;
+
+/*
+Namco 163, mapper 19, https://www.nesdev.org/wiki/Namco_163
+8KB PRG banks, 1KB CHR banks and a 15 bit CPU cycle IRQ counter. The nametable registers can also
+point at CHR ROM, only the common case of them selecting console VRAM is handled.
+
+The audio is up to 8 wavetable channels sharing 128 bytes of sound RAM with their registers,
+https://www.nesdev.org/wiki/Namco_163_audio
+*/
+
+const PRG_BANK_8K: usize = 0x2000;
+const CHR_BANK_1K: usize = 0x400;
+
+const IRQ_COUNTER_MAX: u16 = 0x7FFF;
+
+pub struct Namco163 {
+    prg_size: usize,
+    prg_banks: [u8; 3], // $8000, $A000, $C000, $E000 is fixed to the last bank
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Namco163 {
+    pub fn new(prg_size: usize) -> Self {
+        Self {
+            prg_size,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            nametable_banks: [0xE0, 0xE1, 0xE0, 0xE1],
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+}
+
+impl Mapper for Namco163 {
+    fn map_prg(&self, address: u16) -> usize {
+        let offset = (address & 0x1FFF) as usize;
+        match address {
+            0x8000..=0xDFFF => {
+                let bank = self.prg_banks[((address - 0x8000) / 0x2000) as usize];
+                bank as usize * PRG_BANK_8K + offset
+            }
+            _ => self.prg_size - PRG_BANK_8K + offset,
+        }
+    }
+
+    fn map_chr(&self, address: u16) -> usize {
+        let bank = self.chr_banks[((address >> 10) & 0x07) as usize];
+        bank as usize * CHR_BANK_1K + (address & 0x3FF) as usize
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address & 0xF800 {
+            0x5000 => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | value as u16;
+                self.irq_pending = false;
+            }
+            0x5800 => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | (((value & 0x7F) as u16) << 8);
+                self.irq_enabled = value & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x8000..=0xB800 => self.chr_banks[((address - 0x8000) >> 11) as usize] = value,
+            0xC000..=0xD800 => self.nametable_banks[((address - 0xC000) >> 11) as usize] = value,
+            0xE000 => self.prg_banks[0] = value & 0x3F, // bit 6 disables the sound
+            0xE800 => self.prg_banks[1] = value & 0x3F,
+            0xF000 => self.prg_banks[2] = value & 0x3F,
+            _ => {} // $4800 and $F800 are the sound RAM ports
+        }
+    }
+
+    fn read_register(&mut self, address: u16) -> Option<u8> {
+        match address & 0xF800 {
+            0x5000 => Some(self.irq_counter as u8),
+            0x5800 => Some((self.irq_counter >> 8) as u8 | ((self.irq_enabled as u8) << 7)),
+            _ => None,
+        }
+    }
+
+    // Values $E0 and up select console VRAM, bit 0 picks the 1KB page
+    fn mirroring(&self) -> Option<Mirroring> {
+        let pages = self.nametable_banks.map(|bank| bank & 0x01);
+        match pages {
+            [0, 1, 0, 1] => Some(Mirroring::VERTICAL),
+            [0, 0, 1, 1] => Some(Mirroring::HORIZONTAL),
+            [0, 0, 0, 0] => Some(Mirroring::SingleScreenLower),
+            [1, 1, 1, 1] => Some(Mirroring::SingleScreenUpper),
+            _ => None,
+        }
+    }
+
+    // Counts up every CPU cycle and stops at $7FFF
+    fn clock(&mut self) {
+        if self.irq_enabled && self.irq_counter < IRQ_COUNTER_MAX {
+            self.irq_counter += 1;
+            if self.irq_counter == IRQ_COUNTER_MAX {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn expansion_audio(&self) -> Option<Box<dyn ExpansionAudio>> {
+        Some(Box::new(Namco163Audio::new()))
+    }
+}
+
+// Channel registers are at the top of the sound RAM, the last channel at $78-$7F, the wave data
+// can use the rest
+const SOUND_RAM_SIZE: usize = 0x80;
+
+// One channel is updated every 15 CPU cycles
+const CYCLES_PER_CHANNEL: u8 = 15;
+
+// Carts vary a lot, NES 2.0 submapper 3 puts them 11-13dB over the APU. A single full volume
+// channel at full swing here is ~12dB over an APU pulse.
+const N163_LEVEL: f32 = APU_PULSE_LEVEL * 4.0 / (15.0 * 15.0);
+
+pub struct Namco163Audio {
+    ram: [u8; SOUND_RAM_SIZE],
+    address: u8,
+    auto_increment: bool,
+    sound_disabled: bool,
+    cycle_counter: u8,
+    current_channel: usize, // counts up from the last channel
+    channel_outputs: [i16; 8],
+}
+
+impl Namco163Audio {
+    pub fn new() -> Self {
+        Self {
+            ram: [0; SOUND_RAM_SIZE],
+            address: 0,
+            auto_increment: false,
+            sound_disabled: false,
+            cycle_counter: 0,
+            current_channel: 0,
+            channel_outputs: [0; 8],
+        }
+    }
+
+    // $7F bits 4-6, 1-8 channels
+    fn enabled_channels(&self) -> usize {
+        ((self.ram[0x7F] >> 4) & 0x07) as usize + 1
+    }
+
+    fn update_channel(&mut self, channel: usize) {
+        let base = 0x78 - 8 * channel;
+        let registers = &self.ram[base..base + 8];
+
+        let frequency =
+            registers[0] as u32 | (registers[2] as u32) << 8 | ((registers[4] & 0x03) as u32) << 16;
+        let length = 256 - (registers[4] & 0xFC) as u32;
+        let wave_address = registers[6] as u32;
+        let volume = (registers[7] & 0x0F) as i16;
+        let mut phase =
+            registers[1] as u32 | (registers[3] as u32) << 8 | (registers[5] as u32) << 16;
+
+        phase = (phase + frequency) % (length << 16);
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+
+        // 4 bit samples, low nibble first
+        let sample_address = ((phase >> 16) + wave_address) & 0xFF;
+        let byte = self.ram[(sample_address >> 1) as usize];
+        let sample = if sample_address & 0x01 == 0 {
+            byte & 0x0F
+        } else {
+            byte >> 4
+        };
+        self.channel_outputs[channel] = (sample as i16 - 8) * volume;
+    }
+}
+
+impl ExpansionAudio for Namco163Audio {
+    fn name(&self) -> &'static str {
+        "Namco 163"
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address & 0xF800 {
+            0x4800 => {
+                self.ram[self.address as usize] = value;
+                if self.auto_increment {
+                    self.address = (self.address + 1) & 0x7F;
+                }
+            }
+            0xE000 => self.sound_disabled = value & 0x40 != 0,
+            0xF800 => {
+                self.address = value & 0x7F;
+                self.auto_increment = value & 0x80 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn read_register(&mut self, address: u16) -> Option<u8> {
+        if address & 0xF800 != 0x4800 {
+            return None;
+        }
+        let value = self.ram[self.address as usize];
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7F;
+        }
+        Some(value)
+    }
+
+    fn clock(&mut self) {
+        if self.sound_disabled {
+            return;
+        }
+
+        self.cycle_counter += 1;
+        if self.cycle_counter < CYCLES_PER_CHANNEL {
+            return;
+        }
+        self.cycle_counter = 0;
+
+        let enabled_channels = self.enabled_channels();
+        if self.current_channel >= enabled_channels {
+            self.current_channel = 0;
+        }
+        self.update_channel(self.current_channel);
+        self.current_channel += 1;
+    }
+
+    // The chip outputs one channel at a time, averaged here like the cart's filtering would.
+    // Fewer channels means each one gets a bigger share of the time and is louder.
+    fn output(&self) -> f32 {
+        if self.sound_disabled {
+            return 0.0;
+        }
+
+        let enabled_channels = self.enabled_channels();
+        let sum: i16 = self.channel_outputs[..enabled_channels].iter().sum();
+        sum as f32 / enabled_channels as f32 * N163_LEVEL
+    }
+}
@@ -1,15 +1,42 @@
 use eframe::{App, Frame, egui};
 use egui::*;
-use std::{thread, time};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
 /*
 This file contains the GUI Debugger implementation and it is also where we execute the emulator.
 */
 
-use crate::nes::audio_output::AudioOutput;
+use crate::nes::apu::{
+    AUDIO_CHANNEL_COUNT, AudioChannel, CPU_FREQUENCY, CPU_FREQUENCY_PAL, ChannelMix,
+    DEFAULT_SAMPLE_RATE,
+};
+use crate::nes::apu_debug::{SCOPE_LENGTH, note_name};
+use crate::nes::audio_filter::FilterMode;
+use crate::nes::audio_output::{
+    AudioConfig, AudioOutput, MAX_LATENCY_MS, MIN_LATENCY_MS, SAMPLE_RATES,
+};
+use crate::nes::audio_sync::{AudioStats, RateControl};
 use crate::nes::controller::Button;
 use crate::nes::cpu::Cpu;
 use crate::nes::cpu::Opcode;
 use crate::nes::debugger::Debugger;
+use crate::nes::fds::{FDS_BIOS_FILE, Fds};
+use crate::nes::nsf::{Nsf, NsfMemory, NsfPlayer};
+use crate::nes::ntsc::{NTSC_OUTPUT_HEIGHT, NTSC_OUTPUT_WIDTH, NtscFilter};
+use crate::nes::overscan::{MAX_OVERSCAN, Overscan};
+use crate::nes::palette::{Palette, PalettePreset};
+use crate::nes::png::write_png;
+use crate::nes::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::nes::rom_database::{ROM_DATABASE_FILE, RomDatabase};
+use crate::nes::scaler::{MAX_INTEGER_SCALE, PixelScaler, PostProcessor};
+use crate::nes::settings::Settings;
+use crate::nes::wav::WavSampleFormat;
+use crate::nes::y4m::Y4mWriter;
+
+// 341 x 262 PPU dots with one skipped every other frame, at 3 dots per CPU cycle
+const NTSC_FRAME_CPU_CYCLES: f64 = 89341.5 / 3.0;
 
 struct GUIInstruction {
     addr: u16,
@@ -39,7 +66,40 @@ pub struct Nes {
     pattern_table_0_texture: Option<TextureHandle>,
     pattern_table_1_texture: Option<TextureHandle>,
     nametable_texture: Option<TextureHandle>,
-    _audio_output: Option<AudioOutput>,
+    palette: Palette,
+    frame_rgba: Vec<u8>,
+    palette_file_input: String,
+    palette_status: String,
+    show_video_window: bool,
+    show_audio_window: bool,
+    show_apu_window: bool,
+    show_disk_window: bool,
+    disk_status: String,
+    ntsc_enabled: bool,
+    ntsc_filter: NtscFilter,
+    post_processor: PostProcessor,
+    overscan: Overscan,
+    cropped_rgba: Vec<u8>,
+    rom_settings: Settings,
+    screenshot_path: String,
+    screenshot_requested: bool,
+    video_path: String,
+    video_recorder: Option<Y4mWriter>,
+    capture_status: String,
+    audio_output: Option<AudioOutput>,
+    nsf_player: Option<NsfPlayer>,
+    audio_config: AudioConfig,
+    audio_device_names: Vec<String>,
+    audio_output_status: String,
+    global_settings: Settings,
+    audio_stats: Arc<AudioStats>,
+    rate_control: RateControl,
+    recording_path: String,
+    recording_format: WavSampleFormat,
+    recording_per_channel: bool,
+    recording_status: String,
+    next_frame_time: Instant,
+    frame_cycles: u64, // CPU cycles emulated since the last frame was paced
 }
 
 impl Nes {
@@ -47,32 +107,53 @@ impl Nes {
         let mut cpu = Cpu::new();
         let debugger: Debugger = Debugger::new(debug_file);
         let step_next_count: u32 = 0;
-        cpu.bus.rom.load_rom(filename);
-        // Set PPU mirroring from ROM
-        cpu.bus.ppu.set_mirroring(cpu.bus.rom.mirroring);
-        cpu.reset();
+        let mut nsf_player = None;
+        if Nsf::is_nsf_file(filename) {
+            // Music player mode, the NSF replaces the cartridge
+            let nsf = Nsf::load(filename).unwrap_or_else(|e| panic!("{}", e));
+            cpu.bus.nsf = Some(NsfMemory::new(&nsf));
+            cpu.bus.apu.set_pal_timing(nsf.pal_timing);
+            cpu.bus.apu.set_expansion_audio(nsf.expansion_audio());
+            let mut player = NsfPlayer::new(nsf);
+            player.start_song(&mut cpu, player.current_song);
+            nsf_player = Some(player);
+        } else {
+            if Fds::is_fds_file(filename) {
+                let bios = Settings::load_global().get("fds_bios", String::from(FDS_BIOS_FILE));
+                cpu.bus
+                    .rom
+                    .load_fds(filename, &bios)
+                    .unwrap_or_else(|e| panic!("{}", e));
+            } else {
+                let database_file =
+                    Settings::load_global().get("rom_database", String::from(ROM_DATABASE_FILE));
+                cpu.bus
+                    .rom
+                    .load_rom(filename, &RomDatabase::load(&database_file));
+            }
+            cpu.bus.apu.set_pal_timing(cpu.bus.rom.pal_timing);
+            cpu.bus
+                .apu
+                .set_expansion_audio(cpu.bus.rom.expansion_audio().into_iter().collect());
+            cpu.reset();
+        }
 
         let disasm: Vec<GUIInstruction> = Vec::new();
 
         let stack_data: Vec<String> = Vec::new();
 
-        // Set up audio output
-        let audio_buffer = cpu.bus.apu.get_audio_buffer();
-        let audio_output = match AudioOutput::new(audio_buffer) {
-            Ok(output) => {
-                println!("Audio output initialized successfully");
-                Some(output)
-            }
-            Err(e) => {
-                eprintln!("Failed to initialize audio output: {}", e);
-                None
-            }
-        };
+        let rom_settings = Settings::load_for_rom(filename);
+        let overscan = Overscan::load(&rom_settings);
+
+        let global_settings = Settings::load_global();
+        let audio_config = AudioConfig::load(&global_settings);
+        let audio_stats = cpu.bus.apu.get_audio_stats();
+        let rate_control = RateControl::new(DEFAULT_SAMPLE_RATE, audio_config.latency_ms);
 
-        Self {
+        let mut nes = Self {
             cpu,
             debugger,
-            running: false,
+            running: nsf_player.is_some(),
             step_next_count,
             step_out_mode: false,
             step_frame: false,
@@ -89,7 +170,74 @@ impl Nes {
             pattern_table_0_texture: None,
             pattern_table_1_texture: None,
             nametable_texture: None,
-            _audio_output: audio_output,
+            palette: Palette::new(),
+            frame_rgba: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+            palette_file_input: String::new(),
+            palette_status: String::new(),
+            show_video_window: false,
+            show_audio_window: false,
+            show_apu_window: false,
+            show_disk_window: false,
+            disk_status: String::new(),
+            ntsc_enabled: false,
+            ntsc_filter: NtscFilter::new(),
+            post_processor: PostProcessor::new(),
+            overscan,
+            cropped_rgba: Vec::new(),
+            rom_settings,
+            screenshot_path: "screenshot.png".to_string(),
+            screenshot_requested: false,
+            video_path: "recording.y4m".to_string(),
+            video_recorder: None,
+            capture_status: String::new(),
+            audio_output: None,
+            audio_config,
+            audio_device_names: AudioOutput::device_names(),
+            audio_output_status: String::new(),
+            global_settings,
+            audio_stats,
+            rate_control,
+            recording_path: "recording.wav".to_string(),
+            recording_format: WavSampleFormat::Pcm16,
+            recording_per_channel: false,
+            recording_status: String::new(),
+            next_frame_time: Instant::now(),
+            frame_cycles: 0,
+            nsf_player,
+        };
+
+        // Set up audio output
+        nes.open_audio_output();
+        nes
+    }
+
+    // (Re)opens the audio device from audio_config, the APU resamples to whatever rate it runs at
+    // False when no stream could be opened, the status label says why
+    fn open_audio_output(&mut self) -> bool {
+        // Only one consumer of the audio buffer at a time, close the old stream first
+        self.audio_output = None;
+
+        let audio_buffer = self.cpu.bus.apu.get_audio_buffer();
+        match AudioOutput::new(
+            audio_buffer,
+            Arc::clone(&self.audio_stats),
+            &self.audio_config,
+        ) {
+            Ok(output) => {
+                println!("Audio output initialized successfully");
+                self.cpu.bus.apu.set_sample_rate(output.sample_rate());
+                self.rate_control
+                    .configure(output.sample_rate(), self.audio_config.latency_ms);
+                self.audio_output_status =
+                    format!("{} at {} Hz", output.device_name(), output.sample_rate());
+                self.audio_output = Some(output);
+                true
+            }
+            Err(e) => {
+                eprintln!("Failed to initialize audio output: {}", e);
+                self.audio_output_status = e;
+                false
+            }
         }
     }
 
@@ -277,8 +425,8 @@ impl Nes {
         self.running = false;
     }
 
-    fn nes_color_to_rgb(nes_color: u8) -> Color32 {
-        let color_u32 = crate::nes::ppu::PALETTE_LOOKUP[(nes_color & 0x3F) as usize];
+    fn nes_color_to_rgb(palette: &Palette, nes_color: u8) -> Color32 {
+        let color_u32 = palette.rgb(nes_color, 0);
         Color32::from_rgb(
             ((color_u32 >> 16) & 0xFF) as u8,
             ((color_u32 >> 8) & 0xFF) as u8,
@@ -308,12 +456,13 @@ impl Nes {
 
     fn get_palette_color(
         ppu: &crate::nes::ppu::Ppu,
+        palette: &Palette,
         palette_index: u8,
         pixel_value: u8,
     ) -> Color32 {
         if pixel_value == 0 {
             let nes_color = ppu.palette_ram[0];
-            return Self::nes_color_to_rgb(nes_color);
+            return Self::nes_color_to_rgb(palette, nes_color);
         }
 
         let palette_addr = if palette_index < 4 {
@@ -323,7 +472,7 @@ impl Nes {
         };
 
         let nes_color = ppu.palette_ram[palette_addr & 0x1F];
-        Self::nes_color_to_rgb(nes_color)
+        Self::nes_color_to_rgb(palette, nes_color)
     }
 
     fn render_pattern_table(&self, table_index: u8, palette_index: u8) -> egui::ColorImage {
@@ -341,8 +490,12 @@ impl Nes {
 
                     for col in 0..8 {
                         let pixel_value = pixels[col];
-                        let color =
-                            Self::get_palette_color(&self.cpu.bus.ppu, palette_index, pixel_value);
+                        let color = Self::get_palette_color(
+                            &self.cpu.bus.ppu,
+                            &self.palette,
+                            palette_index,
+                            pixel_value,
+                        );
 
                         let x = tile_x * 8 + col;
                         let y = tile_y * 8 + row as usize;
@@ -390,8 +543,12 @@ impl Nes {
 
                     for col in 0..8 {
                         let pixel_value = pixels[col];
-                        let color =
-                            Self::get_palette_color(&self.cpu.bus.ppu, palette_index, pixel_value);
+                        let color = Self::get_palette_color(
+                            &self.cpu.bus.ppu,
+                            &self.palette,
+                            palette_index,
+                            pixel_value,
+                        );
 
                         let x = tile_x as usize * 8 + col;
                         let y = tile_y as usize * 8 + row as usize;
@@ -458,6 +615,22 @@ impl Nes {
 
                     ui.add_space(10.0);
 
+                    // Layer toggles, only affect what is drawn
+                    ui.heading("Layers");
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut self.cpu.bus.ppu.show_background_layer, "Background");
+                        ui.checkbox(&mut self.cpu.bus.ppu.show_sprite_layer, "Sprites");
+                        if ui.button("Show all sprites").clicked() {
+                            self.cpu.bus.ppu.hidden_sprites = 0;
+                        }
+                        if ui.button("Hide all sprites").clicked() {
+                            self.cpu.bus.ppu.hidden_sprites = u64::MAX;
+                        }
+                    });
+
+                    ui.add_space(10.0);
+
                     // OAM Sprites
                     ui.heading("OAM Sprites");
                     ui.separator();
@@ -466,7 +639,9 @@ impl Nes {
                         .show(ui, |ui| {
                             ui.set_min_width(ui.available_width());
                             ui.horizontal(|ui| {
-                                ui.heading(RichText::new("#   Y  X  Tile Pal Pri H V").monospace());
+                                ui.heading(
+                                    RichText::new("Show #   Y  X  Tile Pal Pri H V").monospace(),
+                                );
                             });
 
                             for i in 0..64 {
@@ -481,13 +656,21 @@ impl Nes {
                                 let flip_h = if attr & 0x40 != 0 { "Y" } else { "N" };
                                 let flip_v = if attr & 0x80 != 0 { "Y" } else { "N" };
 
-                                ui.heading(
-                                    RichText::new(format!(
-                                        "{:02} {:02X} {:02X} {:02X}  {}  {}  {} {}",
-                                        i, y, x, tile, palette, priority, flip_h, flip_v
-                                    ))
-                                    .monospace(),
-                                );
+                                ui.horizontal(|ui| {
+                                    let sprite_bit = 1u64 << i;
+                                    let mut visible =
+                                        self.cpu.bus.ppu.hidden_sprites & sprite_bit == 0;
+                                    if ui.checkbox(&mut visible, "").changed() {
+                                        self.cpu.bus.ppu.hidden_sprites ^= sprite_bit;
+                                    }
+                                    ui.heading(
+                                        RichText::new(format!(
+                                            "{:02} {:02X} {:02X} {:02X}  {}  {}  {} {}",
+                                            i, y, x, tile, palette, priority, flip_h, flip_v
+                                        ))
+                                        .monospace(),
+                                    );
+                                });
                             }
                         });
 
@@ -615,6 +798,37 @@ impl Nes {
 
                     ui.add_space(10.0);
 
+                    // Active palette (presets or a .pal file)
+                    ui.heading("Palette");
+                    ui.separator();
+                    ui.heading(format!("Active: {}", self.palette.name));
+                    ui.horizontal(|ui| {
+                        for preset in PalettePreset::ALL {
+                            if ui.button(preset.name()).clicked() {
+                                self.palette = Palette::from_preset(preset);
+                                self.palette_status.clear();
+                            }
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.heading(".pal file:");
+                        ui.text_edit_singleline(&mut self.palette_file_input);
+                        if ui.button("Load").clicked() {
+                            match Palette::load_pal_file(self.palette_file_input.trim()) {
+                                Ok(palette) => {
+                                    self.palette = palette;
+                                    self.palette_status.clear();
+                                }
+                                Err(e) => self.palette_status = e,
+                            }
+                        }
+                    });
+                    if !self.palette_status.is_empty() {
+                        ui.heading(RichText::new(&self.palette_status).color(Color32::LIGHT_RED));
+                    }
+
+                    ui.add_space(10.0);
+
                     // Palette RAM
                     ui.heading("Palette RAM");
                     ui.separator();
@@ -625,7 +839,7 @@ impl Nes {
                             for col in 0..4 {
                                 let addr = pal * 4 + col;
                                 let nes_color = self.cpu.bus.ppu.palette_ram[addr];
-                                let rgb_color = Self::nes_color_to_rgb(nes_color);
+                                let rgb_color = Self::nes_color_to_rgb(&self.palette, nes_color);
 
                                 let (rect, _response) = ui.allocate_exact_size(
                                     egui::vec2(16.0, 16.0),
@@ -649,7 +863,7 @@ impl Nes {
                             for col in 0..4 {
                                 let addr = 0x10 + pal * 4 + col;
                                 let nes_color = self.cpu.bus.ppu.palette_ram[addr & 0x1F];
-                                let rgb_color = Self::nes_color_to_rgb(nes_color);
+                                let rgb_color = Self::nes_color_to_rgb(&self.palette, nes_color);
 
                                 let (rect, _response) = ui.allocate_exact_size(
                                     egui::vec2(16.0, 16.0),
@@ -680,6 +894,559 @@ impl Nes {
             });
     }
 
+    fn render_audio_window(&mut self, ctx: &egui::Context) {
+        egui::Window::new("Audio")
+            .collapsible(true)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.heading("Output");
+                ui.separator();
+                let config = &mut self.audio_config;
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_label("Device")
+                        .selected_text(if config.device_name.is_empty() {
+                            "Default"
+                        } else {
+                            config.device_name.as_str()
+                        })
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut config.device_name, String::new(), "Default");
+                            for name in &self.audio_device_names {
+                                ui.selectable_value(&mut config.device_name, name.clone(), name);
+                            }
+                        });
+                    if ui.button("Refresh").clicked() {
+                        self.audio_device_names = AudioOutput::device_names();
+                    }
+                });
+                egui::ComboBox::from_label("Sample rate")
+                    .selected_text(Self::sample_rate_name(config.sample_rate))
+                    .show_ui(ui, |ui| {
+                        for rate in SAMPLE_RATES {
+                            ui.selectable_value(
+                                &mut config.sample_rate,
+                                rate,
+                                Self::sample_rate_name(rate),
+                            );
+                        }
+                    });
+                ui.add(
+                    egui::Slider::new(&mut config.latency_ms, MIN_LATENCY_MS..=MAX_LATENCY_MS)
+                        .text("Latency (ms)"),
+                );
+                // Only keep settings that worked, so a bad choice can't break the next launch
+                if ui.button("Apply").clicked() && self.open_audio_output() {
+                    self.audio_config.save(&mut self.global_settings);
+                    if let Err(e) = self.global_settings.save() {
+                        eprintln!("{}", e);
+                    }
+                }
+                ui.label(&self.audio_output_status);
+
+                ui.add_space(8.0);
+                ui.heading("Output Filter");
+                ui.separator();
+                let filter = &mut self.cpu.bus.apu.output_filter;
+                for mode in FilterMode::ALL {
+                    ui.radio_value(&mut filter.mode, mode, mode.name());
+                }
+
+                ui.add_space(8.0);
+                ui.heading("Channels");
+                ui.separator();
+                egui::Grid::new("audio_channels").show(ui, |ui| {
+                    for (channel, mix) in AudioChannel::ALL
+                        .iter()
+                        .zip(self.cpu.bus.apu.channel_mix.iter_mut())
+                    {
+                        ui.label(channel.name());
+                        ui.checkbox(&mut mix.muted, "Mute");
+                        ui.checkbox(&mut mix.solo, "Solo");
+                        ui.add(egui::Slider::new(&mut mix.volume, 0.0..=1.0).text("Volume"));
+                        ui.end_row();
+                    }
+
+                    // Cartridge sound chips, one row per chip
+                    let names = self.cpu.bus.apu.expansion_audio_names();
+                    for (name, mix) in names.iter().zip(self.cpu.bus.apu.expansion_mix.iter_mut()) {
+                        ui.label(*name);
+                        ui.checkbox(&mut mix.muted, "Mute");
+                        ui.checkbox(&mut mix.solo, "Solo");
+                        ui.add(egui::Slider::new(&mut mix.volume, 0.0..=1.0).text("Volume"));
+                        ui.end_row();
+                    }
+                });
+                if ui.button("Reset channels").clicked() {
+                    let apu = &mut self.cpu.bus.apu;
+                    apu.channel_mix = [ChannelMix::new(); AUDIO_CHANNEL_COUNT];
+                    apu.expansion_mix.fill(ChannelMix::new());
+                }
+
+                ui.add_space(8.0);
+                ui.heading("Recording");
+                ui.separator();
+                let apu = &mut self.cpu.bus.apu;
+                let recording = apu.is_recording();
+                ui.add_enabled_ui(!recording, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("WAV file:");
+                        ui.text_edit_singleline(&mut self.recording_path);
+                    });
+                    ui.horizontal(|ui| {
+                        for format in WavSampleFormat::ALL {
+                            ui.radio_value(&mut self.recording_format, format, format.name());
+                        }
+                    });
+                    ui.checkbox(
+                        &mut self.recording_per_channel,
+                        "Also record each channel to its own file",
+                    );
+                });
+                if !recording && ui.button("Start recording").clicked() {
+                    self.recording_status = match apu.start_recording(
+                        &self.recording_path,
+                        self.recording_format,
+                        self.recording_per_channel,
+                    ) {
+                        Ok(()) => format!("Recording to {}", self.recording_path),
+                        Err(e) => e,
+                    };
+                }
+                if recording && ui.button("Stop recording").clicked() {
+                    self.recording_status = match apu.stop_recording() {
+                        Ok(()) => format!("Saved {}", self.recording_path),
+                        Err(e) => e,
+                    };
+                }
+                if let Some(error) = &apu.recording_error {
+                    self.recording_status = error.clone();
+                }
+                if !self.recording_status.is_empty() {
+                    ui.label(&self.recording_status);
+                }
+
+                ui.add_space(8.0);
+                ui.heading("Sync");
+                ui.separator();
+                let buffered = self.cpu.bus.apu.buffered_samples();
+                ui.label(format!(
+                    "Buffer: {} / {} samples",
+                    buffered, self.rate_control.target_fill
+                ));
+                ui.label(format!(
+                    "Rate adjustment: {:+.3}%",
+                    (self.rate_control.ratio - 1.0) * 100.0
+                ));
+                ui.label(format!(
+                    "Underruns: {}",
+                    self.audio_stats.underruns.load(Ordering::Relaxed)
+                ));
+                ui.label(format!(
+                    "Dropped samples: {}",
+                    self.audio_stats.overrun_samples.load(Ordering::Relaxed)
+                ));
+                if ui.button("Reset stats").clicked() {
+                    self.audio_stats.reset();
+                }
+            });
+    }
+
+    // Disk writes go to the sidecar as soon as the BIOS is done with them
+    fn save_disk_writes(&mut self) {
+        let Some(fds) = self.cpu.bus.rom.fds_mut() else {
+            return;
+        };
+        if fds.needs_saving()
+            && let Err(e) = fds.save()
+        {
+            self.disk_status = e;
+        }
+    }
+
+    fn render_disk_window(&mut self, ctx: &egui::Context) {
+        let Some(fds) = self.cpu.bus.rom.fds_mut() else {
+            return;
+        };
+
+        egui::Window::new("Disk")
+            .collapsible(true)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    for side in 0..fds.side_count() {
+                        let label = format!("Disk {} side {}", side / 2 + 1, ['A', 'B'][side % 2]);
+                        if ui
+                            .selectable_label(fds.current_side() == Some(side), label)
+                            .clicked()
+                        {
+                            fds.insert(side);
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("Eject").clicked() {
+                        fds.eject();
+                    }
+                    match fds.current_side() {
+                        Some(_) => ui.label("Disk inserted"),
+                        None => ui.label("Drive empty"),
+                    };
+                });
+                if !self.disk_status.is_empty() {
+                    ui.label(&self.disk_status);
+                }
+            });
+    }
+
+    fn render_apu_window(&mut self, ctx: &egui::Context) {
+        const SCOPE_WIDTH: usize = 512;
+        const SCOPE_HEIGHT: f32 = 48.0;
+        const DUTY_NAMES: [&str; 4] = ["12.5%", "25%", "50%", "75%"];
+
+        let apu = &self.cpu.bus.apu;
+        let states = apu.channel_debug_states();
+
+        egui::Window::new("APU")
+            .collapsible(true)
+            .resizable(false)
+            .show(ctx, |ui| {
+                for (i, (channel, state)) in AudioChannel::ALL.iter().zip(states.iter()).enumerate()
+                {
+                    ui.horizontal(|ui| {
+                        ui.heading(channel.name());
+                        if !state.enabled {
+                            ui.label("(silent)");
+                        }
+                    });
+
+                    ui.horizontal(|ui| {
+                        if let Some(duty) = state.duty {
+                            ui.label(format!("Duty: {}", DUTY_NAMES[duty as usize]));
+                        }
+                        ui.label(format!("Period: {}", state.period));
+                        if let Some(frequency) = state.frequency {
+                            ui.label(format!("{:.1} Hz ({})", frequency, note_name(frequency)));
+                        }
+                        ui.label(format!("Volume: {}", state.volume));
+                        if *channel != AudioChannel::Dmc {
+                            ui.label(format!("Length: {}", state.length_counter));
+                        }
+                    });
+                    ui.label(&state.detail);
+
+                    // Oscilloscope, scaled to the channel's output range
+                    let max_output = if *channel == AudioChannel::Dmc {
+                        127.0
+                    } else {
+                        15.0
+                    };
+                    let (response, painter) = ui.allocate_painter(
+                        egui::vec2(SCOPE_WIDTH as f32, SCOPE_HEIGHT),
+                        egui::Sense::hover(),
+                    );
+                    let rect = response.rect;
+                    painter.rect_filled(rect, 0.0, Color32::from_rgb(0, 32, 0));
+
+                    let stride = SCOPE_LENGTH / SCOPE_WIDTH;
+                    let points: Vec<Pos2> = apu
+                        .scope
+                        .samples(i)
+                        .step_by(stride)
+                        .enumerate()
+                        .map(|(x, sample)| {
+                            let y = rect.bottom() - (sample as f32 / max_output) * rect.height();
+                            pos2(rect.left() + x as f32, y)
+                        })
+                        .collect();
+                    painter.add(Shape::line(
+                        points,
+                        Stroke::new(1.0, Color32::from_rgb(0, 255, 0)),
+                    ));
+
+                    ui.add_space(4.0);
+                }
+            });
+    }
+
+    fn sample_rate_name(sample_rate: u32) -> String {
+        if sample_rate == 0 {
+            "Device default".to_string()
+        } else {
+            format!("{} Hz", sample_rate)
+        }
+    }
+
+    fn render_nsf_player(&mut self, ui: &mut egui::Ui) {
+        let Some(player) = &mut self.nsf_player else {
+            return;
+        };
+        let nsf = &player.nsf;
+
+        ui.heading(if nsf.title.is_empty() {
+            "Unknown title"
+        } else {
+            nsf.title.as_str()
+        });
+        ui.label(&nsf.artist);
+        ui.label(&nsf.copyright);
+        let chips = nsf.expansion_chip_names();
+        if !chips.is_empty() {
+            ui.label(format!("Expansion audio: {}", chips.join(", ")));
+        }
+        ui.label(format!(
+            "{}, {} songs{}",
+            if nsf.pal_timing { "PAL" } else { "NTSC" },
+            nsf.total_songs,
+            if nsf.uses_bankswitching() {
+                ", bankswitched"
+            } else {
+                ""
+            }
+        ));
+
+        ui.add_space(8.0);
+        let format_time = |ms: u64| format!("{}:{:02}", ms / 60_000, (ms / 1000) % 60);
+        let elapsed = format_time(player.elapsed_ms());
+        let time = match nsf.track_time_ms(player.current_song) {
+            Some(length) => format!("{} / {}", elapsed, format_time(length as u64)),
+            None => elapsed,
+        };
+        ui.heading(format!("{}  {}", nsf.track_name(player.current_song), time));
+
+        let mut selected_song = None;
+        ui.horizontal(|ui| {
+            if ui.button("Previous").clicked() && player.current_song > 0 {
+                selected_song = Some(player.current_song - 1);
+            }
+            if self.running {
+                if ui.button("Pause").clicked() {
+                    self.running = false;
+                }
+            } else if ui.button("Play").clicked() {
+                self.running = true;
+            }
+            if ui.button("Next").clicked() && player.current_song + 1 < player.nsf.total_songs {
+                selected_song = Some(player.current_song + 1);
+            }
+        });
+
+        ui.add_space(8.0);
+        ui.heading("Tracks");
+        ui.separator();
+        egui::ScrollArea::vertical()
+            .id_source("nsf_tracks")
+            .max_height(240.0)
+            .show(ui, |ui| {
+                for song in 0..player.nsf.total_songs {
+                    let selected = song == player.current_song;
+                    if ui
+                        .selectable_label(selected, player.nsf.track_name(song))
+                        .clicked()
+                    {
+                        selected_song = Some(song);
+                    }
+                }
+            });
+
+        if let Some(song) = selected_song {
+            player.start_song(&mut self.cpu, song);
+            self.running = true;
+        }
+    }
+
+    // An NSF brings its own timing, the cartridge slot is empty then
+    fn cpu_frequency(&self) -> f64 {
+        if let Some(player) = &self.nsf_player {
+            return player.nsf.cpu_frequency();
+        }
+        if self.cpu.bus.rom.pal_timing {
+            CPU_FREQUENCY_PAL
+        } else {
+            CPU_FREQUENCY
+        }
+    }
+
+    // The time the emulated CPU cycles take on the console, which keeps the video at the rate the
+    // APU resamples for. The PPU only has NTSC timing, so a PAL game runs its NTSC length frames
+    // at the PAL CPU clock. When paused nothing ran, refresh the UI at 60.0988 fps.
+    fn frame_duration(&self) -> Duration {
+        if self.frame_cycles == 0 {
+            Duration::from_secs_f64(1.0 / 60.0988)
+        } else {
+            Duration::from_secs_f64(self.frame_cycles as f64 / self.cpu_frequency())
+        }
+    }
+
+    // Steer the APU output rate so the audio buffer stays near its target fill
+    fn sync_audio(&mut self) {
+        self.audio_stats
+            .active
+            .store(self.running, Ordering::Relaxed);
+        if self.running {
+            let ratio = self
+                .rate_control
+                .update(self.cpu.bus.apu.buffered_samples());
+            self.cpu.bus.apu.set_rate_adjustment(ratio);
+        }
+    }
+
+    // Sleep until the next frame is due, if we fell behind start counting from now
+    fn wait_for_next_frame(&mut self) {
+        let now = Instant::now();
+        if now < self.next_frame_time {
+            thread::sleep(self.next_frame_time - now);
+            self.next_frame_time += self.frame_duration();
+        } else {
+            self.next_frame_time = now + self.frame_duration();
+        }
+        self.frame_cycles = 0;
+    }
+
+    fn render_video_window(&mut self, ctx: &egui::Context) {
+        egui::Window::new("Video")
+            .collapsible(true)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.heading("NTSC Filter");
+                ui.separator();
+                ui.checkbox(&mut self.ntsc_enabled, "Composite NTSC signal");
+
+                let settings = &mut self.ntsc_filter.settings;
+                ui.add(egui::Slider::new(&mut settings.sharpness, -1.0..=1.0).text("Sharpness"));
+                ui.add(egui::Slider::new(&mut settings.saturation, 0.0..=2.0).text("Saturation"));
+                ui.add(egui::Slider::new(&mut settings.hue, -45.0..=45.0).text("Hue"));
+                ui.checkbox(&mut settings.dot_crawl, "Dot crawl");
+
+                ui.add_space(8.0);
+                ui.heading("Scaling");
+                ui.separator();
+                let settings = &mut self.post_processor.settings;
+                ui.horizontal(|ui| {
+                    ui.heading("Filter:");
+                    for scaler in PixelScaler::ALL {
+                        ui.selectable_value(&mut settings.scaler, scaler, scaler.name());
+                    }
+                });
+                ui.add(egui::Slider::new(&mut settings.scale, 1..=MAX_INTEGER_SCALE).text("Scale"));
+                ui.checkbox(&mut settings.aspect_correction, "8:7 pixel aspect ratio");
+                ui.checkbox(&mut settings.scanlines, "Scanlines");
+                ui.add(
+                    egui::Slider::new(&mut settings.scanline_intensity, 0.0..=1.0)
+                        .text("Scanline intensity"),
+                );
+
+                ui.add_space(8.0);
+                ui.heading("Overscan (saved per ROM)");
+                ui.separator();
+                let overscan = &mut self.overscan;
+                let mut finished = false;
+                for (edge, label) in [
+                    (&mut overscan.top, "Top"),
+                    (&mut overscan.bottom, "Bottom"),
+                    (&mut overscan.left, "Left"),
+                    (&mut overscan.right, "Right"),
+                ] {
+                    let response = ui.add(egui::Slider::new(edge, 0..=MAX_OVERSCAN).text(label));
+                    // Write the file once an edit is done, not for every step of a drag
+                    finished |= response.drag_released()
+                        || response.lost_focus()
+                        || (response.changed() && !response.dragged());
+                }
+                if finished {
+                    self.overscan.save(&mut self.rom_settings);
+                    if let Err(e) = self.rom_settings.save() {
+                        eprintln!("{}", e);
+                    }
+                }
+
+                ui.add_space(8.0);
+                ui.heading("Capture (cropped by the overscan)");
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.label("Screenshot");
+                    ui.text_edit_singleline(&mut self.screenshot_path);
+                    if ui.button("Save").clicked() {
+                        self.screenshot_requested = true;
+                    }
+                });
+                let recording = self.video_recorder.is_some();
+                ui.add_enabled_ui(!recording, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Video (.y4m)");
+                        ui.text_edit_singleline(&mut self.video_path);
+                    });
+                });
+                if !recording && ui.button("Start recording").clicked() {
+                    self.start_video_recording();
+                }
+                if recording && ui.button("Stop recording").clicked() {
+                    self.stop_video_recording();
+                }
+                if !self.capture_status.is_empty() {
+                    ui.label(&self.capture_status);
+                }
+            });
+    }
+
+    // The size of the cropped frame, before the scalers
+    fn cropped_frame_size(&self) -> (usize, usize) {
+        let (width, height, logical_width) = if self.ntsc_enabled {
+            (NTSC_OUTPUT_WIDTH, NTSC_OUTPUT_HEIGHT, SCREEN_WIDTH)
+        } else {
+            (SCREEN_WIDTH, SCREEN_HEIGHT, SCREEN_WIDTH)
+        };
+        let overscan = &self.overscan;
+        (
+            width - (overscan.left + overscan.right) * width / logical_width,
+            height - overscan.top - overscan.bottom,
+        )
+    }
+
+    fn start_video_recording(&mut self) {
+        let (width, height) = self.cropped_frame_size();
+        let frame_rate = self.cpu_frequency() / NTSC_FRAME_CPU_CYCLES;
+        self.capture_status = match Y4mWriter::create(&self.video_path, width, height, frame_rate) {
+            Ok(recorder) => {
+                self.video_recorder = Some(recorder);
+                format!("Recording to {}", self.video_path)
+            }
+            Err(e) => e,
+        };
+    }
+
+    fn stop_video_recording(&mut self) {
+        if let Some(recorder) = self.video_recorder.take() {
+            self.capture_status = match recorder.finish() {
+                Ok(()) => format!("Saved {}", self.video_path),
+                Err(e) => e,
+            };
+        }
+    }
+
+    // Screenshots and video get the same cropped picture as the screen, without the scalers
+    fn capture_frame(&mut self, width: usize, height: usize, emulated_frame: bool) {
+        if self.screenshot_requested {
+            self.screenshot_requested = false;
+            self.capture_status =
+                match write_png(&self.screenshot_path, width, height, &self.cropped_rgba) {
+                    Ok(()) => format!("Saved {}", self.screenshot_path),
+                    Err(e) => e,
+                };
+        }
+
+        // Only frames the console produced, a paused emulator doesn't add frames to the video
+        let result = match &mut self.video_recorder {
+            Some(recorder) if emulated_frame => {
+                recorder.write_frame(&self.cropped_rgba, width, height)
+            }
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            self.video_recorder = None;
+            self.capture_status = e;
+        }
+    }
+
     fn handle_keyboard_input(&mut self, ctx: &egui::Context) {
         ctx.input(|input_state| {
             for event in &input_state.events {
@@ -736,6 +1503,14 @@ impl Nes {
         });
     }
 
+    fn execute_cycle(&mut self) {
+        self.cpu.execute_cpu_ppu();
+        self.frame_cycles += 1;
+        if let Some(player) = &mut self.nsf_player {
+            player.clock(&mut self.cpu);
+        }
+    }
+
     fn emulator_execution_loop(&mut self) {
         self.ran_instruction = false;
         let start_frame_count = self.cpu.bus.ppu.frame_counter;
@@ -754,9 +1529,9 @@ impl Nes {
             self.previous_pc = self.cpu.pc;
 
             // Start Core emulation here
-            self.cpu.execute_cpu_ppu();
+            self.execute_cycle();
             while !self.cpu.ready_to_execute_next_instruction() {
-                self.cpu.execute_cpu_ppu();
+                self.execute_cycle();
             }
             self.ran_instruction = true;
 
@@ -791,15 +1566,14 @@ impl Nes {
 
 impl App for Nes {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut Frame) {
-        let start_time = std::time::Instant::now();
         self.emulator_execution_loop();
+        self.sync_audio();
+        self.save_disk_writes();
         self.handle_keyboard_input(&ctx);
 
-        let elapsed_time: time::Duration = start_time.elapsed();
-        if elapsed_time < std::time::Duration::from_millis(16) {
-            // Refresh the UI at 60fps
-            thread::sleep(std::time::Duration::from_millis(16) - elapsed_time);
-        }
+        // Refresh the UI at the console frame rate
+        let emulated_frame = self.frame_cycles > 0;
+        self.wait_for_next_frame();
 
         ctx.request_repaint();
 
@@ -808,6 +1582,23 @@ impl App for Nes {
             self.render_ppu_debug_window(ctx);
         }
 
+        if self.show_video_window {
+            self.render_video_window(ctx);
+        }
+
+        if self.show_audio_window {
+            self.render_audio_window(ctx);
+        }
+
+        self.cpu.bus.apu.scope.enabled = self.show_apu_window;
+        if self.show_apu_window {
+            self.render_apu_window(ctx);
+        }
+
+        if self.show_disk_window {
+            self.render_disk_window(ctx);
+        }
+
         if self.ran_instruction && !self.running {
             // SUPER SUPER EXPENSIVE, this scans the entire memory map
             self.memory_dump = self.generate_memory_dump();
@@ -824,12 +1615,48 @@ impl App for Nes {
         */
         // End Keyboard Hooks
 
-        // Render image
-        self.image = Some(ctx.load_texture(
-            "ppu_preview",
-            self.cpu.bus.ppu.gbuffer.clone(), // TODO look into replacing this clone with arc?
-            egui::TextureOptions::NEAREST,
-        ));
+        // Render image, the PPU only outputs palette indices so convert them to RGBA here
+        let (frame_rgba, frame_width, frame_height) = if self.ntsc_enabled {
+            self.ntsc_filter
+                .apply(&self.cpu.bus.ppu.gbuffer, self.cpu.bus.ppu.frame_counter);
+            (
+                &self.ntsc_filter.output,
+                NTSC_OUTPUT_WIDTH,
+                NTSC_OUTPUT_HEIGHT,
+            )
+        } else {
+            self.palette
+                .frame_to_rgba(&self.cpu.bus.ppu.gbuffer, &mut self.frame_rgba);
+            (&self.frame_rgba, SCREEN_WIDTH, SCREEN_HEIGHT)
+        };
+        let (cropped_width, cropped_height, logical_width) = self.overscan.crop_rgba(
+            frame_rgba,
+            frame_width,
+            frame_height,
+            SCREEN_WIDTH,
+            &mut self.cropped_rgba,
+        );
+        self.capture_frame(cropped_width, cropped_height, emulated_frame);
+        self.post_processor.process(
+            &self.cropped_rgba,
+            cropped_width,
+            cropped_height,
+            logical_width,
+        );
+        let frame_image = ColorImage::from_rgba_unmultiplied(
+            [self.post_processor.width, self.post_processor.height],
+            &self.post_processor.output,
+        );
+        match &mut self.image {
+            Some(tex) => tex.set(frame_image, egui::TextureOptions::NEAREST),
+            None => {
+                self.image = Some(ctx.load_texture(
+                    "ppu_preview",
+                    frame_image,
+                    egui::TextureOptions::NEAREST,
+                ))
+            }
+        }
 
         // Regenerate PPU debug images if window is open and instruction ran
         if self.show_ppu_debug_window && self.ran_instruction {
@@ -867,7 +1694,10 @@ impl App for Nes {
                     self.emulator_stop();
                 }
                 if ui.button("Reset").clicked() {
-                    self.cpu.reset();
+                    match &mut self.nsf_player {
+                        Some(player) => player.start_song(&mut self.cpu, player.current_song),
+                        None => self.cpu.reset(),
+                    }
                     self.previous_pc = 0;
                     self.step_next_count = 0;
                     self.step_out_mode = false;
@@ -875,6 +1705,18 @@ impl App for Nes {
                 if ui.button("PPU").clicked() {
                     self.show_ppu_debug_window = !self.show_ppu_debug_window;
                 }
+                if ui.button("Video").clicked() {
+                    self.show_video_window = !self.show_video_window;
+                }
+                if ui.button("Audio").clicked() {
+                    self.show_audio_window = !self.show_audio_window;
+                }
+                if ui.button("APU").clicked() {
+                    self.show_apu_window = !self.show_apu_window;
+                }
+                if self.cpu.bus.rom.fds().is_some() && ui.button("Disk").clicked() {
+                    self.show_disk_window = !self.show_disk_window;
+                }
                 if ui.button("Breakpoint").clicked() {
                     self.show_breakpoint_window = !self.show_breakpoint_window;
                 }
@@ -883,9 +1725,14 @@ impl App for Nes {
 
         // Side panel first - spans full vertical space between top and bottom of window
         egui::SidePanel::right("sidebar").show(ctx, |ui| {
-            // Game Rendering Screen is here:
-            if let Some(tex) = &self.image {
-                ui.add(egui::Image::from_texture(tex).fit_to_exact_size(egui::vec2(512.0, 480.0)));
+            // Game Rendering Screen is here, or the music player for NSFs
+            if self.nsf_player.is_some() {
+                self.render_nsf_player(ui);
+            } else if let Some(tex) = &self.image {
+                ui.add(egui::Image::from_texture(tex).fit_to_exact_size(egui::vec2(
+                    self.post_processor.width as f32,
+                    self.post_processor.height as f32,
+                )));
             }
             ui.add_space(8.0);
 
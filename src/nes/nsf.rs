@@ -0,0 +1,519 @@
+use crate::nes::cpu::Cpu;
+use crate::nes::expansion_audio::ExpansionAudio;
+use crate::nes::fds::FdsAudio;
+use crate::nes::mmc5::Mmc5Audio;
+use crate::nes::n163::Namco163Audio;
+use crate::nes::sunsoft5b::Sunsoft5bAudio;
+use crate::nes::vrc6::Vrc6Audio;
+use std::fs;
+
+/*
+NSF/NSFe music rips, https://www.nesdev.org/wiki/NSF and https://www.nesdev.org/wiki/NSFe
+An NSF is the music engine of a game with a header telling us where to load it and which routines
+to call: init once per song with the song number in A, then play at a fixed rate.
+
+There is no reset vector to run, so the player pretends to be the caller. It pushes a return
+address into a tiny driver at NSF_IDLE_ADDRESS which just loops on itself, the routine returning
+there tells us it is done.
+*/
+
+const NSF_HEADER_SIZE: usize = 0x80;
+const NSF_BANK_SIZE: usize = 0x1000; // 4KB banks at $8000-$FFFF
+
+// 4KB slots at $6000-$FFFF, only FDS NSFs put banks in the first two
+const NSF_SLOT_COUNT: usize = 10;
+const NSF_FIRST_SLOT_ADDRESS: u16 = 0x6000;
+
+// JMP $4100, the CPU spins here between calls
+pub const NSF_IDLE_ADDRESS: u16 = 0x4100;
+const NSF_IDLE_DRIVER: [u8; 3] = [0x4C, 0x00, 0x41];
+
+// Default play rates in microseconds, used when the file doesn't give one
+const NSF_DEFAULT_SPEED_NTSC: u16 = 16639;
+const NSF_DEFAULT_SPEED_PAL: u16 = 19997;
+
+const CPU_FREQUENCY_NTSC: f64 = 1_789_773.0;
+const CPU_FREQUENCY_PAL: f64 = 1_662_607.0;
+
+const EXPANSION_CHIP_VRC6: u8 = 0x01;
+const EXPANSION_CHIP_FDS: u8 = 0x04;
+const EXPANSION_CHIP_MMC5: u8 = 0x08;
+const EXPANSION_CHIP_N163: u8 = 0x10;
+const EXPANSION_CHIP_SUNSOFT_5B: u8 = 0x20;
+
+const EXPANSION_CHIP_NAMES: [&str; 6] = ["VRC6", "VRC7", "FDS", "MMC5", "Namco 163", "Sunsoft 5B"];
+
+pub struct Nsf {
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub total_songs: u8,
+    pub starting_song: u8, // 0 based
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub play_speed_ntsc: u16, // microseconds between play calls
+    pub play_speed_pal: u16,
+    pub pal_timing: bool,
+    pub bankswitch_init: [u8; 8],
+    pub expansion_chips: u8,
+    pub data: Vec<u8>,
+
+    // NSFe only
+    pub track_labels: Vec<String>,
+    pub track_times_ms: Vec<Option<u32>>,
+}
+
+impl Nsf {
+    pub fn is_nsf_file(filename: &str) -> bool {
+        let filename = filename.to_lowercase();
+        filename.ends_with(".nsf") || filename.ends_with(".nsfe")
+    }
+
+    pub fn load(filename: &str) -> Result<Nsf, String> {
+        let bytes =
+            fs::read(filename).map_err(|e| format!("Failed to read {}: {}", filename, e))?;
+        if bytes.starts_with(b"NESM\x1A") {
+            Nsf::from_nsf_bytes(&bytes)
+        } else if bytes.starts_with(b"NSFE") {
+            Nsf::from_nsfe_bytes(&bytes)
+        } else {
+            Err(format!("{} is not an NSF or NSFe file", filename))
+        }
+    }
+
+    fn empty() -> Nsf {
+        Nsf {
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            total_songs: 1,
+            starting_song: 0,
+            load_address: 0x8000,
+            init_address: 0x8000,
+            play_address: 0x8000,
+            play_speed_ntsc: NSF_DEFAULT_SPEED_NTSC,
+            play_speed_pal: NSF_DEFAULT_SPEED_PAL,
+            pal_timing: false,
+            bankswitch_init: [0; 8],
+            expansion_chips: 0,
+            data: Vec::new(),
+            track_labels: Vec::new(),
+            track_times_ms: Vec::new(),
+        }
+    }
+
+    pub fn from_nsf_bytes(bytes: &[u8]) -> Result<Nsf, String> {
+        if bytes.len() <= NSF_HEADER_SIZE {
+            return Err("NSF file is too small".to_string());
+        }
+        let header = &bytes[..NSF_HEADER_SIZE];
+        let read_u16 = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+
+        let mut nsf = Nsf::empty();
+        nsf.total_songs = header[0x06];
+        nsf.starting_song = header[0x07].saturating_sub(1);
+        nsf.load_address = read_u16(0x08);
+        nsf.init_address = read_u16(0x0A);
+        nsf.play_address = read_u16(0x0C);
+        nsf.title = Self::parse_string(&header[0x0E..0x2E]);
+        nsf.artist = Self::parse_string(&header[0x2E..0x4E]);
+        nsf.copyright = Self::parse_string(&header[0x4E..0x6E]);
+        nsf.play_speed_ntsc = Self::speed_or_default(read_u16(0x6E), NSF_DEFAULT_SPEED_NTSC);
+        nsf.bankswitch_init.copy_from_slice(&header[0x70..0x78]);
+        nsf.play_speed_pal = Self::speed_or_default(read_u16(0x78), NSF_DEFAULT_SPEED_PAL);
+        // Bit 0: PAL, bit 1: dual PAL/NTSC, which we play as NTSC
+        nsf.pal_timing = header[0x7A] & 0x03 == 0x01;
+        nsf.expansion_chips = header[0x7B];
+        nsf.data = bytes[NSF_HEADER_SIZE..].to_vec();
+
+        nsf.validate()?;
+        Ok(nsf)
+    }
+
+    pub fn from_nsfe_bytes(bytes: &[u8]) -> Result<Nsf, String> {
+        let mut nsf = Nsf::empty();
+        let mut has_info = false;
+        let mut has_data = false;
+
+        let mut offset = 4; // skip "NSFE"
+        while offset + 8 <= bytes.len() {
+            let length = u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ]) as usize;
+            let id = &bytes[offset + 4..offset + 8];
+            offset += 8;
+            if offset + length > bytes.len() {
+                return Err("NSFe chunk runs past the end of the file".to_string());
+            }
+            let chunk = &bytes[offset..offset + length];
+            offset += length;
+
+            let read_u16 = |at: usize| {
+                chunk
+                    .get(at..at + 2)
+                    .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            };
+
+            match id {
+                b"INFO" => {
+                    if chunk.len() < 8 {
+                        return Err("NSFe INFO chunk is too small".to_string());
+                    }
+                    nsf.load_address = read_u16(0).unwrap_or(0x8000);
+                    nsf.init_address = read_u16(2).unwrap_or(0x8000);
+                    nsf.play_address = read_u16(4).unwrap_or(0x8000);
+                    nsf.pal_timing = chunk[6] & 0x03 == 0x01;
+                    nsf.expansion_chips = chunk[7];
+                    nsf.total_songs = chunk.get(8).copied().unwrap_or(1);
+                    nsf.starting_song = chunk.get(9).copied().unwrap_or(0);
+                    has_info = true;
+                }
+                b"DATA" => {
+                    nsf.data = chunk.to_vec();
+                    has_data = true;
+                }
+                b"BANK" => {
+                    let count = chunk.len().min(8);
+                    nsf.bankswitch_init[..count].copy_from_slice(&chunk[..count]);
+                }
+                b"RATE" => {
+                    if let Some(speed) = read_u16(0) {
+                        nsf.play_speed_ntsc = Self::speed_or_default(speed, NSF_DEFAULT_SPEED_NTSC);
+                    }
+                    if let Some(speed) = read_u16(2) {
+                        nsf.play_speed_pal = Self::speed_or_default(speed, NSF_DEFAULT_SPEED_PAL);
+                    }
+                }
+                b"auth" => {
+                    let mut strings = Self::parse_string_list(chunk).into_iter();
+                    nsf.title = strings.next().unwrap_or_default();
+                    nsf.artist = strings.next().unwrap_or_default();
+                    nsf.copyright = strings.next().unwrap_or_default();
+                }
+                b"tlbl" => {
+                    nsf.track_labels = Self::parse_string_list(chunk);
+                }
+                b"time" => {
+                    nsf.track_times_ms = chunk
+                        .chunks_exact(4)
+                        .map(|b| {
+                            let ms = i32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                            (ms >= 0).then_some(ms as u32)
+                        })
+                        .collect();
+                }
+                b"NEND" => break,
+                _ => {
+                    // Chunks starting with an uppercase letter are required to play the file
+                    if id[0].is_ascii_uppercase() {
+                        return Err(format!(
+                            "Unsupported NSFe chunk {}",
+                            String::from_utf8_lossy(id)
+                        ));
+                    }
+                }
+            }
+        }
+
+        if !has_info || !has_data {
+            return Err("NSFe file is missing its INFO or DATA chunk".to_string());
+        }
+        nsf.validate()?;
+        Ok(nsf)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.total_songs == 0 {
+            return Err("NSF has no songs".to_string());
+        }
+        // FDS NSFs run from the RAM adapter, which starts at $6000
+        let lowest_load_address = if self.uses_fds() { 0x6000 } else { 0x8000 };
+        if !self.uses_bankswitching() && self.load_address < lowest_load_address {
+            return Err(format!(
+                "NSF load address ${:04X} is below ${:04X}",
+                self.load_address, lowest_load_address
+            ));
+        }
+        Ok(())
+    }
+
+    fn speed_or_default(speed: u16, default: u16) -> u16 {
+        if speed == 0 { default } else { speed }
+    }
+
+    // Fixed size, null padded string
+    fn parse_string(bytes: &[u8]) -> String {
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+    }
+
+    // Back to back null terminated strings
+    fn parse_string_list(bytes: &[u8]) -> Vec<String> {
+        if bytes.is_empty() {
+            return Vec::new();
+        }
+        let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+        bytes
+            .split(|b| *b == 0)
+            .map(|s| String::from_utf8_lossy(s).to_string())
+            .collect()
+    }
+
+    pub fn uses_bankswitching(&self) -> bool {
+        self.bankswitch_init.iter().any(|bank| *bank != 0)
+    }
+
+    pub fn uses_fds(&self) -> bool {
+        self.expansion_chips & EXPANSION_CHIP_FDS != 0
+    }
+
+    pub fn expansion_chip_names(&self) -> Vec<&'static str> {
+        EXPANSION_CHIP_NAMES
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| self.expansion_chips & (1 << bit) != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    /// Sound chips the music uses that are emulated, the rest stay silent
+    pub fn expansion_audio(&self) -> Vec<Box<dyn ExpansionAudio>> {
+        let mut chips: Vec<Box<dyn ExpansionAudio>> = Vec::new();
+        if self.expansion_chips & EXPANSION_CHIP_VRC6 != 0 {
+            chips.push(Box::new(Vrc6Audio::new(false)));
+        }
+        if self.expansion_chips & EXPANSION_CHIP_FDS != 0 {
+            chips.push(Box::new(FdsAudio::new()));
+        }
+        if self.expansion_chips & EXPANSION_CHIP_MMC5 != 0 {
+            chips.push(Box::new(Mmc5Audio::new()));
+        }
+        if self.expansion_chips & EXPANSION_CHIP_N163 != 0 {
+            chips.push(Box::new(Namco163Audio::new()));
+        }
+        if self.expansion_chips & EXPANSION_CHIP_SUNSOFT_5B != 0 {
+            chips.push(Box::new(Sunsoft5bAudio::new()));
+        }
+        chips
+    }
+
+    pub fn track_name(&self, song: u8) -> String {
+        match self.track_labels.get(song as usize) {
+            Some(label) => format!("{}: {}", song + 1, label),
+            None => format!("Track {}", song + 1),
+        }
+    }
+
+    pub fn track_time_ms(&self, song: u8) -> Option<u32> {
+        self.track_times_ms.get(song as usize).copied().flatten()
+    }
+
+    pub fn cpu_frequency(&self) -> f64 {
+        if self.pal_timing {
+            CPU_FREQUENCY_PAL
+        } else {
+            CPU_FREQUENCY_NTSC
+        }
+    }
+
+    fn cycles_per_play(&self) -> f64 {
+        let speed = if self.pal_timing {
+            self.play_speed_pal
+        } else {
+            self.play_speed_ntsc
+        };
+        speed as f64 * self.cpu_frequency() / 1_000_000.0
+    }
+}
+
+// The NSF's view of $4100-$5FFF and $8000-$FFFF, the bus routes reads and writes here in NSF mode.
+// FDS NSFs also get $6000-$7FFF. They run from the RAM adapter's RAM, so a bank write copies the
+// bank into RAM and the code can write over itself at $6000-$DFFF.
+pub struct NsfMemory {
+    prg: Vec<u8>,
+    banks: [u8; NSF_SLOT_COUNT], // $5FF6-$5FFF, 4KB bank for each of $6000, $7000 ... $F000
+    initial_banks: [u8; NSF_SLOT_COUNT],
+    fds_ram: Option<Vec<u8>>, // $6000-$FFFF
+}
+
+impl NsfMemory {
+    pub fn new(nsf: &Nsf) -> Self {
+        let fds = nsf.uses_fds();
+        let mut initial_banks = [0; NSF_SLOT_COUNT];
+        // Bankswitched data starts at (load address & $0FFF) of its first bank
+        let padding = if nsf.uses_bankswitching() {
+            initial_banks[2..].copy_from_slice(&nsf.bankswitch_init);
+            // $5FF6/$5FF7 start with the same banks as $5FFE/$5FFF
+            if fds {
+                initial_banks[0] = nsf.bankswitch_init[6];
+                initial_banks[1] = nsf.bankswitch_init[7];
+            }
+            (nsf.load_address & 0x0FFF) as usize
+        } else {
+            let first_slot = if fds { 0 } else { 2 };
+            for (slot, bank) in initial_banks.iter_mut().enumerate().skip(first_slot) {
+                *bank = (slot - first_slot) as u8;
+            }
+            (nsf.load_address - NSF_FIRST_SLOT_ADDRESS - first_slot as u16 * 0x1000) as usize
+        };
+
+        let mut prg = vec![0; padding];
+        prg.extend_from_slice(&nsf.data);
+        let bank_count = prg.len().div_ceil(NSF_BANK_SIZE).max(NSF_SLOT_COUNT);
+        prg.resize(bank_count * NSF_BANK_SIZE, 0);
+
+        let mut memory = Self {
+            prg,
+            banks: initial_banks,
+            initial_banks,
+            fds_ram: fds.then(|| vec![0; NSF_SLOT_COUNT * NSF_BANK_SIZE]),
+        };
+        memory.reset_banks();
+        memory
+    }
+
+    pub fn reset_banks(&mut self) {
+        self.banks = self.initial_banks;
+        for slot in 0..NSF_SLOT_COUNT {
+            self.load_fds_bank(slot);
+        }
+    }
+
+    fn bank_offset(&self, slot: usize) -> usize {
+        let bank_count = self.prg.len() / NSF_BANK_SIZE;
+        (self.banks[slot] as usize % bank_count) * NSF_BANK_SIZE
+    }
+
+    fn load_fds_bank(&mut self, slot: usize) {
+        let offset = self.bank_offset(slot);
+        if let Some(ram) = &mut self.fds_ram {
+            ram[slot * NSF_BANK_SIZE..(slot + 1) * NSF_BANK_SIZE]
+                .copy_from_slice(&self.prg[offset..offset + NSF_BANK_SIZE]);
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            NSF_IDLE_ADDRESS..=0x4102 => NSF_IDLE_DRIVER[(address - NSF_IDLE_ADDRESS) as usize],
+            0x6000..=0xFFFF => {
+                let offset = (address - NSF_FIRST_SLOT_ADDRESS) as usize;
+                match &self.fds_ram {
+                    Some(ram) => ram[offset],
+                    None => {
+                        let slot = offset / NSF_BANK_SIZE;
+                        self.prg[self.bank_offset(slot) + offset % NSF_BANK_SIZE]
+                    }
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    /// $6000-$7FFF is the console's work RAM unless the NSF is for the FDS
+    pub fn maps_prg_low(&self) -> bool {
+        self.fds_ram.is_some()
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x5FF6..=0x5FF7 if self.fds_ram.is_none() => {}
+            0x5FF6..=0x5FFF => {
+                let slot = (address - 0x5FF6) as usize;
+                self.banks[slot] = value;
+                self.load_fds_bank(slot);
+            }
+            0x6000..=0xDFFF => {
+                if let Some(ram) = &mut self.fds_ram {
+                    ram[(address - NSF_FIRST_SLOT_ADDRESS) as usize] = value;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+pub struct NsfPlayer {
+    pub nsf: Nsf,
+    pub current_song: u8,
+    pub elapsed_cycles: u64,
+    cycles_until_play: f64,
+    play_pending: bool,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf) -> Self {
+        let starting_song = nsf.starting_song.min(nsf.total_songs - 1);
+        Self {
+            nsf,
+            current_song: starting_song,
+            elapsed_cycles: 0,
+            cycles_until_play: 0.0,
+            play_pending: false,
+        }
+    }
+
+    /// Resets the machine and runs the init routine of `song`
+    pub fn start_song(&mut self, cpu: &mut Cpu, song: u8) {
+        self.current_song = song.min(self.nsf.total_songs - 1);
+        self.elapsed_cycles = 0;
+        self.cycles_until_play = self.nsf.cycles_per_play();
+        self.play_pending = false;
+
+        let bus = &mut cpu.bus;
+        bus.ram2k.memory.fill(0);
+        bus.workram.memory.fill(0);
+        if let Some(memory) = &mut bus.nsf {
+            memory.reset_banks();
+        }
+
+        // Silence the APU like the NSF spec asks for
+        for address in 0x4000..=0x4013 {
+            bus.write_ram(address, 0x00);
+        }
+        bus.write_ram(0x4015, 0x00);
+        bus.write_ram(0x4015, 0x0F);
+        bus.write_ram(0x4017, 0x40);
+        bus.apu.reset_expansion_audio();
+
+        cpu.reg_a = self.current_song;
+        cpu.reg_x = self.nsf.pal_timing as u8;
+        cpu.reg_y = 0;
+        cpu.reg_sp = 0xFD;
+        cpu.flag.set_flag_i(true);
+        self.call(cpu, self.nsf.init_address);
+    }
+
+    // JSR into a routine that returns to the idle driver
+    fn call(&self, cpu: &mut Cpu, address: u16) {
+        cpu.push_stack_u16(NSF_IDLE_ADDRESS - 1); // RTS adds 1
+        cpu.pc = address;
+        cpu.cycles = 0;
+    }
+
+    /// Called every CPU cycle, calls play at the file's rate once the previous call returned
+    pub fn clock(&mut self, cpu: &mut Cpu) {
+        self.elapsed_cycles += 1;
+
+        self.cycles_until_play -= 1.0;
+        if self.cycles_until_play <= 0.0 {
+            self.cycles_until_play += self.nsf.cycles_per_play();
+            self.play_pending = true;
+        }
+
+        if self.play_pending
+            && cpu.pc == NSF_IDLE_ADDRESS
+            && cpu.ready_to_execute_next_instruction()
+        {
+            self.play_pending = false;
+            self.call(cpu, self.nsf.play_address);
+        }
+    }
+
+    pub fn elapsed_ms(&self) -> u64 {
+        (self.elapsed_cycles as f64 * 1000.0 / self.nsf.cpu_frequency()) as u64
+    }
+}
@@ -0,0 +1,156 @@
+use crate::nes::palette::{
+    NTSC_BLACK, NTSC_WHITE, PALETTE_COLOR_COUNT, PALETTE_EMPHASIS_COUNT, ntsc_signal_level,
+    yiq_to_rgb,
+};
+use crate::nes::ppu::{PIXEL_EMPHASIS_SHIFT, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/*
+Software NTSC composite filter, https://www.nesdev.org/wiki/NTSC_video
+The PPU outputs 8 samples per pixel of a square wave at 12 samples per color subcarrier cycle.
+We rebuild that signal for each scanline and decode it back to YIQ like a TV would, which gives
+the color fringing, artifact colors and dot crawl of a real composite connection.
+*/
+
+const SAMPLES_PER_PIXEL: usize = 8;
+const SAMPLES_PER_LINE: usize = SCREEN_WIDTH * SAMPLES_PER_PIXEL;
+const SUBCARRIER_PHASES: usize = 12;
+
+// Each scanline is 341 * 8 = 2728 samples long, 2728 % 12 = 4 phases of drift per line
+const LINE_PHASE_STEP: usize = 4;
+
+// Chroma is averaged over two subcarrier cycles to band-limit I and Q
+const CHROMA_WINDOW: usize = SUBCARRIER_PHASES * 2;
+
+pub const NTSC_OUTPUT_WIDTH: usize = 640;
+pub const NTSC_OUTPUT_HEIGHT: usize = SCREEN_HEIGHT;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NtscSettings {
+    pub sharpness: f32,  // -1.0 (blurry) to 1.0 (sharp, more luma artifacts)
+    pub saturation: f32, // 0.0 to 2.0
+    pub hue: f32,        // degrees, -45 to 45
+    pub dot_crawl: bool, // advance the subcarrier phase every frame like real hardware
+}
+
+impl NtscSettings {
+    pub fn new() -> Self {
+        Self {
+            sharpness: 0.0,
+            saturation: 1.0,
+            hue: 0.0,
+            dot_crawl: true,
+        }
+    }
+}
+
+pub struct NtscFilter {
+    pub settings: NtscSettings,
+    pub output: Vec<u8>, // RGBA8, NTSC_OUTPUT_WIDTH x NTSC_OUTPUT_HEIGHT
+
+    // Normalized signal level for every (pixel value, phase) pair
+    signal_table: Vec<f32>,
+
+    // Per-line prefix sums of the signal, the signal times cos(phase) and times sin(phase)
+    sum_y: Vec<f32>,
+    sum_i: Vec<f32>,
+    sum_q: Vec<f32>,
+}
+
+impl NtscFilter {
+    pub fn new() -> Self {
+        let pixel_values = PALETTE_COLOR_COUNT * PALETTE_EMPHASIS_COUNT;
+        let mut signal_table = vec![0.0; pixel_values * SUBCARRIER_PHASES];
+        for pixel in 0..pixel_values {
+            for phase in 0..SUBCARRIER_PHASES {
+                let signal = ntsc_signal_level(
+                    (pixel & 0x3F) as u8,
+                    (pixel >> PIXEL_EMPHASIS_SHIFT) as u8,
+                    phase as u32,
+                );
+                signal_table[pixel * SUBCARRIER_PHASES + phase] =
+                    (signal - NTSC_BLACK) / (NTSC_WHITE - NTSC_BLACK);
+            }
+        }
+
+        Self {
+            settings: NtscSettings::new(),
+            output: vec![0; NTSC_OUTPUT_WIDTH * NTSC_OUTPUT_HEIGHT * 4],
+            signal_table,
+            sum_y: vec![0.0; SAMPLES_PER_LINE + 1],
+            sum_i: vec![0.0; SAMPLES_PER_LINE + 1],
+            sum_q: vec![0.0; SAMPLES_PER_LINE + 1],
+        }
+    }
+
+    /// Filters a frame of palette indices (see `Ppu::gbuffer`) into `output`
+    pub fn apply(&mut self, frame: &[u16], frame_counter: u16) {
+        let frame_phase = if self.settings.dot_crawl {
+            (frame_counter as usize * LINE_PHASE_STEP) % SUBCARRIER_PHASES
+        } else {
+            0
+        };
+
+        // Demodulation carriers for each phase, 3.9 phases matches Palette::generate_ntsc
+        let hue_offset = self.settings.hue.to_radians();
+        let mut carrier_cos = [0.0f32; SUBCARRIER_PHASES];
+        let mut carrier_sin = [0.0f32; SUBCARRIER_PHASES];
+        for phase in 0..SUBCARRIER_PHASES {
+            let angle = std::f32::consts::PI * (phase as f32 + 3.9) / 6.0 + hue_offset;
+            carrier_cos[phase] = angle.cos();
+            carrier_sin[phase] = angle.sin();
+        }
+
+        // A full subcarrier cycle of luma cancels out the chroma, less is sharper but noisier
+        let luma_window =
+            ((SUBCARRIER_PHASES as f32) * (1.0 - 0.5 * self.settings.sharpness)).round() as usize;
+        let luma_window = luma_window.clamp(4, CHROMA_WINDOW);
+        let saturation = self.settings.saturation;
+
+        for y in 0..SCREEN_HEIGHT {
+            let line_phase = (frame_phase + y * LINE_PHASE_STEP) % SUBCARRIER_PHASES;
+            let line = &frame[y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH];
+
+            // Generate the composite signal for the line and accumulate the prefix sums
+            let mut acc_y = 0.0;
+            let mut acc_i = 0.0;
+            let mut acc_q = 0.0;
+            for (x, pixel) in line.iter().enumerate() {
+                let table_offset = ((*pixel as usize) & 0x1FF) * SUBCARRIER_PHASES;
+                for s in 0..SAMPLES_PER_PIXEL {
+                    let n = x * SAMPLES_PER_PIXEL + s;
+                    let phase = (n + line_phase) % SUBCARRIER_PHASES;
+                    let level = self.signal_table[table_offset + phase];
+                    acc_y += level;
+                    acc_i += level * carrier_cos[phase];
+                    acc_q += level * carrier_sin[phase];
+                    self.sum_y[n + 1] = acc_y;
+                    self.sum_i[n + 1] = acc_i;
+                    self.sum_q[n + 1] = acc_q;
+                }
+            }
+
+            // Decode, each output pixel is centered on its position in the sample stream
+            for out_x in 0..NTSC_OUTPUT_WIDTH {
+                let center = (out_x * SAMPLES_PER_LINE + SAMPLES_PER_LINE / 2) / NTSC_OUTPUT_WIDTH;
+
+                let luma = Self::window_average(&self.sum_y, center, luma_window);
+                let i = Self::window_average(&self.sum_i, center, CHROMA_WINDOW) * saturation;
+                let q = Self::window_average(&self.sum_q, center, CHROMA_WINDOW) * saturation;
+
+                let (r, g, b) = yiq_to_rgb(luma, i, q);
+                let offset = (y * NTSC_OUTPUT_WIDTH + out_x) * 4;
+                self.output[offset] = r;
+                self.output[offset + 1] = g;
+                self.output[offset + 2] = b;
+                self.output[offset + 3] = 0xFF;
+            }
+        }
+    }
+
+    // Average of the samples in a window around `center`, clipped to the line edges
+    fn window_average(prefix_sum: &[f32], center: usize, window: usize) -> f32 {
+        let start = center.saturating_sub(window / 2);
+        let end = (start + window).min(SAMPLES_PER_LINE);
+        (prefix_sum[end] - prefix_sum[start]) / ((end - start) as f32)
+    }
+}
@@ -0,0 +1,79 @@
+use crate::nes::settings::Settings;
+
+/*
+NTSC TVs hide roughly 8 lines at the top and bottom of the picture and a lot of games leave garbage
+there (scroll seams, mapper IRQ timing glitches), so the displayed frame gets cropped per edge.
+Values are in NES pixels and are applied before any scaling.
+*/
+
+pub const MAX_OVERSCAN: usize = 32;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl Overscan {
+    pub fn new() -> Self {
+        Self {
+            top: 8,
+            bottom: 8,
+            left: 0,
+            right: 0,
+        }
+    }
+
+    pub fn load(settings: &Settings) -> Self {
+        let defaults = Overscan::new();
+        Self {
+            top: settings.get("overscan_top", defaults.top).min(MAX_OVERSCAN),
+            bottom: settings
+                .get("overscan_bottom", defaults.bottom)
+                .min(MAX_OVERSCAN),
+            left: settings
+                .get("overscan_left", defaults.left)
+                .min(MAX_OVERSCAN),
+            right: settings
+                .get("overscan_right", defaults.right)
+                .min(MAX_OVERSCAN),
+        }
+    }
+
+    pub fn save(&self, settings: &mut Settings) {
+        settings.set("overscan_top", self.top);
+        settings.set("overscan_bottom", self.bottom);
+        settings.set("overscan_left", self.left);
+        settings.set("overscan_right", self.right);
+    }
+
+    /// Crops an RGBA frame into `out`. Frames wider than `logical_width` (NTSC filter output) get
+    /// their columns cropped proportionally. Returns the cropped (width, height, logical_width).
+    pub fn crop_rgba(
+        &self,
+        rgba: &[u8],
+        width: usize,
+        height: usize,
+        logical_width: usize,
+        out: &mut Vec<u8>,
+    ) -> (usize, usize, usize) {
+        let left = self.left * width / logical_width;
+        let right = self.right * width / logical_width;
+        let cropped_width = width - left - right;
+        let cropped_height = height - self.top - self.bottom;
+
+        out.clear();
+        for y in self.top..(height - self.bottom) {
+            let row_start = (y * width + left) * 4;
+            out.extend_from_slice(&rgba[row_start..row_start + cropped_width * 4]);
+        }
+
+        (
+            cropped_width,
+            cropped_height,
+            logical_width - self.left - self.right,
+        )
+    }
+}
@@ -0,0 +1,276 @@
+use crate::nes::ppu::PIXEL_EMPHASIS_SHIFT;
+use std::fs;
+
+// https://www.nesdev.org/wiki/PPU_palettes
+// Colors are stored as 0xRRGGBB, 64 base colors for each of the 8 emphasis combinations.
+pub const PALETTE_COLOR_COUNT: usize = 64;
+pub const PALETTE_EMPHASIS_COUNT: usize = 8;
+
+const PAL_FILE_SIZE: usize = PALETTE_COLOR_COUNT * 3; // 192 bytes
+const PAL_FILE_SIZE_WITH_EMPHASIS: usize = PALETTE_COLOR_COUNT * PALETTE_EMPHASIS_COUNT * 3; // 1536 bytes
+
+// Emphasis darkens the channels that are NOT emphasized (measured ~0.75-0.82 on real hardware)
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+pub static DEFAULT_PALETTE: [u32; 64] = [
+    0x545454, 0x001E74, 0x081090, 0x300088, 0x440064, 0x5C0030, 0x540400, 0x3C1800, 0x202A00,
+    0x083A00, 0x004000, 0x003C00, 0x00323C, 0x000000, 0x000000, 0x000000, 0x989698, 0x084CC4,
+    0x3032EC, 0x5C1EE4, 0x8814B0, 0xA01464, 0x982220, 0x783C00, 0x545A00, 0x287200, 0x087C00,
+    0x007628, 0x006678, 0x000000, 0x000000, 0x000000, 0xECEEEC, 0x4C9AEC, 0x787CEC, 0xB062EC,
+    0xE454EC, 0xEC58B4, 0xEC6A64, 0xD48820, 0xA0AA00, 0x74C400, 0x4CD020, 0x38CC6C, 0x38B4CC,
+    0x3C3C3C, 0x000000, 0x000000, 0xECEEEC, 0xA8CCEC, 0xBCBCEC, 0xD4B2EC, 0xECAEEC, 0xECAED4,
+    0xECB4B0, 0xE4C490, 0xCCD278, 0xB4DE78, 0xA8E290, 0x98E2B4, 0xA0D6E4, 0xA0A2A0, 0x000000,
+    0x000000,
+];
+
+// The "classic" 2C02 palette used by a lot of older emulators (FCEU and friends)
+pub static CLASSIC_PALETTE: [u32; 64] = [
+    0x747474, 0x24188C, 0x0000A8, 0x44009C, 0x8C0074, 0xA80010, 0xA40000, 0x7C0800, 0x402C00,
+    0x004400, 0x005000, 0x003C14, 0x183C5C, 0x000000, 0x000000, 0x000000, 0xBCBCBC, 0x0070EC,
+    0x2038EC, 0x8000F0, 0xBC00BC, 0xE40058, 0xD82800, 0xC84C0C, 0x887000, 0x009400, 0x00A800,
+    0x009038, 0x008088, 0x000000, 0x000000, 0x000000, 0xFCFCFC, 0x3CBCFC, 0x5C94FC, 0xCC88FC,
+    0xF478FC, 0xFC74B4, 0xFC7460, 0xFC9838, 0xF0BC3C, 0x80D010, 0x4CDC48, 0x58F898, 0x00E8D8,
+    0x787878, 0x000000, 0x000000, 0xFCFCFC, 0xA8E4FC, 0xC4D4FC, 0xD4C8FC, 0xFCC4FC, 0xFCC4D8,
+    0xFCBCB0, 0xFCD8A8, 0xFCE4A0, 0xE0FCA0, 0xA8F0BC, 0xB0FCCC, 0x9CFCF0, 0xC4C4C4, 0x000000,
+    0x000000,
+];
+
+// Composite signal levels in volts, https://www.nesdev.org/wiki/NTSC_video
+pub const NTSC_SIGNAL_LOW: [f32; 4] = [0.228, 0.312, 0.552, 0.880];
+pub const NTSC_SIGNAL_HIGH: [f32; 4] = [0.616, 0.840, 1.100, 1.100];
+pub const NTSC_BLACK: f32 = 0.312;
+pub const NTSC_WHITE: f32 = 1.100;
+pub const NTSC_EMPHASIS_ATTENUATION: f32 = 0.746;
+
+/// True when the square wave for `color` is high during the given 1/12th of the subcarrier.
+pub fn ntsc_in_color_phase(color: u8, phase: u32) -> bool {
+    (color as u32 + phase) % 12 < 6
+}
+
+/// Composite voltage output for a palette index + emphasis bits during one subcarrier phase.
+pub fn ntsc_signal_level(color_index: u8, emphasis: u8, phase: u32) -> f32 {
+    let color = color_index & 0x0F;
+    let level = if color > 0x0D {
+        1 // $xE and $xF are forced black
+    } else {
+        ((color_index >> 4) & 0x03) as usize
+    };
+
+    let mut low = NTSC_SIGNAL_LOW[level];
+    let mut high = NTSC_SIGNAL_HIGH[level];
+    if color == 0x00 {
+        low = high; // greys are a flat high level
+    }
+    if color > 0x0C {
+        high = low; // $xD-$xF are a flat low level
+    }
+
+    let mut signal = if ntsc_in_color_phase(color, phase) {
+        high
+    } else {
+        low
+    };
+
+    let attenuate = ((emphasis & 0x01 != 0) && ntsc_in_color_phase(0x0C, phase))
+        || ((emphasis & 0x02 != 0) && ntsc_in_color_phase(0x04, phase))
+        || ((emphasis & 0x04 != 0) && ntsc_in_color_phase(0x08, phase));
+    if attenuate && color < 0x0E {
+        signal *= NTSC_EMPHASIS_ATTENUATION;
+    }
+
+    signal
+}
+
+/// Standard FCC YIQ -> RGB conversion, each output clamped to 0-255
+pub fn yiq_to_rgb(y: f32, i: f32, q: f32) -> (u8, u8, u8) {
+    let to_u8 = |v: f32| (v * 255.0).clamp(0.0, 255.0) as u8;
+    (
+        to_u8(y + 0.946882 * i + 0.623557 * q),
+        to_u8(y - 0.274788 * i - 0.635691 * q),
+        to_u8(y - 1.108545 * i + 1.709007 * q),
+    )
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PalettePreset {
+    Default,
+    Classic,
+    GeneratedNtsc,
+}
+
+impl PalettePreset {
+    pub const ALL: [PalettePreset; 3] = [
+        PalettePreset::Default,
+        PalettePreset::Classic,
+        PalettePreset::GeneratedNtsc,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PalettePreset::Default => "Default",
+            PalettePreset::Classic => "Classic (FCEU)",
+            PalettePreset::GeneratedNtsc => "Generated NTSC",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Palette {
+    pub name: String,
+    colors: [u32; PALETTE_COLOR_COUNT * PALETTE_EMPHASIS_COUNT],
+}
+
+impl Palette {
+    pub fn new() -> Palette {
+        Palette::from_preset(PalettePreset::Default)
+    }
+
+    pub fn from_preset(preset: PalettePreset) -> Palette {
+        match preset {
+            PalettePreset::Default => Palette::from_base_colors(preset.name(), &DEFAULT_PALETTE),
+            PalettePreset::Classic => Palette::from_base_colors(preset.name(), &CLASSIC_PALETTE),
+            PalettePreset::GeneratedNtsc => Palette::generate_ntsc(0.0, 1.0),
+        }
+    }
+
+    /// Builds the 8 emphasis variants from the 64 base colors
+    fn from_base_colors(name: &str, base: &[u32; PALETTE_COLOR_COUNT]) -> Palette {
+        let mut colors = [0u32; PALETTE_COLOR_COUNT * PALETTE_EMPHASIS_COUNT];
+        for emphasis in 0..PALETTE_EMPHASIS_COUNT {
+            for index in 0..PALETTE_COLOR_COUNT {
+                let color = base[index];
+                colors[emphasis * PALETTE_COLOR_COUNT + index] =
+                    if emphasis == 0 || (index & 0x0F) >= 0x0E {
+                        color
+                    } else {
+                        Self::apply_emphasis(color, emphasis as u8)
+                    };
+            }
+        }
+
+        Palette {
+            name: name.to_string(),
+            colors,
+        }
+    }
+
+    fn apply_emphasis(color: u32, emphasis: u8) -> u32 {
+        let mut r = ((color >> 16) & 0xFF) as f32;
+        let mut g = ((color >> 8) & 0xFF) as f32;
+        let mut b = (color & 0xFF) as f32;
+
+        // Bit 0 = red, bit 1 = green, bit 2 = blue (NTSC ordering)
+        if emphasis & 0x01 == 0 {
+            r *= EMPHASIS_ATTENUATION;
+        }
+        if emphasis & 0x02 == 0 {
+            g *= EMPHASIS_ATTENUATION;
+        }
+        if emphasis & 0x04 == 0 {
+            b *= EMPHASIS_ATTENUATION;
+        }
+
+        ((r as u32) << 16) | ((g as u32) << 8) | (b as u32)
+    }
+
+    /// Decodes a palette from the composite signal the PPU generates, hue is in degrees
+    pub fn generate_ntsc(hue: f32, saturation: f32) -> Palette {
+        let mut colors = [0u32; PALETTE_COLOR_COUNT * PALETTE_EMPHASIS_COUNT];
+        let hue_offset = hue.to_radians();
+
+        for emphasis in 0..PALETTE_EMPHASIS_COUNT {
+            for index in 0..PALETTE_COLOR_COUNT {
+                let (mut y, mut i, mut q) = (0.0f32, 0.0f32, 0.0f32);
+                for phase in 0..12 {
+                    let signal = ntsc_signal_level(index as u8, emphasis as u8, phase);
+                    let level = (signal - NTSC_BLACK) / (NTSC_WHITE - NTSC_BLACK);
+                    // 3.9 phases is the colorburst offset between the PPU clock and the decoder
+                    let angle = std::f32::consts::PI * (phase as f32 + 3.9) / 6.0 + hue_offset;
+                    y += level;
+                    i += level * angle.cos();
+                    q += level * angle.sin();
+                }
+                y /= 12.0;
+                i = i / 12.0 * saturation;
+                q = q / 12.0 * saturation;
+
+                let (r, g, b) = yiq_to_rgb(y, i, q);
+                colors[emphasis * PALETTE_COLOR_COUNT + index] =
+                    ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
+            }
+        }
+
+        Palette {
+            name: PalettePreset::GeneratedNtsc.name().to_string(),
+            colors,
+        }
+    }
+
+    /// Loads a standard .pal file, either 64 colors (192 bytes) or 512 colors with emphasis (1536 bytes)
+    pub fn load_pal_file(filename: &str) -> Result<Palette, String> {
+        let data = fs::read(filename)
+            .map_err(|e| format!("Failed to read palette {}: {}", filename, e))?;
+        let name = std::path::Path::new(filename)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| filename.to_string());
+
+        Palette::from_pal_bytes(&name, &data)
+    }
+
+    pub fn from_pal_bytes(name: &str, data: &[u8]) -> Result<Palette, String> {
+        let read_color = |i: usize| {
+            ((data[i * 3] as u32) << 16)
+                | ((data[i * 3 + 1] as u32) << 8)
+                | (data[i * 3 + 2] as u32)
+        };
+
+        match data.len() {
+            PAL_FILE_SIZE => {
+                let mut base = [0u32; PALETTE_COLOR_COUNT];
+                for (i, color) in base.iter_mut().enumerate() {
+                    *color = read_color(i);
+                }
+                Ok(Palette::from_base_colors(name, &base))
+            }
+            PAL_FILE_SIZE_WITH_EMPHASIS => {
+                let mut colors = [0u32; PALETTE_COLOR_COUNT * PALETTE_EMPHASIS_COUNT];
+                for (i, color) in colors.iter_mut().enumerate() {
+                    *color = read_color(i);
+                }
+                Ok(Palette {
+                    name: name.to_string(),
+                    colors,
+                })
+            }
+            size => Err(format!(
+                "Unsupported palette size {} bytes, expected {} or {}",
+                size, PAL_FILE_SIZE, PAL_FILE_SIZE_WITH_EMPHASIS
+            )),
+        }
+    }
+
+    /// Returns 0xRRGGBB for a 6-bit palette index and the 3 PPUMASK emphasis bits
+    #[inline(always)]
+    pub fn rgb(&self, color_index: u8, emphasis: u8) -> u32 {
+        self.colors
+            [((emphasis & 0x07) as usize) * PALETTE_COLOR_COUNT + (color_index & 0x3F) as usize]
+    }
+
+    /// Returns 0xRRGGBB for a frame buffer pixel (palette index + emphasis, see `Ppu::gbuffer`)
+    #[inline(always)]
+    pub fn rgb_for_pixel(&self, pixel: u16) -> u32 {
+        self.rgb(pixel as u8, (pixel >> PIXEL_EMPHASIS_SHIFT) as u8)
+    }
+
+    /// Converts a frame of palette indices into packed RGBA8, `rgba` must hold 4 bytes per pixel
+    pub fn frame_to_rgba(&self, frame: &[u16], rgba: &mut [u8]) {
+        for (pixel, out) in frame.iter().zip(rgba.chunks_exact_mut(4)) {
+            let color = self.rgb_for_pixel(*pixel);
+            out[0] = (color >> 16) as u8;
+            out[1] = (color >> 8) as u8;
+            out[2] = color as u8;
+            out[3] = 0xFF;
+        }
+    }
+}
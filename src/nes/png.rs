@@ -0,0 +1,73 @@
+use crate::nes::rom_database::crc32;
+use std::fs;
+
+/*
+Minimal PNG writer for screenshots, 8 bit RGBA. The image data goes in uncompressed deflate
+blocks, which every decoder reads, so there's no compressor to carry around. A 256x240 frame is
+about 240KB.
+*/
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+const MAX_STORED_BLOCK_SIZE: usize = 0xFFFF;
+
+/// `rgba` is `width * height` pixels, 4 bytes each
+pub fn write_png(path: &str, width: usize, height: usize, rgba: &[u8]) -> Result<(), String> {
+    let mut png = Vec::new();
+    png.extend_from_slice(&PNG_SIGNATURE);
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.push(8); // bit depth
+    header.push(6); // color type RGBA
+    header.push(0); // compression, deflate
+    header.push(0); // filter method
+    header.push(0); // no interlace
+    write_chunk(&mut png, b"IHDR", &header);
+
+    // Each row starts with its filter type, 0 is none
+    let mut raw = Vec::with_capacity(height * (width * 4 + 1));
+    for row in rgba.chunks_exact(width * 4).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+
+    fs::write(path, png).map_err(|e| format!("Failed to write {}: {}", path, e))
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    png.extend_from_slice(&crc32(&[&kind[..], data]).to_be_bytes());
+}
+
+// A zlib stream made of stored (uncompressed) deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_STORED_BLOCK_SIZE * 5 + 11);
+    out.extend_from_slice(&[0x78, 0x01]); // deflate, 32KB window, no dictionary
+
+    let mut blocks = data.chunks(MAX_STORED_BLOCK_SIZE).peekable();
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        out.push(last as u8); // BFINAL, BTYPE 00 stored
+        let length = block.len() as u16;
+        out.extend_from_slice(&length.to_le_bytes());
+        out.extend_from_slice(&(!length).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
@@ -1,19 +1,17 @@
+use crate::nes::mapper::PpuFetch;
 use crate::nes::rom::{Mirroring, Rom};
-use egui::{Color32, ColorImage};
-
-#[allow(dead_code)]
-pub static PALETTE_LOOKUP: [u32; 64] = [
-    0x545454, 0x001E74, 0x081090, 0x300088, 0x440064, 0x5C0030, 0x540400, 0x3C1800, 0x202A00,
-    0x083A00, 0x004000, 0x003C00, 0x00323C, 0x000000, 0x000000, 0x000000, 0x989698, 0x084CC4,
-    0x3032EC, 0x5C1EE4, 0x8814B0, 0xA01464, 0x982220, 0x783C00, 0x545A00, 0x287200, 0x087C00,
-    0x007628, 0x006678, 0x000000, 0x000000, 0x000000, 0xECEEEC, 0x4C9AEC, 0x787CEC, 0xB062EC,
-    0xE454EC, 0xEC58B4, 0xEC6A64, 0xD48820, 0xA0AA00, 0x74C400, 0x4CD020, 0x38CC6C, 0x38B4CC,
-    0x3C3C3C, 0x000000, 0x000000, 0xECEEEC, 0xA8CCEC, 0xBCBCEC, 0xD4B2EC, 0xECAEEC, 0xECAED4,
-    0xECB4B0, 0xE4C490, 0xCCD278, 0xB4DE78, 0xA8E290, 0x98E2B4, 0xA0D6E4, 0xA0A2A0, 0x000000,
-    0x000000,
-];
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+// Frame buffer pixels are the 6-bit palette index with the 3 PPUMASK emphasis bits above it
+pub const PIXEL_EMPHASIS_SHIFT: u16 = 6;
 
 const PPU_STATUS_VBLANK_BIT: u8 = 1 << 7;
+
+// https://www.nesdev.org/wiki/Open_bus_behavior#PPU_open_bus
+// Each bit of the I/O latch holds its value for roughly 600ms after it was last driven high.
+const IO_LATCH_DECAY_CYCLES: u64 = 5_369_318 * 6 / 10; // ~600ms of PPU cycles
 const PPU_CTRL_NMI_TRIGGER_BIT: u8 = 1 << 7;
 
 pub struct Ppu {
@@ -25,10 +23,21 @@ pub struct Ppu {
     address: u16,
     data: u8,
     data_buffer: u8,
+
+    // Internal data bus latch ("open bus"), returned for write-only registers and unused bits
+    io_latch: u8,
+    io_latch_refresh_cycle: [u64; 8], // PPU cycle each bit was last refreshed
+    cycle_count: u64,
     pub pixel: u16,
     pub scanline: u16,
     pub frame_counter: u16,
-    pub gbuffer: ColorImage,
+    pub gbuffer: [u16; SCREEN_WIDTH * SCREEN_HEIGHT],
+
+    // Debug layer toggles, these only affect the composed output pixel (sprite 0 hit/overflow
+    // are still computed from the real layers)
+    pub show_background_layer: bool,
+    pub show_sprite_layer: bool,
+    pub hidden_sprites: u64, // bit N hides OAM sprite N
     nmi_triggered: bool,
 
     // Loopy registers (PPU internal addressing)
@@ -56,6 +65,7 @@ pub struct Ppu {
     sprite_positions: [u8; 8],
     sprite_priorities: [u8; 8],
     sprite_indices: [u8; 8],
+    sprite_pattern_addresses: [u16; 8], // fetched during dots 257-320
 
     // Sprite 0 hit detection
     sprite_zero_being_rendered: bool,
@@ -64,9 +74,6 @@ pub struct Ppu {
     // OAM address
     oam_addr: u8,
 
-    // Mirroring mode
-    mirroring: Mirroring,
-
     // https://wiki.nesdev.com/w/index.php/PPU_memory_map
     // pattern table usually maps to rom CHR
     vram_bank_1: [u8; 0x400], //  Nametable Ram only 2k (room for 2 nametables mirrored, some roms have onboard memory for 4 tables)
@@ -85,10 +92,16 @@ impl Ppu {
             address: 0,
             data: 0,
             data_buffer: 0,
+            io_latch: 0,
+            io_latch_refresh_cycle: [0; 8],
+            cycle_count: 0,
             pixel: 0,
             scanline: 0,
             frame_counter: 0,
-            gbuffer: ColorImage::new([256usize, 240usize], Color32::BLACK),
+            gbuffer: [0x0F; SCREEN_WIDTH * SCREEN_HEIGHT],
+            show_background_layer: true,
+            show_sprite_layer: true,
+            hidden_sprites: 0,
             nmi_triggered: false,
             v: 0,
             t: 0,
@@ -108,10 +121,10 @@ impl Ppu {
             sprite_positions: [0; 8],
             sprite_priorities: [0; 8],
             sprite_indices: [0; 8],
+            sprite_pattern_addresses: [0; 8],
             sprite_zero_being_rendered: false,
             sprite_zero_hit_possible: false,
             oam_addr: 0,
-            mirroring: Mirroring::HORIZONTAL,
             vram_bank_1: [0; 0x400],
             vram_bank_2: [0; 0x400],
             palette_ram: [0; 0x20],
@@ -124,10 +137,6 @@ impl Ppu {
         return self.reg_status & PPU_STATUS_VBLANK_BIT != 0;
     }
 
-    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
-        self.mirroring = mirroring;
-    }
-
     pub fn cpuReadImmutable(&self, _rom: &Rom, register_num: u8) -> u8 {
         return match register_num {
             0 => self.read_PPUCTRL_Immutable(),
@@ -150,7 +159,7 @@ impl Ppu {
             1 => self.read_PPUMASK(),
             2 => self.read_PPUSTATUS(),
             3 => self.read_OAMADDR(),
-            4 => self.read_OAMDATA(),
+            4 => self.read_OAMDATA_refresh_latch(),
             5 => self.read_PPUSCROLL(),
             6 => self.read_PPUADDR(),
             7 => self.read_PPUDATA(rom),
@@ -160,7 +169,10 @@ impl Ppu {
         };
     }
 
-    pub fn cpuWrite(&mut self, _rom: &mut Rom, register_num: u8, value: u8) {
+    pub fn cpuWrite(&mut self, rom: &mut Rom, register_num: u8, value: u8) {
+        // Any write drives every bit of the latch
+        self.refresh_io_latch(value, 0xFF);
+
         match register_num {
             0 => self.write_PPUCTRL(value),
             1 => self.write_PPUMASK(value),
@@ -169,13 +181,35 @@ impl Ppu {
             4 => self.write_OAMDATA(value),
             5 => self.write_PPUSCROLL(value),
             6 => self.write_PPUADDR(value),
-            7 => self.write_PPUDATA(value),
+            7 => self.write_PPUDATA(rom, value),
             _ => {
                 panic!("We should never get here in the PPU addr={}", register_num);
             }
         }
     }
 
+    /// Current value of the I/O latch with decayed bits cleared
+    fn read_open_bus(&self) -> u8 {
+        let mut value = 0;
+        for bit in 0..8 {
+            let age = self.cycle_count - self.io_latch_refresh_cycle[bit];
+            if age < IO_LATCH_DECAY_CYCLES {
+                value |= self.io_latch & (1 << bit);
+            }
+        }
+        value
+    }
+
+    /// Puts `value` on the latch for the bits in `mask`, the other bits keep decaying
+    fn refresh_io_latch(&mut self, value: u8, mask: u8) {
+        self.io_latch = (self.read_open_bus() & !mask) | (value & mask);
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.io_latch_refresh_cycle[bit] = self.cycle_count;
+            }
+        }
+    }
+
     /// Public wrapper for PPU memory reads (for debugger/visualization)
     pub fn read_ppu_memory(&self, rom: &Rom, address: u16) -> u8 {
         self.ppuRead(rom, address)
@@ -190,6 +224,11 @@ impl Ppu {
             0x2000..=0x3EFF => {
                 let tmp_addr = address & 0xFFF;
 
+                // The cartridge can map its own memory over the nametables
+                if let Some(value) = rom.read_nametable(0x2000 | tmp_addr) {
+                    return value;
+                }
+
                 match rom.mirroring {
                     Mirroring::HORIZONTAL => {
                         if tmp_addr <= 0x7FF {
@@ -212,9 +251,15 @@ impl Ppu {
                         }
                     }
                     Mirroring::FourScreen => {
-                        // TODO implement, also need SINGLE SCREEN
+                        // TODO implement
                         return 0;
                     }
+                    Mirroring::SingleScreenLower => {
+                        return self.vram_bank_1[(tmp_addr & 0x3FF) as usize];
+                    }
+                    Mirroring::SingleScreenUpper => {
+                        return self.vram_bank_2[(tmp_addr & 0x3FF) as usize];
+                    }
                 }
             }
             0x3F00..=0x3FFF => {
@@ -237,15 +282,19 @@ impl Ppu {
         };
     }
 
-    fn ppuWrite(&mut self, address: u16, value: u8) {
+    fn ppuWrite(&mut self, rom: &mut Rom, address: u16, value: u8) {
         match address {
             0x0000..=0x1FFF => {
-                // CHR ROM - usually read-only for Mapper 0
+                // Ignored unless the cartridge has CHR RAM
+                rom.write_chr(address, value);
             }
             0x2000..=0x3EFF => {
                 let tmp_addr = address & 0xFFF;
+                if rom.write_nametable(0x2000 | tmp_addr, value) {
+                    return;
+                }
 
-                match self.mirroring {
+                match rom.mirroring {
                     Mirroring::HORIZONTAL => {
                         if tmp_addr <= 0x7FF {
                             self.vram_bank_1[(tmp_addr & 0x3FF) as usize] = value;
@@ -267,6 +316,12 @@ impl Ppu {
                     Mirroring::FourScreen => {
                         // TODO: Need 4KB VRAM
                     }
+                    Mirroring::SingleScreenLower => {
+                        self.vram_bank_1[(tmp_addr & 0x3FF) as usize] = value;
+                    }
+                    Mirroring::SingleScreenUpper => {
+                        self.vram_bank_2[(tmp_addr & 0x3FF) as usize] = value;
+                    }
                 }
             }
             0x3F00..=0x3FFF => {
@@ -284,7 +339,7 @@ impl Ppu {
     }
 
     fn read_PPUCTRL(&self) -> u8 {
-        return 0; // not readable
+        return self.read_open_bus(); // not readable
     }
 
     fn read_PPUCTRL_Immutable(&self) -> u8 {
@@ -292,7 +347,7 @@ impl Ppu {
     }
 
     fn read_PPUMASK(&self) -> u8 {
-        return 0; // not readable
+        return self.read_open_bus(); // not readable
     }
 
     fn read_PPUMASK_Immutable(&self) -> u8 {
@@ -302,8 +357,9 @@ impl Ppu {
     fn read_PPUSTATUS(&mut self) -> u8 {
         self.w = false; // Reset write latch
 
-        // last 5 bits are the last data bits written -- whack
-        let output = (self.reg_status & 0xE0) | (self.data_buffer & 0x1F);
+        // low 5 bits are not driven, they come from the I/O latch
+        let output = (self.reg_status & 0xE0) | (self.read_open_bus() & 0x1F);
+        self.refresh_io_latch(output, 0xE0);
 
         self.reg_status &= !PPU_STATUS_VBLANK_BIT;
         return output;
@@ -314,34 +370,59 @@ impl Ppu {
     }
 
     fn read_OAMADDR(&self) -> u8 {
-        return 0; // Not readable
+        return self.read_open_bus(); // Not readable
     }
 
     fn read_OAMDATA(&self) -> u8 {
         self.oam_ram[self.oam_addr as usize]
     }
 
+    fn read_OAMDATA_refresh_latch(&mut self) -> u8 {
+        let value = self.read_OAMDATA();
+        self.refresh_io_latch(value, 0xFF);
+        value
+    }
+
     fn read_PPUSCROLL(&self) -> u8 {
-        return 0; // Not Readable
+        return self.read_open_bus(); // Not Readable
     }
 
     fn read_PPUADDR(&self) -> u8 {
-        return 0; // Not Readable
+        return self.read_open_bus(); // Not Readable
     }
 
     fn read_PPUDATA(&mut self, rom: &Rom) -> u8 {
-        self.data = self.data_buffer;
-        self.data_buffer = self.ppuRead(rom, self.v);
-
-        // Palette reads are not buffered
-        if self.v >= 0x3F00 {
+        let address = self.v & 0x3FFF;
+        if address >= 0x3F00 {
+            // Palette reads are not buffered, the palette is only 6 bits wide so the top 2 bits
+            // are open bus. The buffer gets the nametable byte "underneath" the palette.
+            let palette_value = self.ppuRead(rom, address);
+            self.data = (self.read_open_bus() & 0xC0) | palette_value;
+            self.data_buffer = self.ppuRead(rom, address & 0x2FFF);
+            self.refresh_io_latch(self.data, 0x3F);
+        } else {
             self.data = self.data_buffer;
+            self.data_buffer = self.ppuRead(rom, address);
+            self.refresh_io_latch(self.data, 0xFF);
         }
 
-        self.v = self.v.wrapping_add(self.get_vram_increment());
+        self.increment_vram_address();
         return self.data;
     }
 
+    // https://www.nesdev.org/wiki/PPU_scrolling#$2007_reads_and_writes
+    // Outside of rendering v is incremented by 1 or 32, during rendering $2007 accesses glitch
+    // and trigger a coarse X increment and a Y increment at the same time.
+    fn increment_vram_address(&mut self) {
+        let rendering_scanline = self.scanline < 240 || self.scanline == 261;
+        if self.is_rendering_enabled() && rendering_scanline {
+            self.increment_scroll_x();
+            self.increment_scroll_y();
+        } else {
+            self.v = self.v.wrapping_add(self.get_vram_increment()) & 0x7FFF;
+        }
+    }
+
     fn read_PPUDATA_Immutable(&self) -> u8 {
         return 0; //debug only
     }
@@ -406,6 +487,10 @@ impl Ppu {
         (self.reg_mask & 0x01) != 0
     }
 
+    fn get_emphasis(&self) -> u8 {
+        (self.reg_mask >> 5) & 0x07
+    }
+
     // VRAM address manipulation (Loopy register updates)
     fn increment_scroll_x(&mut self) {
         if !self.is_rendering_enabled() {
@@ -482,15 +567,21 @@ impl Ppu {
         self.bg_palette_shift_high = (self.bg_palette_shift_high & 0xFF00) | palette_high;
     }
 
+    // Rendering reads, the cartridge is told what each one is for before it happens
+    fn fetch(&self, rom: &mut Rom, fetch: PpuFetch, address: u16) -> u8 {
+        rom.ppu_fetch(fetch, address);
+        self.ppuRead(rom, address)
+    }
+
     // Background tile fetching methods
-    fn fetch_nametable_byte(&self, rom: &Rom) -> u8 {
+    fn fetch_nametable_byte(&self, rom: &mut Rom) -> u8 {
         let addr = 0x2000 | (self.v & 0x0FFF);
-        self.ppuRead(rom, addr)
+        self.fetch(rom, PpuFetch::Nametable, addr)
     }
 
-    fn fetch_attribute_byte(&self, rom: &Rom) -> u8 {
+    fn fetch_attribute_byte(&self, rom: &mut Rom) -> u8 {
         let addr = 0x23C0 | (self.v & 0x0C00) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x07);
-        let attribute = self.ppuRead(rom, addr);
+        let attribute = self.fetch(rom, PpuFetch::Attribute, addr);
 
         // Determine which 2x2 tile quadrant (4 quadrants per attribute byte)
         let coarse_x = self.v & 0x1F;
@@ -501,16 +592,16 @@ impl Ppu {
         (attribute >> shift) & 0x03
     }
 
-    fn fetch_pattern_low(&self, rom: &Rom, tile_id: u8, fine_y: u8) -> u8 {
+    fn fetch_pattern_low(&self, rom: &mut Rom, tile_id: u8, fine_y: u8) -> u8 {
         let base = self.get_bg_pattern_table();
         let addr = base + ((tile_id as u16) << 4) + (fine_y as u16);
-        self.ppuRead(rom, addr)
+        self.fetch(rom, PpuFetch::BackgroundPattern, addr)
     }
 
-    fn fetch_pattern_high(&self, rom: &Rom, tile_id: u8, fine_y: u8) -> u8 {
+    fn fetch_pattern_high(&self, rom: &mut Rom, tile_id: u8, fine_y: u8) -> u8 {
         let base = self.get_bg_pattern_table();
         let addr = base + ((tile_id as u16) << 4) + (fine_y as u16) + 8;
-        self.ppuRead(rom, addr)
+        self.fetch(rom, PpuFetch::BackgroundPattern, addr)
     }
 
     // Pixel generation methods
@@ -538,7 +629,7 @@ impl Ppu {
         (pixel, palette)
     }
 
-    fn get_color_from_palette(&self, palette: u8, pixel: u8) -> u32 {
+    fn get_color_from_palette(&self, palette: u8, pixel: u8) -> u16 {
         let addr = if pixel == 0 {
             0x3F00 // Universal background color
         } else if palette < 4 {
@@ -549,7 +640,7 @@ impl Ppu {
             0x3F10 + (((palette - 4) as u16) << 2) + (pixel as u16)
         };
 
-        let mut palette_index = self.palette_ram[(addr & 0x1F) as usize];
+        let mut palette_index = self.palette_ram[(addr & 0x1F) as usize] & 0x3F;
 
         // Handle palette mirroring
         let mirrored_addr = addr & 0x1F;
@@ -558,21 +649,29 @@ impl Ppu {
             || mirrored_addr == 0x18
             || mirrored_addr == 0x1C
         {
-            palette_index = self.palette_ram[(mirrored_addr & 0x0F) as usize];
+            palette_index = self.palette_ram[(mirrored_addr & 0x0F) as usize] & 0x3F;
         }
 
         if self.is_greyscale() {
             palette_index &= 0x30;
         }
 
-        PALETTE_LOOKUP[(palette_index & 0x3F) as usize]
+        (palette_index as u16) | ((self.get_emphasis() as u16) << PIXEL_EMPHASIS_SHIFT)
     }
 
-    // Sprite evaluation (scanline N for scanline N+1)
-    fn evaluate_sprites(&mut self, rom: &Rom) {
+    // Sprite evaluation (scanline N for scanline N+1), the patterns are fetched afterwards
+    fn evaluate_sprites(&mut self) {
         self.sprite_count = 0;
         self.sprite_zero_being_rendered = false;
 
+        // Empty slots still fetch, tile $FF
+        let empty_slot_address = if self.get_sprite_size() == 16 {
+            0x1FF0
+        } else {
+            self.get_sprite_pattern_table() + 0xFF0
+        };
+        self.sprite_pattern_addresses = [empty_slot_address; 8];
+
         for i in 0..64 {
             let oam_offset = i * 4;
             let sprite_y = self.oam_ram[oam_offset] as u16;
@@ -613,17 +712,7 @@ impl Ppu {
                         table + tile + (if row >= 8 { row + 8 } else { row })
                     };
 
-                    let mut pattern_low = self.ppuRead(rom, pattern_addr);
-                    let mut pattern_high = self.ppuRead(rom, pattern_addr + 8);
-
-                    // Horizontal flip
-                    if attributes & 0x40 != 0 {
-                        pattern_low = pattern_low.reverse_bits();
-                        pattern_high = pattern_high.reverse_bits();
-                    }
-
-                    self.sprite_pattern_shift_low[self.sprite_count] = pattern_low;
-                    self.sprite_pattern_shift_high[self.sprite_count] = pattern_high;
+                    self.sprite_pattern_addresses[self.sprite_count] = pattern_addr;
                     self.sprite_positions[self.sprite_count] = sprite_x;
                     self.sprite_priorities[self.sprite_count] = attributes;
                     self.sprite_indices[self.sprite_count] = i as u8;
@@ -637,8 +726,27 @@ impl Ppu {
         }
     }
 
-    // Get sprite pixel for current position
-    fn get_sprite_pixel(&self) -> (u8, u8, u8, bool) {
+    // One of the eight sprite slots fetched in dots 257-320, 8 dots each
+    fn fetch_sprite_pattern(&mut self, rom: &mut Rom, slot: usize, high: bool) {
+        let address = self.sprite_pattern_addresses[slot] + if high { 8 } else { 0 };
+        let mut pattern = self.fetch(rom, PpuFetch::SpritePattern, address);
+        if slot >= self.sprite_count {
+            return;
+        }
+
+        // Horizontal flip
+        if self.sprite_priorities[slot] & 0x40 != 0 {
+            pattern = pattern.reverse_bits();
+        }
+        if high {
+            self.sprite_pattern_shift_high[slot] = pattern;
+        } else {
+            self.sprite_pattern_shift_low[slot] = pattern;
+        }
+    }
+
+    // Get sprite pixel for current position, sprites in `hidden_sprites` are skipped
+    fn get_sprite_pixel(&self, hidden_sprites: u64) -> (u8, u8, u8, bool) {
         if !self.show_sprites() {
             return (0, 0, 0, false);
         }
@@ -659,8 +767,9 @@ impl Ppu {
                 let p0 = ((self.sprite_pattern_shift_low[i] & bit_mux) != 0) as u8;
                 let p1 = ((self.sprite_pattern_shift_high[i] & bit_mux) != 0) as u8;
                 let pixel = (p1 << 1) | p0;
+                let hidden = hidden_sprites & (1u64 << self.sprite_indices[i]) != 0;
 
-                if pixel != 0 {
+                if pixel != 0 && !hidden {
                     let palette = (self.sprite_priorities[i] & 0x03) + 4;
                     let priority = (self.sprite_priorities[i] & 0x20) == 0;
                     let sprite_zero =
@@ -678,7 +787,6 @@ impl Ppu {
         self.reg_ctrl = value;
         // Update t with nametable select (bits 0-1 go to bits 10-11 of t)
         self.t = (self.t & 0xF3FF) | (((value as u16) & 0x03) << 10);
-        self.data_buffer = value;
     }
     fn write_PPUMASK(&mut self, value: u8) {
         self.reg_mask = value;
@@ -705,7 +813,6 @@ impl Ppu {
             self.t = (self.t & 0xFC1F) | (((value as u16) & 0xF8) << 2); // Coarse Y
             self.w = false;
         }
-        self.data_buffer = value;
     }
     fn write_PPUADDR(&mut self, value: u8) {
         if !self.w {
@@ -718,25 +825,24 @@ impl Ppu {
             self.v = self.t; // Copy t to v
             self.w = false;
         }
-        self.data_buffer = value;
     }
 
-    fn write_PPUDATA(&mut self, value: u8) {
-        self.ppuWrite(self.v, value);
-        self.v = self.v.wrapping_add(self.get_vram_increment());
-        self.data_buffer = value;
+    fn write_PPUDATA(&mut self, rom: &mut Rom, value: u8) {
+        self.ppuWrite(rom, self.v & 0x3FFF, value);
+        self.increment_vram_address();
     }
 
     #[allow(dead_code)]
-    fn get_color(&self, rom: &Rom, palette: u8, sprite_color_index: u8) -> u32 {
+    fn get_color(&self, rom: &Rom, palette: u8, sprite_color_index: u8) -> u16 {
         // 4 colors per palette, sprite_color_index indexes into the palette
-        return PALETTE_LOOKUP[(self.ppuRead(
+        let palette_index = self.ppuRead(
             rom,
             0x3F00 + ((palette as u16) << 2) + (sprite_color_index as u16),
-        ) & 0x3F) as usize];
+        ) & 0x3F;
+        (palette_index as u16) | ((self.get_emphasis() as u16) << PIXEL_EMPHASIS_SHIFT)
     }
 
-    pub fn tick(&mut self, rom: &Rom) {
+    pub fn tick(&mut self, rom: &mut Rom) {
         /*
         if self.scanline == 0 && self.pixel == 1 {
             println!(
@@ -775,7 +881,7 @@ impl Ppu {
 
                     // Get sprite pixel
                     let (sprite_pixel, sprite_palette, sprite_priority, is_sprite_zero) =
-                        self.get_sprite_pixel();
+                        self.get_sprite_pixel(0);
 
                     // Sprite 0 hit detection
                     if is_sprite_zero && bg_pixel > 0 && sprite_pixel > 0 {
@@ -792,6 +898,26 @@ impl Ppu {
                         }
                     }
 
+                    // Apply the debug layer toggles, hidden sprites let the sprites behind them show
+                    let (bg_pixel, bg_palette) = if self.show_background_layer {
+                        (bg_pixel, bg_palette)
+                    } else {
+                        (0, 0)
+                    };
+                    let (sprite_pixel, sprite_palette, sprite_priority, _) =
+                        if !self.show_sprite_layer {
+                            (0, 0, 0, false)
+                        } else if self.hidden_sprites != 0 {
+                            self.get_sprite_pixel(self.hidden_sprites)
+                        } else {
+                            (
+                                sprite_pixel,
+                                sprite_palette,
+                                sprite_priority,
+                                is_sprite_zero,
+                            )
+                        };
+
                     // Determine final pixel using priority rules
                     let (final_pixel, final_palette) = if bg_pixel == 0 && sprite_pixel == 0 {
                         (0, 0)
@@ -810,13 +936,8 @@ impl Ppu {
 
                     let color = self.get_color_from_palette(final_palette, final_pixel);
 
-                    let offset = ((self.pixel - 1) + self.scanline * 256) as usize;
-                    let rgb = [
-                        ((color >> 16) & 0xFF) as u8,
-                        ((color >> 8) & 0xFF) as u8,
-                        (color & 0xFF) as u8,
-                    ];
-                    self.gbuffer.pixels[offset] = Color32::from_rgb(rgb[0], rgb[1], rgb[2]);
+                    let offset = (self.pixel - 1) as usize + self.scanline as usize * SCREEN_WIDTH;
+                    self.gbuffer[offset] = color;
                 }
             }
         }
@@ -859,9 +980,24 @@ impl Ppu {
                 self.transfer_address_x();
                 // Sprite evaluation for next scanline (only on visible scanlines)
                 if self.scanline < 240 {
-                    self.evaluate_sprites(rom);
+                    self.evaluate_sprites();
+                }
+            }
+
+            // Sprite pattern fetches for the next scanline, low then high byte of each slot
+            if self.pixel >= 257 && self.pixel <= 320 {
+                let slot = ((self.pixel - 257) / 8) as usize;
+                match (self.pixel - 257) % 8 {
+                    4 => self.fetch_sprite_pattern(rom, slot, false),
+                    6 => self.fetch_sprite_pattern(rom, slot, true),
+                    _ => {}
                 }
             }
+
+            // Two unused nametable fetches end the scanline
+            if self.pixel == 337 || self.pixel == 339 {
+                self.fetch_nametable_byte(rom);
+            }
         }
 
         // Pre-render scanline (261)
@@ -886,6 +1022,7 @@ impl Ppu {
         }
 
         // Advance counters
+        self.cycle_count += 1;
         self.pixel += 1;
         if self.pixel > 340 {
             self.pixel = 0;
@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/*
+Single-producer/single-consumer lock-free ring buffer for audio samples.
+The APU (emulation thread) is the only producer and the cpal callback (audio thread) the only
+consumer, so neither side ever has to wait on the other. Samples are stored as f32 bits in atomics
+which keeps the whole thing free of unsafe code.
+
+`write_index` is only written by the producer and `read_index` only by the consumer. Both count up
+forever and are masked into the buffer, the difference between them is the fill level.
+*/
+
+pub struct AudioRingBuffer {
+    samples: Box<[AtomicU32]>,
+    mask: usize,
+    write_index: AtomicUsize,
+    read_index: AtomicUsize,
+}
+
+impl AudioRingBuffer {
+    /// Capacity is rounded up to a power of two
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.next_power_of_two();
+        Self {
+            samples: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+            mask: capacity - 1,
+            write_index: AtomicUsize::new(0),
+            read_index: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn available(&self) -> usize {
+        let write_index = self.write_index.load(Ordering::Acquire);
+        let read_index = self.read_index.load(Ordering::Acquire);
+        write_index.wrapping_sub(read_index)
+    }
+
+    /// Producer side. Returns how many samples fit, the rest are dropped.
+    pub fn push_slice(&self, samples: &[f32]) -> usize {
+        let write_index = self.write_index.load(Ordering::Relaxed);
+        let read_index = self.read_index.load(Ordering::Acquire);
+        let free = self.capacity() - write_index.wrapping_sub(read_index);
+        let count = samples.len().min(free);
+
+        for (i, sample) in samples[..count].iter().enumerate() {
+            let slot = write_index.wrapping_add(i) & self.mask;
+            self.samples[slot].store(sample.to_bits(), Ordering::Relaxed);
+        }
+
+        // Publishes the samples to the consumer
+        self.write_index
+            .store(write_index.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    /// Consumer side. Fills `out` from the front of the buffer, returns how many were read.
+    pub fn pop_slice(&self, out: &mut [f32]) -> usize {
+        let read_index = self.read_index.load(Ordering::Relaxed);
+        let write_index = self.write_index.load(Ordering::Acquire);
+        let available = write_index.wrapping_sub(read_index);
+        let count = out.len().min(available);
+
+        for (i, sample) in out[..count].iter_mut().enumerate() {
+            let slot = read_index.wrapping_add(i) & self.mask;
+            *sample = f32::from_bits(self.samples[slot].load(Ordering::Relaxed));
+        }
+
+        // Hands the slots back to the producer
+        self.read_index
+            .store(read_index.wrapping_add(count), Ordering::Release);
+        count
+    }
+}
@@ -1,3 +1,7 @@
+use crate::nes::expansion_audio::ExpansionAudio;
+use crate::nes::fds::{FDS_BIOS_SIZE, Fds};
+use crate::nes::mapper::{Mapper, Nrom, PpuFetch, create_mapper};
+use crate::nes::rom_database::{RomDatabase, crc32};
 use std::fs;
 use std::fs::File;
 use std::io::Read;
@@ -6,24 +10,24 @@ use std::str;
 const PRG_BANK_BANK_SIZE: u32 = 1 << 14; // 16384
 const CHR_BANK_BANK_SIZE: u32 = 1 << 13; // 8192
 
-const MAX_PRG_BANK_COUNT: u8 = 16; // 1MB
-const MAX_CHR_BANK_COUNT: u8 = 16; // 512KB
-
-const MAX_PRG_BANK_SIZE: u32 = PRG_BANK_BANK_SIZE * (MAX_PRG_BANK_COUNT as u32);
-const MAX_CHR_BANK_SIZE: u32 = CHR_BANK_BANK_SIZE * (MAX_CHR_BANK_COUNT as u32);
+// The largest boards we support, MMC5 banks up to 1MB of both
+const MAX_PRG_BANK_COUNT: u8 = 64; // 1MB
+const MAX_CHR_BANK_COUNT: u8 = 128; // 1MB
 
 #[derive(Debug, Copy, Clone)]
 pub enum Mirroring {
     HORIZONTAL,
     VERTICAL,
     FourScreen,
+    SingleScreenLower, // every nametable is the first 1KB of VRAM
+    SingleScreenUpper,
 }
 
 pub struct Rom {
     pub header: [u8; 16],
     pub trainer: [u8; 512],
-    prg: [u8; MAX_PRG_BANK_SIZE as usize],
-    chr: [u8; MAX_CHR_BANK_SIZE as usize],
+    prg: Vec<u8>,
+    chr: Vec<u8>,
     pub title: [u8; 128],
     pub title_size: u8,
 
@@ -33,6 +37,10 @@ pub struct Rom {
     pub has_battery_ram: bool,
     pub has_trainer: bool,
     pub mapper_number: u8,
+    pub submapper: u8, // 0 unless the header is NES 2.0 or the ROM database knows the game
+    pub pal_timing: bool,
+    chr_is_ram: bool,
+    mapper: Box<dyn Mapper>,
 }
 
 impl Rom {
@@ -40,8 +48,8 @@ impl Rom {
         Self {
             header: [0; 16],
             trainer: [0; 512],
-            prg: [0; MAX_PRG_BANK_SIZE as usize],
-            chr: [0; MAX_CHR_BANK_SIZE as usize],
+            prg: vec![0; PRG_BANK_BANK_SIZE as usize],
+            chr: vec![0; CHR_BANK_BANK_SIZE as usize],
             title: [0; 128],
             title_size: 0,
             prg_bank_count: 0,
@@ -49,32 +57,142 @@ impl Rom {
             has_trainer: false,
             has_battery_ram: false,
             mapper_number: 0,
+            submapper: 0,
+            pal_timing: false,
+            chr_is_ram: false,
             mirroring: Mirroring::VERTICAL,
+            mapper: Box::new(Nrom {}),
         }
     }
 
-    // todo implement mapper (for READ/WRITE PRG CHR)
+    fn prg_size(&self) -> usize {
+        (PRG_BANK_BANK_SIZE * self.prg_bank_count.max(1) as u32) as usize
+    }
+
+    fn chr_size(&self) -> usize {
+        (CHR_BANK_BANK_SIZE * self.chr_bank_count.max(1) as u32) as usize
+    }
+
     pub fn read_chr(&self, address: u16) -> u8 {
-        return self.chr[(address & ((CHR_BANK_BANK_SIZE as u16) - 1)) as usize];
+        self.chr[self.mapper.map_chr(address) % self.chr_size()]
+    }
+
+    /// PPU writes to $0000-$1FFF, ignored for CHR ROM
+    pub fn write_chr(&mut self, address: u16, value: u8) {
+        if self.chr_is_ram {
+            let offset = self.mapper.map_chr(address) % self.chr_size();
+            self.chr[offset] = value;
+        }
     }
 
     pub fn read_prg(&self, address: u16) -> u8 {
-        if self.prg_bank_count > 1 {
-            return self.prg[(address & 0x7FFF) as usize]; //32kb mask
-        } else {
-            return self.prg[(address & 0x3FFF) as usize]; //16kb mask
+        if let Some(value) = self.mapper.read_prg_ram(address) {
+            return value;
         }
+        self.prg[self.mapper.map_prg(address) % self.prg_size()]
     }
 
-    pub fn write_prg(&mut self, address: u16, value: u8) {
-        if self.prg_bank_count > 1 {
-            self.prg[(address & 0x7FFF) as usize] = value; //32kb mask
-        } else {
-            self.prg[(address & 0x3FFF) as usize] = value; //16kb mask
+    /// $6000-$7FFF, None when the work RAM is mapped there
+    pub fn read_prg_low(&self, address: u16) -> Option<u8> {
+        if let Some(value) = self.mapper.read_prg_ram(address) {
+            return Some(value);
         }
+        let offset = self.mapper.map_prg_low(address)?;
+        Some(self.prg[offset % self.prg_size()])
+    }
+
+    /// Cartridge reads of $4020-$5FFF
+    pub fn read_register(&mut self, address: u16) -> Option<u8> {
+        self.mapper.read_register(address)
+    }
+
+    /// False when the mapper has no RAM at the address, $6000-$7FFF is the work RAM then
+    pub fn write_prg_ram(&mut self, address: u16, value: u8) -> bool {
+        self.mapper.write_prg_ram(address, value)
+    }
+
+    /// CPU writes to $2000-$3FFF also reach the cartridge
+    pub fn write_ppu_register(&mut self, address: u16, value: u8) {
+        self.mapper.write_ppu_register(address, value);
+    }
+
+    /// The PPU is about to read `address` for rendering
+    pub fn ppu_fetch(&mut self, fetch: PpuFetch, address: u16) {
+        self.mapper.ppu_fetch(fetch, address);
+    }
+
+    /// $2000-$2FFF, None when the console VRAM is mapped there
+    pub fn read_nametable(&self, address: u16) -> Option<u8> {
+        self.mapper.read_nametable(address)
+    }
+
+    /// False when the console VRAM takes the write
+    pub fn write_nametable(&mut self, address: u16, value: u8) -> bool {
+        self.mapper.write_nametable(address, value)
+    }
+
+    // Writes to $4020-$5FFF and $8000-$FFFF reach the mapper registers, and the RAM in PRG space
+    // on boards that have some there
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        self.write_prg_ram(address, value);
+        self.mapper.write_register(address, value);
+        if let Some(mirroring) = self.mapper.mirroring() {
+            self.mirroring = mirroring;
+        }
+    }
+
+    /// Called once per CPU cycle
+    pub fn clock(&mut self) {
+        self.mapper.clock();
     }
 
-    pub fn load_rom(&mut self, filename: &String) {
+    pub fn irq_pending(&self) -> bool {
+        self.mapper.irq_pending()
+    }
+
+    pub fn expansion_audio(&self) -> Option<Box<dyn ExpansionAudio>> {
+        self.mapper.expansion_audio()
+    }
+
+    pub fn fds(&self) -> Option<&Fds> {
+        self.mapper.as_fds()
+    }
+
+    pub fn fds_mut(&mut self) -> Option<&mut Fds> {
+        self.mapper.as_fds_mut()
+    }
+
+    /// The Famicom Disk System RAM adapter with a disk image in the drive, the BIOS is a dump of
+    /// the console's own that we can't ship
+    pub fn load_fds(&mut self, filename: &str, bios_filename: &str) -> Result<(), String> {
+        println!("Loading disk: {}", filename);
+        let bios = fs::read(bios_filename)
+            .map_err(|e| format!("Failed to read the FDS BIOS {}: {}", bios_filename, e))?;
+        if bios.len() != FDS_BIOS_SIZE {
+            return Err(format!(
+                "FDS BIOS {} should be {} bytes, found {}",
+                bios_filename,
+                FDS_BIOS_SIZE,
+                bios.len()
+            ));
+        }
+        let fds = Fds::load(filename)?;
+
+        // The RAM adapter keeps its 32KB of RAM, the BIOS is the only ROM
+        self.prg = bios;
+        self.prg_bank_count = 1;
+        self.chr_bank_count = 1;
+        self.chr = vec![0; self.chr_size()];
+        self.chr_is_ram = true;
+        self.mapper_number = 20; // the iNES number reserved for the FDS
+        self.mapper = Box::new(fds);
+        if let Some(mirroring) = self.mapper.mirroring() {
+            self.mirroring = mirroring;
+        }
+        Ok(())
+    }
+
+    pub fn load_rom(&mut self, filename: &String, database: &RomDatabase) {
         let mut file_handle = File::open(&filename).expect("no file found");
         let meta_data = fs::metadata(&filename).expect("unable to read metadata");
         let _file_length = meta_data.len();
@@ -96,23 +214,23 @@ impl Rom {
         // Load PRG
         {
             let prg_total_bank_size = (PRG_BANK_BANK_SIZE * (self.prg_bank_count as u32)) as u64;
-            let mut buf = vec![0u8; prg_total_bank_size as usize];
-            file_handle.read_exact(&mut buf).expect("buffer overflow");
-            for i in 0..prg_total_bank_size {
-                self.prg[i as usize] = buf[i as usize];
-            }
+            self.prg = vec![0u8; self.prg_size()];
+            file_handle
+                .read_exact(&mut self.prg[..prg_total_bank_size as usize])
+                .expect("buffer overflow");
             println!("Loaded PRG ROM, size was {:#x}", prg_total_bank_size);
         }
 
         // Load CHR
         {
             let chr_total_bank_size = (CHR_BANK_BANK_SIZE * (self.chr_bank_count as u32)) as u64;
-            let mut buf = vec![0u8; chr_total_bank_size as usize];
-            file_handle.read_exact(&mut buf).expect("buffer overflow");
-            for i in 0..chr_total_bank_size {
-                self.chr[i as usize] = buf[i as usize];
-            }
+            self.chr = vec![0u8; self.chr_size()];
+            file_handle
+                .read_exact(&mut self.chr[..chr_total_bank_size as usize])
+                .expect("buffer overflow");
             println!("Loaded CHR ROM, size was {:#x}", chr_total_bank_size);
+            // No CHR ROM means the board has 8KB of CHR RAM instead
+            self.chr_is_ram = self.chr_bank_count == 0;
         }
 
         // Load Title
@@ -127,6 +245,13 @@ impl Rom {
             println!("Title bytes loaded: {}", self.title_size);
         }
 
+        self.identify_board(database);
+        self.mapper = create_mapper(self.mapper_number, self.submapper, self.prg_size())
+            .unwrap_or_else(|e| panic!("{}", e));
+        if let Some(mirroring) = self.mapper.mirroring() {
+            self.mirroring = mirroring;
+        }
+
         // Verify we are EOF
         {
             let mut buf = vec![0u8; 1024 as usize];
@@ -142,6 +267,25 @@ impl Rom {
         }
     }
 
+    // Old headers don't have a submapper and are often wrong about the mapper
+    fn identify_board(&mut self, database: &RomDatabase) {
+        if self.submapper != 0 {
+            return;
+        }
+        let prg_size = PRG_BANK_BANK_SIZE as usize * self.prg_bank_count as usize;
+        let chr_size = CHR_BANK_BANK_SIZE as usize * self.chr_bank_count as usize;
+        let crc = crc32(&[&self.prg[..prg_size], &self.chr[..chr_size]]);
+        println!("ROM CRC32: {:08X}", crc);
+        if let Some(info) = database.find(crc) {
+            println!(
+                "ROM database: mapper {} submapper {}",
+                info.mapper_number, info.submapper
+            );
+            self.mapper_number = info.mapper_number;
+            self.submapper = info.submapper;
+        }
+    }
+
     fn parse_header(&mut self) {
         // Parse NES marker
         let nes_header = str::from_utf8(&self.header[0..4]).unwrap();
@@ -206,8 +350,20 @@ impl Rom {
         self.has_trainer = f6_flags & (1 << 2) != 0;
         println!("Trainer: {:?}", self.has_trainer);
 
-        self.mapper_number = f6_flags >> 4;
+        // Flags 7 bits 4-7: upper nybble of mapper number
+        self.mapper_number = (self.header[7] & 0xF0) | (f6_flags >> 4);
         println!("Mapper number: {:?}", self.mapper_number);
+
+        // NES 2.0 is flagged by flags 7 bits 2-3 being 10, byte 8 bits 4-7 is the submapper
+        self.submapper = 0;
+        if self.header[7] & 0x0C == 0x08 {
+            self.submapper = self.header[8] >> 4;
+            println!("NES 2.0 submapper: {:?}", self.submapper);
+        }
+
+        // Flags 9 bit 0: TV system (0: NTSC; 1: PAL), rarely set but it is all iNES 1.0 gives us
+        self.pal_timing = self.header[9] & 0x01 != 0;
+        println!("PAL: {:?}", self.pal_timing);
     }
 
     /*
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::fs;
+
+/*
+ROM database, for boards an iNES 1.0 header can't describe.
+NES 2.0 headers carry a submapper, old dumps don't, so the board is looked up by the CRC32 of
+the PRG and CHR data (no header or trainer), the same checksum other emulators' databases use.
+One ROM per line, numbers after the checksum in decimal, lines starting with # are ignored:
+
+    # crc32   mapper  submapper
+    1A2B3C4D  23      2
+*/
+
+// Looked for in the working directory unless the rom_database global setting says otherwise
+pub const ROM_DATABASE_FILE: &str = "romdb.txt";
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RomInfo {
+    pub mapper_number: u8,
+    pub submapper: u8,
+}
+
+pub struct RomDatabase {
+    entries: HashMap<u32, RomInfo>,
+}
+
+impl RomDatabase {
+    /// A missing file gives an empty database, bad lines are skipped with a message
+    pub fn load(path: &str) -> RomDatabase {
+        let mut entries = HashMap::new();
+        if let Ok(contents) = fs::read_to_string(path) {
+            for (number, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match parse_line(line) {
+                    Some((crc, info)) => {
+                        entries.insert(crc, info);
+                    }
+                    None => println!("{}:{}: invalid ROM database entry", path, number + 1),
+                }
+            }
+        }
+
+        RomDatabase { entries }
+    }
+
+    pub fn find(&self, crc: u32) -> Option<RomInfo> {
+        self.entries.get(&crc).copied()
+    }
+}
+
+fn parse_line(line: &str) -> Option<(u32, RomInfo)> {
+    let mut fields = line.split_whitespace();
+    let crc = u32::from_str_radix(fields.next()?, 16).ok()?;
+    let mapper_number = fields.next()?.parse().ok()?;
+    let submapper = fields.next()?.parse().ok()?;
+    if fields.next().is_some() {
+        return None;
+    }
+    Some((
+        crc,
+        RomInfo {
+            mapper_number,
+            submapper,
+        },
+    ))
+}
+
+/// CRC-32 as used by zip, reflected polynomial 0xEDB88320
+pub fn crc32(chunks: &[&[u8]]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in chunks.iter().flat_map(|chunk| chunk.iter()) {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,477 @@
+/*
+CPU side post processing of the displayed frame, runs right before the texture upload:
+  1. optional pixel art scaler (Scale2x/3x, hq2x/3x, xBR) on the raw NES resolution frame
+  2. nearest neighbour integer scaling (+ linear horizontal resampling for 8:7 pixel aspect)
+  3. optional darkened scanlines
+*/
+
+// NES pixels are slightly wider than tall on an NTSC TV
+const PIXEL_ASPECT_RATIO: f32 = 8.0 / 7.0;
+
+pub const MAX_INTEGER_SCALE: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PixelScaler {
+    None,
+    Scale2x,
+    Scale3x,
+    Hq2x,
+    Hq3x,
+    Xbr2x,
+}
+
+impl PixelScaler {
+    pub const ALL: [PixelScaler; 6] = [
+        PixelScaler::None,
+        PixelScaler::Scale2x,
+        PixelScaler::Scale3x,
+        PixelScaler::Hq2x,
+        PixelScaler::Hq3x,
+        PixelScaler::Xbr2x,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PixelScaler::None => "None",
+            PixelScaler::Scale2x => "Scale2x",
+            PixelScaler::Scale3x => "Scale3x",
+            PixelScaler::Hq2x => "hq2x",
+            PixelScaler::Hq3x => "hq3x",
+            PixelScaler::Xbr2x => "xBR 2x",
+        }
+    }
+
+    pub fn factor(&self) -> usize {
+        match self {
+            PixelScaler::None => 1,
+            PixelScaler::Scale2x | PixelScaler::Hq2x | PixelScaler::Xbr2x => 2,
+            PixelScaler::Scale3x | PixelScaler::Hq3x => 3,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ScalerSettings {
+    pub scaler: PixelScaler,
+    pub scale: usize, // final integer scale, raised to the scaler factor if lower
+    pub scanlines: bool,
+    pub scanline_intensity: f32, // 0.0 (off) to 1.0 (black)
+    pub aspect_correction: bool,
+}
+
+impl ScalerSettings {
+    pub fn new() -> Self {
+        Self {
+            scaler: PixelScaler::None,
+            scale: 2,
+            scanlines: false,
+            scanline_intensity: 0.35,
+            aspect_correction: false,
+        }
+    }
+}
+
+pub struct PostProcessor {
+    pub settings: ScalerSettings,
+    pub output: Vec<u8>, // RGBA8
+    pub width: usize,
+    pub height: usize,
+
+    // Scratch buffers as 0xRRGGBB
+    source: Vec<u32>,
+    scaled: Vec<u32>,
+    resized: Vec<u32>,
+}
+
+impl PostProcessor {
+    pub fn new() -> Self {
+        Self {
+            settings: ScalerSettings::new(),
+            output: Vec::new(),
+            width: 0,
+            height: 0,
+            source: Vec::new(),
+            scaled: Vec::new(),
+            resized: Vec::new(),
+        }
+    }
+
+    /// `logical_width` is the frame width in NES pixels, wider frames (NTSC filter output) skip the
+    /// pixel art scaler and are resampled horizontally to the same final size
+    pub fn process(&mut self, rgba: &[u8], width: usize, height: usize, logical_width: usize) {
+        self.source.clear();
+        self.source.extend(
+            rgba.chunks_exact(4)
+                .map(|p| ((p[0] as u32) << 16) | ((p[1] as u32) << 8) | (p[2] as u32)),
+        );
+
+        let scaler = if width == logical_width {
+            self.settings.scaler
+        } else {
+            PixelScaler::None
+        };
+        let factor = scaler.factor();
+        let scale = self
+            .settings
+            .scale
+            .clamp(factor, MAX_INTEGER_SCALE.max(factor));
+
+        // 1. Pixel art scaler
+        let scaled_width = width * factor;
+        let scaled_height = height * factor;
+        self.scaled.clear();
+        self.scaled.resize(scaled_width * scaled_height, 0);
+        match scaler {
+            PixelScaler::None => self.scaled.copy_from_slice(&self.source),
+            PixelScaler::Scale2x => scale2x(&self.source, width, height, &mut self.scaled),
+            PixelScaler::Scale3x => scale3x(&self.source, width, height, &mut self.scaled),
+            PixelScaler::Hq2x => hqx(&self.source, width, height, 2, &mut self.scaled),
+            PixelScaler::Hq3x => hqx(&self.source, width, height, 3, &mut self.scaled),
+            PixelScaler::Xbr2x => xbr2x(&self.source, width, height, &mut self.scaled),
+        }
+
+        // 2. Integer scale + aspect ratio
+        let mut target_width = logical_width * scale;
+        if self.settings.aspect_correction {
+            target_width = (target_width as f32 * PIXEL_ASPECT_RATIO).round() as usize;
+        }
+        let target_height = height * scale;
+
+        self.resized.clear();
+        self.resized.resize(target_width * target_height, 0);
+        for y in 0..target_height {
+            let src_y = y * scaled_height / target_height;
+            let src_row = &self.scaled[src_y * scaled_width..(src_y + 1) * scaled_width];
+            let dst_row = &mut self.resized[y * target_width..(y + 1) * target_width];
+            resample_row(src_row, dst_row);
+        }
+
+        // 3. Scanlines, darken the last output row of every source line
+        if self.settings.scanlines && scale >= 2 {
+            let keep = 1.0 - self.settings.scanline_intensity.clamp(0.0, 1.0);
+            for y in (scale - 1..target_height).step_by(scale) {
+                for pixel in &mut self.resized[y * target_width..(y + 1) * target_width] {
+                    *pixel = scale_color(*pixel, keep);
+                }
+            }
+        }
+
+        self.width = target_width;
+        self.height = target_height;
+        self.output.resize(target_width * target_height * 4, 0);
+        for (pixel, out) in self.resized.iter().zip(self.output.chunks_exact_mut(4)) {
+            out[0] = (pixel >> 16) as u8;
+            out[1] = (pixel >> 8) as u8;
+            out[2] = *pixel as u8;
+            out[3] = 0xFF;
+        }
+    }
+}
+
+// Nearest neighbour when the ratio is an integer (keeps pixels crisp), linear otherwise
+fn resample_row(src: &[u32], dst: &mut [u32]) {
+    if dst.len().is_multiple_of(src.len()) {
+        let ratio = dst.len() / src.len();
+        for (x, pixel) in dst.iter_mut().enumerate() {
+            *pixel = src[x / ratio];
+        }
+        return;
+    }
+
+    let step = src.len() as f32 / dst.len() as f32;
+    for (x, pixel) in dst.iter_mut().enumerate() {
+        let pos = ((x as f32 + 0.5) * step - 0.5).max(0.0);
+        let left = (pos as usize).min(src.len() - 1);
+        let right = (left + 1).min(src.len() - 1);
+        let t = pos - left as f32;
+        *pixel = lerp_color(src[left], src[right], t);
+    }
+}
+
+fn channel(color: u32, shift: u32) -> f32 {
+    ((color >> shift) & 0xFF) as f32
+}
+
+fn pack_color(r: f32, g: f32, b: f32) -> u32 {
+    let c = |v: f32| v.round().clamp(0.0, 255.0) as u32;
+    (c(r) << 16) | (c(g) << 8) | c(b)
+}
+
+fn scale_color(color: u32, factor: f32) -> u32 {
+    lerp_color(0, color, factor)
+}
+
+// Fixed point per channel blend, this runs for every output pixel when resampling
+fn lerp_color(a: u32, b: u32, t: f32) -> u32 {
+    let wb = (t.clamp(0.0, 1.0) * 256.0) as u32;
+    let wa = 256 - wb;
+    let mix =
+        |shift: u32| ((((a >> shift) & 0xFF) * wa + ((b >> shift) & 0xFF) * wb) >> 8) << shift;
+    mix(16) | mix(8) | mix(0)
+}
+
+// Weighted average of colors, weights do not need to be normalized
+fn blend(colors: &[(u32, f32)]) -> u32 {
+    let total: f32 = colors.iter().map(|(_, w)| w).sum();
+    let mut r = 0.0;
+    let mut g = 0.0;
+    let mut b = 0.0;
+    for (color, weight) in colors {
+        r += channel(*color, 16) * weight;
+        g += channel(*color, 8) * weight;
+        b += channel(*color, 0) * weight;
+    }
+    pack_color(r / total, g / total, b / total)
+}
+
+// Pixel fetch with the edges clamped
+fn fetch(src: &[u32], width: usize, height: usize, x: isize, y: isize) -> u32 {
+    let x = x.clamp(0, width as isize - 1) as usize;
+    let y = y.clamp(0, height as isize - 1) as usize;
+    src[y * width + x]
+}
+
+// 3x3 neighbourhood, A B C / D E F / G H I
+fn neighbours(src: &[u32], width: usize, height: usize, x: usize, y: usize) -> [u32; 9] {
+    let mut n = [0u32; 9];
+    for dy in 0..3 {
+        for dx in 0..3 {
+            n[dy * 3 + dx] = fetch(
+                src,
+                width,
+                height,
+                x as isize + dx as isize - 1,
+                y as isize + dy as isize - 1,
+            );
+        }
+    }
+    n
+}
+
+// https://www.scale2x.it/algorithm
+fn scale2x(src: &[u32], width: usize, height: usize, dst: &mut [u32]) {
+    let dst_width = width * 2;
+    for y in 0..height {
+        for x in 0..width {
+            let [_, b, _, d, e, f, _, h, _] = neighbours(src, width, height, x, y);
+            let mut out = [e; 4];
+            if b != h && d != f {
+                out[0] = if d == b { d } else { e };
+                out[1] = if b == f { f } else { e };
+                out[2] = if d == h { d } else { e };
+                out[3] = if h == f { f } else { e };
+            }
+            let base = y * 2 * dst_width + x * 2;
+            dst[base] = out[0];
+            dst[base + 1] = out[1];
+            dst[base + dst_width] = out[2];
+            dst[base + dst_width + 1] = out[3];
+        }
+    }
+}
+
+fn scale3x(src: &[u32], width: usize, height: usize, dst: &mut [u32]) {
+    let dst_width = width * 3;
+    for y in 0..height {
+        for x in 0..width {
+            let [a, b, c, d, e, f, g, h, i] = neighbours(src, width, height, x, y);
+            let mut out = [e; 9];
+            if b != h && d != f {
+                out[0] = if d == b { d } else { e };
+                out[1] = if (d == b && e != c) || (b == f && e != a) {
+                    b
+                } else {
+                    e
+                };
+                out[2] = if b == f { f } else { e };
+                out[3] = if (d == b && e != g) || (d == h && e != a) {
+                    d
+                } else {
+                    e
+                };
+                out[5] = if (b == f && e != i) || (h == f && e != c) {
+                    f
+                } else {
+                    e
+                };
+                out[6] = if d == h { d } else { e };
+                out[7] = if (d == h && e != i) || (h == f && e != g) {
+                    h
+                } else {
+                    e
+                };
+                out[8] = if h == f { f } else { e };
+            }
+            for row in 0..3 {
+                let base = (y * 3 + row) * dst_width + x * 3;
+                dst[base..base + 3].copy_from_slice(&out[row * 3..row * 3 + 3]);
+            }
+        }
+    }
+}
+
+fn to_yuv(color: u32) -> (f32, f32, f32) {
+    let r = channel(color, 16);
+    let g = channel(color, 8);
+    let b = channel(color, 0);
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let u = -0.169 * r - 0.331 * g + 0.5 * b + 128.0;
+    let v = 0.5 * r - 0.419 * g - 0.081 * b + 128.0;
+    (y, u, v)
+}
+
+// hqx considers two colors different when any YUV component is past its threshold
+fn hq_diff(a: u32, b: u32) -> bool {
+    if a == b {
+        return false;
+    }
+    let (y1, u1, v1) = to_yuv(a);
+    let (y2, u2, v2) = to_yuv(b);
+    (y1 - y2).abs() > 48.0 || (u1 - u2).abs() > 7.0 || (v1 - v2).abs() > 6.0
+}
+
+// Corner of an hqx output block, `side1`/`side2` are the orthogonal neighbours touching that corner
+fn hq_corner(e: u32, side1: u32, side2: u32, diagonal: u32) -> u32 {
+    if !hq_diff(side1, side2) && hq_diff(e, side1) {
+        if !hq_diff(diagonal, side1) {
+            // Solid edge running across the corner
+            blend(&[(e, 2.0), (side1, 3.0), (side2, 3.0)])
+        } else {
+            blend(&[(e, 2.0), (side1, 1.0), (side2, 1.0)])
+        }
+    } else if hq_diff(e, diagonal) {
+        blend(&[(e, 3.0), (diagonal, 1.0)])
+    } else {
+        e
+    }
+}
+
+// Middle of an hqx output block edge, `side` is the neighbour on that edge
+fn hq_edge(e: u32, side: u32, across1: u32, across2: u32) -> u32 {
+    if hq_diff(e, side) && (!hq_diff(side, across1) || !hq_diff(side, across2)) {
+        blend(&[(e, 3.0), (side, 1.0)])
+    } else {
+        e
+    }
+}
+
+/*
+hq2x / hq3x (Maxim Stepin). Uses the same YUV thresholds and interpolation weights as the reference,
+but derives each output sub-pixel from the neighbours around it instead of the 256 entry pattern table.
+*/
+fn hqx(src: &[u32], width: usize, height: usize, factor: usize, dst: &mut [u32]) {
+    let dst_width = width * factor;
+    for y in 0..height {
+        for x in 0..width {
+            let [a, b, c, d, e, f, g, h, i] = neighbours(src, width, height, x, y);
+            let top_left = hq_corner(e, d, b, a);
+            let top_right = hq_corner(e, b, f, c);
+            let bottom_left = hq_corner(e, h, d, g);
+            let bottom_right = hq_corner(e, f, h, i);
+
+            let out: [u32; 9] = if factor == 2 {
+                [
+                    top_left,
+                    top_right,
+                    bottom_left,
+                    bottom_right,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                ]
+            } else {
+                [
+                    top_left,
+                    hq_edge(e, b, d, f),
+                    top_right,
+                    hq_edge(e, d, b, h),
+                    e,
+                    hq_edge(e, f, b, h),
+                    bottom_left,
+                    hq_edge(e, h, d, f),
+                    bottom_right,
+                ]
+            };
+
+            for row in 0..factor {
+                let base = (y * factor + row) * dst_width + x * factor;
+                dst[base..base + factor].copy_from_slice(&out[row * factor..(row + 1) * factor]);
+            }
+        }
+    }
+}
+
+// xBR uses a weighted YUV distance
+fn xbr_distance(a: u32, b: u32) -> f32 {
+    if a == b {
+        return 0.0;
+    }
+    let (y1, u1, v1) = to_yuv(a);
+    let (y2, u2, v2) = to_yuv(b);
+    48.0 * (y1 - y2).abs() + 7.0 * (u1 - u2).abs() + 6.0 * (v1 - v2).abs()
+}
+
+/*
+xBR level 1 (Hyllian) at 2x. For every corner we compare the edge strength along the two diagonals of
+a 5x5 window; when the corner diagonal is the weaker edge the corner gets blended toward the closer
+of its two orthogonal neighbours.
+*/
+fn xbr2x(src: &[u32], width: usize, height: usize, dst: &mut [u32]) {
+    let dst_width = width * 2;
+    for y in 0..height {
+        for x in 0..width {
+            let e = src[y * width + x];
+            let mut out = [e; 4];
+
+            // (dx, dy) direction of each output corner: top-left, top-right, bottom-left, bottom-right
+            for (corner, (sx, sy)) in [(-1isize, -1isize), (1, -1), (-1, 1), (1, 1)]
+                .into_iter()
+                .enumerate()
+            {
+                // Rotate the 5x5 window so the corner being computed is always bottom-right
+                let p = |u: isize, v: isize| {
+                    fetch(src, width, height, x as isize + u * sx, y as isize + v * sy)
+                };
+                let f = p(1, 0);
+                let h = p(0, 1);
+                let i = p(1, 1);
+                let c = p(1, -1);
+                let g = p(-1, 1);
+                let b = p(0, -1);
+                let d = p(-1, 0);
+                let f4 = p(2, 0);
+                let h5 = p(0, 2);
+                let i4 = p(2, 1);
+                let i5 = p(1, 2);
+
+                let d_edge = xbr_distance(e, c)
+                    + xbr_distance(e, g)
+                    + xbr_distance(i, f4)
+                    + xbr_distance(i, h5)
+                    + 4.0 * xbr_distance(h, f);
+                let i_edge = xbr_distance(h, d)
+                    + xbr_distance(h, i5)
+                    + xbr_distance(f, i4)
+                    + xbr_distance(f, b)
+                    + 4.0 * xbr_distance(e, i);
+
+                if d_edge < i_edge && e != f && e != h {
+                    let new_color = if xbr_distance(e, f) <= xbr_distance(e, h) {
+                        f
+                    } else {
+                        h
+                    };
+                    out[corner] = lerp_color(e, new_color, 0.5);
+                }
+            }
+
+            let base = y * 2 * dst_width + x * 2;
+            dst[base] = out[0];
+            dst[base + 1] = out[1];
+            dst[base + dst_width] = out[2];
+            dst[base + dst_width + 1] = out[3];
+        }
+    }
+}
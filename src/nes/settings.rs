@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/*
+Very small persisted settings store, one `key=value` per line, lines starting with # are ignored.
+Global settings live in the working directory, per ROM settings in a sidecar file next to the ROM.
+*/
+
+pub const GLOBAL_SETTINGS_FILE: &str = "rustynes.ini";
+
+pub struct Settings {
+    path: String,
+    values: BTreeMap<String, String>,
+}
+
+impl Settings {
+    /// Loads the settings file, a missing or unreadable file just gives empty settings
+    pub fn load(path: &str) -> Settings {
+        let mut values = BTreeMap::new();
+        if let Ok(contents) = fs::read_to_string(path) {
+            for line in contents.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                if let Some((key, value)) = line.split_once('=') {
+                    values.insert(key.trim().to_string(), value.trim().to_string());
+                }
+            }
+        }
+
+        Settings {
+            path: path.to_string(),
+            values,
+        }
+    }
+
+    pub fn load_global() -> Settings {
+        Settings::load(GLOBAL_SETTINGS_FILE)
+    }
+
+    /// Settings stored in a sidecar file next to the ROM, e.g. `game.nes` -> `game.ini`
+    pub fn load_for_rom(rom_filename: &str) -> Settings {
+        let path = Path::new(rom_filename).with_extension("ini");
+        Settings::load(&path.to_string_lossy())
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let mut contents = String::new();
+        for (key, value) in &self.values {
+            contents.push_str(&format!("{}={}\n", key, value));
+        }
+        fs::write(&self.path, contents)
+            .map_err(|e| format!("Failed to save settings {}: {}", self.path, e))
+    }
+
+    pub fn get<T: FromStr>(&self, key: &str, default: T) -> T {
+        self.values
+            .get(key)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    }
+
+    pub fn set<T: ToString>(&mut self, key: &str, value: T) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+}
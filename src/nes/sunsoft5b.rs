@@ -0,0 +1,373 @@
+use crate::nes::apu::APU_PULSE_LEVEL;
+use crate::nes::expansion_audio::ExpansionAudio;
+use crate::nes::mapper::Mapper;
+use crate::nes::rom::Mirroring;
+
+/*
+Sunsoft FME-7, mapper 69, https://www.nesdev.org/wiki/Sunsoft_FME-7
+A command/parameter register pair selects 8KB PRG banks (including ROM at $6000), 1KB CHR banks,
+mirroring and a 16 bit CPU cycle IRQ counter.
+
+The Sunsoft 5B is the same mapper with a YM2149 (AY-3-8910 clone) inside, used by Gimmick!.
+Three square channels with shared noise and envelope generators and a logarithmic volume,
+https://www.nesdev.org/wiki/Sunsoft_5B_audio
+*/
+
+const PRG_BANK_8K: usize = 0x2000;
+const CHR_BANK_1K: usize = 0x400;
+
+pub struct Fme7 {
+    prg_size: usize,
+    command: u8,
+    chr_banks: [u8; 8],
+    prg_bank_6000: u8,  // bit 6 selects RAM, bit 7 enables it
+    prg_banks: [u8; 3], // $8000, $A000, $C000, $E000 is fixed to the last bank
+    mirroring: Mirroring,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Fme7 {
+    pub fn new(prg_size: usize) -> Self {
+        Self {
+            prg_size,
+            command: 0,
+            chr_banks: [0; 8],
+            prg_bank_6000: 0,
+            prg_banks: [0; 3],
+            mirroring: Mirroring::VERTICAL,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    fn write_parameter(&mut self, value: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = value,
+            0x8 => self.prg_bank_6000 = value,
+            0x9..=0xB => self.prg_banks[(self.command - 0x9) as usize] = value & 0x3F,
+            0xC => {
+                self.mirroring = match value & 0x03 {
+                    0 => Mirroring::VERTICAL,
+                    1 => Mirroring::HORIZONTAL,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                }
+            }
+            0xD => {
+                self.irq_enabled = value & 0x01 != 0;
+                self.irq_counter_enabled = value & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | value as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | (value as u16) << 8,
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn map_prg(&self, address: u16) -> usize {
+        let offset = (address & 0x1FFF) as usize;
+        match address {
+            0x8000..=0xDFFF => {
+                let bank = self.prg_banks[((address - 0x8000) / 0x2000) as usize];
+                bank as usize * PRG_BANK_8K + offset
+            }
+            _ => self.prg_size - PRG_BANK_8K + offset,
+        }
+    }
+
+    fn map_chr(&self, address: u16) -> usize {
+        let bank = self.chr_banks[((address >> 10) & 0x07) as usize];
+        bank as usize * CHR_BANK_1K + (address & 0x3FF) as usize
+    }
+
+    fn map_prg_low(&self, address: u16) -> Option<usize> {
+        if self.prg_bank_6000 & 0x40 != 0 {
+            return None; // work RAM
+        }
+        let bank = (self.prg_bank_6000 & 0x3F) as usize;
+        Some(bank * PRG_BANK_8K + (address & 0x1FFF) as usize)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0x9FFF => self.command = value & 0x0F,
+            0xA000..=0xBFFF => self.write_parameter(value),
+            _ => {} // $C000 and $E000 are the audio
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn clock(&mut self) {
+        if !self.irq_counter_enabled {
+            return;
+        }
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+        if self.irq_counter == 0xFFFF && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    // Only the 5B has the audio, but no game writes to it on a plain FME-7
+    fn expansion_audio(&self) -> Option<Box<dyn ExpansionAudio>> {
+        Some(Box::new(Sunsoft5bAudio::new()))
+    }
+}
+
+// The chip runs at half the CPU clock and divides by 16 again for the tone and noise
+const TONE_CLOCK_DIVIDER: u16 = 16;
+const NOISE_CLOCK_DIVIDER: u16 = 32;
+// 32 envelope steps in the time the AY takes for 16
+const ENVELOPE_CLOCK_DIVIDER: u32 = 16;
+
+// 1.5dB per step, 0 is silent. Register volumes use every other step.
+const VOLUME_TABLE: [f32; 32] = build_volume_table();
+
+const fn build_volume_table() -> [f32; 32] {
+    let mut table = [0.0; 32];
+    let mut step = 1;
+    let mut level = 1.0;
+    // Walk down from full volume, const fn can't call powf
+    while step < 32 {
+        table[32 - step] = level;
+        level /= 1.188_502_2; // 10^(1.5/20)
+        step += 1;
+    }
+    table
+}
+
+// Roughly where Gimmick! sits against the APU, a full volume channel like a full volume pulse
+const SUNSOFT_5B_LEVEL: f32 = APU_PULSE_LEVEL;
+
+struct ToneChannel {
+    period: u16,
+    counter: u16,
+    output: bool,
+    volume: u8,
+    use_envelope: bool,
+    tone_disabled: bool,
+    noise_disabled: bool,
+}
+
+impl ToneChannel {
+    fn new() -> Self {
+        Self {
+            period: 0,
+            counter: 0,
+            output: false,
+            volume: 0,
+            use_envelope: false,
+            tone_disabled: true,
+            noise_disabled: true,
+        }
+    }
+
+    fn clock(&mut self) {
+        self.counter += 1;
+        if self.counter >= self.period.max(1) * TONE_CLOCK_DIVIDER {
+            self.counter = 0;
+            self.output = !self.output;
+        }
+    }
+
+    // 5 bit level, register volumes map onto every other step
+    fn level(&self, envelope_level: u8) -> u8 {
+        if self.use_envelope {
+            envelope_level
+        } else if self.volume == 0 {
+            0
+        } else {
+            self.volume * 2 + 1
+        }
+    }
+}
+
+pub struct Sunsoft5bAudio {
+    register_select: u8,
+    write_enabled: bool, // the upper bits of $C000 have to be clear
+    channels: [ToneChannel; 3],
+
+    noise_period: u8,
+    noise_counter: u16,
+    noise_lfsr: u32,
+
+    envelope_period: u16,
+    envelope_counter: u32,
+    envelope_step: u8, // 0-31
+    envelope_continue: bool,
+    envelope_attack: bool,
+    envelope_alternate: bool,
+    envelope_hold: bool,
+    envelope_holding: bool,
+}
+
+impl Sunsoft5bAudio {
+    pub fn new() -> Self {
+        Self {
+            register_select: 0,
+            write_enabled: true,
+            channels: [ToneChannel::new(), ToneChannel::new(), ToneChannel::new()],
+            noise_period: 0,
+            noise_counter: 0,
+            noise_lfsr: 1,
+            envelope_period: 0,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_continue: false,
+            envelope_attack: false,
+            envelope_alternate: false,
+            envelope_hold: false,
+            envelope_holding: true,
+        }
+    }
+
+    fn write_data(&mut self, value: u8) {
+        match self.register_select {
+            register @ 0x0..=0x5 => {
+                let channel = &mut self.channels[(register / 2) as usize];
+                if register.is_multiple_of(2) {
+                    channel.period = (channel.period & 0x0F00) | value as u16;
+                } else {
+                    channel.period = (channel.period & 0x00FF) | ((value & 0x0F) as u16) << 8;
+                }
+            }
+            0x6 => self.noise_period = value & 0x1F,
+            0x7 => {
+                for (i, channel) in self.channels.iter_mut().enumerate() {
+                    channel.tone_disabled = value & (1 << i) != 0;
+                    channel.noise_disabled = value & (1 << (i + 3)) != 0;
+                }
+            }
+            register @ 0x8..=0xA => {
+                let channel = &mut self.channels[(register - 0x8) as usize];
+                channel.volume = value & 0x0F;
+                channel.use_envelope = value & 0x10 != 0;
+            }
+            0xB => self.envelope_period = (self.envelope_period & 0xFF00) | value as u16,
+            0xC => self.envelope_period = (self.envelope_period & 0x00FF) | (value as u16) << 8,
+            0xD => {
+                // Restarts the envelope
+                self.envelope_continue = value & 0x08 != 0;
+                self.envelope_attack = value & 0x04 != 0;
+                self.envelope_alternate = value & 0x02 != 0;
+                self.envelope_hold = value & 0x01 != 0;
+                self.envelope_step = 0;
+                self.envelope_counter = 0;
+                self.envelope_holding = false;
+            }
+            _ => {} // I/O ports, not connected
+        }
+    }
+
+    fn clock_noise(&mut self) {
+        self.noise_counter += 1;
+        if self.noise_counter >= (self.noise_period.max(1) as u16) * NOISE_CLOCK_DIVIDER {
+            self.noise_counter = 0;
+            // 17 bit LFSR with taps at bits 0 and 3
+            let feedback = (self.noise_lfsr ^ (self.noise_lfsr >> 3)) & 0x01;
+            self.noise_lfsr = (self.noise_lfsr >> 1) | (feedback << 16);
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+
+        self.envelope_counter += 1;
+        if self.envelope_counter < self.envelope_period.max(1) as u32 * ENVELOPE_CLOCK_DIVIDER {
+            return;
+        }
+        self.envelope_counter = 0;
+
+        if self.envelope_step < 31 {
+            self.envelope_step += 1;
+            return;
+        }
+
+        // End of a ramp
+        if !self.envelope_continue {
+            // Shapes 0-7 drop to 0 and stay there
+            self.envelope_attack = false;
+            self.envelope_holding = true;
+        } else if self.envelope_hold {
+            if self.envelope_alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_holding = true;
+        } else {
+            if self.envelope_alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_step = 0;
+        }
+    }
+
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_holding {
+            // The level the ramp ended on, or 0 after a shape without continue
+            return if self.envelope_attack { 31 } else { 0 };
+        }
+        if self.envelope_attack {
+            self.envelope_step
+        } else {
+            31 - self.envelope_step
+        }
+    }
+}
+
+impl ExpansionAudio for Sunsoft5bAudio {
+    fn name(&self) -> &'static str {
+        "Sunsoft 5B"
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xC000..=0xDFFF => {
+                self.register_select = value & 0x0F;
+                self.write_enabled = value & 0xF0 == 0;
+            }
+            0xE000..=0xFFFF if self.write_enabled => self.write_data(value),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        for channel in self.channels.iter_mut() {
+            channel.clock();
+        }
+        self.clock_noise();
+        self.clock_envelope();
+    }
+
+    fn output(&self) -> f32 {
+        let noise = self.noise_lfsr & 0x01 != 0;
+        let envelope_level = self.envelope_level();
+
+        let sum: f32 = self
+            .channels
+            .iter()
+            .filter(|channel| {
+                (channel.output || channel.tone_disabled) && (noise || channel.noise_disabled)
+            })
+            .map(|channel| VOLUME_TABLE[channel.level(envelope_level) as usize])
+            .sum();
+        sum * SUNSOFT_5B_LEVEL
+    }
+}
@@ -0,0 +1,195 @@
+use crate::nes::mapper::Mapper;
+use crate::nes::rom::Mirroring;
+use crate::nes::vrc_irq::VrcIrq;
+
+/*
+Konami VRC2 and VRC4, mappers 21, 22, 23 and 25, https://www.nesdev.org/wiki/VRC2_and_VRC4
+The chips have the same registers at $x000-$x003. The boards wire different CPU address lines
+to the two register select pins, so that's what the mapper numbers actually tell apart:
+
+    mapper  submapper  chip    register bits 0/1
+    21      1          VRC4a   A1 A2
+    21      2          VRC4c   A6 A7
+    22      0          VRC2a   A1 A0    CHR bank bit 0 isn't connected
+    23      1          VRC4f   A0 A1
+    23      2          VRC4e   A2 A3
+    23      3          VRC2b   A0 A1
+    25      1          VRC4b   A1 A0
+    25      2          VRC4d   A3 A2
+    25      3          VRC2c   A1 A0
+
+iNES 1.0 headers have no submapper. When the ROM database doesn't know the game either, the
+register is decoded with both wirings of the mapper ORed together, which works because games
+only write to addresses that are valid for their own board. That is treated as a VRC4, the
+VRC2 games on those mappers never touch the IRQ registers.
+
+The VRC2 has no IRQ, one bit of mirroring and 8 bit CHR banks. The VRC4 adds the VRC IRQ, single
+screen mirroring, a PRG swap mode and 9 bit CHR banks.
+*/
+
+const PRG_BANK_8K: usize = 0x2000;
+const CHR_BANK_1K: usize = 0x400;
+
+// CPU address lines connected to the register select pins
+#[derive(Copy, Clone)]
+struct Wiring {
+    bit0: u16,
+    bit1: u16,
+}
+
+const A0_A1: Wiring = Wiring {
+    bit0: 0x01,
+    bit1: 0x02,
+};
+const A1_A0: Wiring = Wiring {
+    bit0: 0x02,
+    bit1: 0x01,
+};
+const A1_A2: Wiring = Wiring {
+    bit0: 0x02,
+    bit1: 0x04,
+};
+const A2_A3: Wiring = Wiring {
+    bit0: 0x04,
+    bit1: 0x08,
+};
+const A3_A2: Wiring = Wiring {
+    bit0: 0x08,
+    bit1: 0x04,
+};
+const A6_A7: Wiring = Wiring {
+    bit0: 0x40,
+    bit1: 0x80,
+};
+
+impl Wiring {
+    fn or(self, other: Wiring) -> Wiring {
+        Wiring {
+            bit0: self.bit0 | other.bit0,
+            bit1: self.bit1 | other.bit1,
+        }
+    }
+
+    // $x000-$x003
+    fn register_address(self, address: u16) -> u16 {
+        let bit0 = (address & self.bit0 != 0) as u16;
+        let bit1 = (address & self.bit1 != 0) as u16;
+        (address & 0xF000) | (bit1 << 1) | bit0
+    }
+}
+
+pub struct Vrc4 {
+    wiring: Wiring,
+    vrc2: bool,
+    chr_bank_shift: u8, // VRC2a drops the low CHR bank bit
+    prg_size: usize,
+    prg_banks: [u8; 2],
+    prg_swap_mode: bool, // the first bank goes to $C000 and $8000 is fixed
+    chr_banks: [u16; 8],
+    mirroring: Mirroring,
+    irq: VrcIrq,
+}
+
+impl Vrc4 {
+    pub fn new(mapper_number: u8, submapper: u8, prg_size: usize) -> Self {
+        let (wiring, vrc2) = match (mapper_number, submapper) {
+            (21, 1) => (A1_A2, false),
+            (21, 2) => (A6_A7, false),
+            (21, _) => (A1_A2.or(A6_A7), false),
+            (22, _) => (A1_A0, true),
+            (23, 1) => (A0_A1, false),
+            (23, 2) => (A2_A3, false),
+            (23, 3) => (A0_A1, true),
+            (23, _) => (A0_A1.or(A2_A3), false),
+            (_, 1) => (A1_A0, false),
+            (_, 2) => (A3_A2, false),
+            (_, 3) => (A1_A0, true),
+            _ => (A1_A0.or(A3_A2), false),
+        };
+
+        Self {
+            wiring,
+            vrc2,
+            chr_bank_shift: if mapper_number == 22 { 1 } else { 0 },
+            prg_size,
+            prg_banks: [0; 2],
+            prg_swap_mode: false,
+            chr_banks: [0; 8],
+            mirroring: Mirroring::VERTICAL,
+            irq: VrcIrq::new(),
+        }
+    }
+
+    fn write_mirroring(&mut self, value: u8) {
+        let mask = if self.vrc2 { 0x01 } else { 0x03 };
+        self.mirroring = match value & mask {
+            0 => Mirroring::VERTICAL,
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        };
+    }
+
+    // $B000-$E003, each 1KB bank takes its low and high nibble from two registers
+    fn write_chr_bank(&mut self, register: u16, value: u8) {
+        let index = (((register - 0xB000) >> 12) * 2 + ((register >> 1) & 0x01)) as usize;
+        let bank = &mut self.chr_banks[index];
+        if register & 0x01 == 0 {
+            *bank = (*bank & 0x1F0) | (value & 0x0F) as u16;
+        } else {
+            let high_mask = if self.vrc2 { 0x0F } else { 0x1F };
+            *bank = (*bank & 0x0F) | (((value & high_mask) as u16) << 4);
+        }
+    }
+}
+
+impl Mapper for Vrc4 {
+    fn map_prg(&self, address: u16) -> usize {
+        let second_last = self.prg_size / PRG_BANK_8K - 2;
+        let bank = match (address, self.prg_swap_mode) {
+            (0x8000..=0x9FFF, false) | (0xC000..=0xDFFF, true) => self.prg_banks[0] as usize,
+            (0xA000..=0xBFFF, _) => self.prg_banks[1] as usize,
+            (0xE000..=0xFFFF, _) => second_last + 1,
+            _ => second_last,
+        };
+        bank * PRG_BANK_8K + (address & 0x1FFF) as usize
+    }
+
+    fn map_chr(&self, address: u16) -> usize {
+        let bank = self.chr_banks[(address >> 10) as usize & 0x07] >> self.chr_bank_shift;
+        bank as usize * CHR_BANK_1K + (address & 0x3FF) as usize
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        if address < 0x8000 {
+            return;
+        }
+
+        match self.wiring.register_address(address) {
+            0x8000..=0x8003 => self.prg_banks[0] = value & 0x1F,
+            0x9000..=0x9003 if self.vrc2 => self.write_mirroring(value),
+            0x9000..=0x9001 => self.write_mirroring(value),
+            0x9002..=0x9003 => self.prg_swap_mode = value & 0x02 != 0,
+            0xA000..=0xA003 => self.prg_banks[1] = value & 0x1F,
+            register @ 0xB000..=0xE003 => self.write_chr_bank(register, value),
+            _ if self.vrc2 => {}
+            0xF000 => self.irq.write_latch_low(value),
+            0xF001 => self.irq.write_latch_high(value),
+            0xF002 => self.irq.write_control(value),
+            0xF003 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn clock(&mut self) {
+        self.irq.clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending()
+    }
+}
@@ -0,0 +1,353 @@
+use crate::nes::apu::APU_PULSE_LEVEL;
+use crate::nes::expansion_audio::ExpansionAudio;
+use crate::nes::mapper::Mapper;
+use crate::nes::rom::Mirroring;
+use crate::nes::vrc_irq::VrcIrq;
+
+/*
+Konami VRC6, mappers 24 and 26, https://www.nesdev.org/wiki/VRC6
+Akumajou Densetsu is mapper 24, Madara and Esper Dream 2 are mapper 26 which has the A0 and A1
+address lines swapped. Registers are decoded as $x000-$x003 after undoing the swap.
+
+The audio is two pulse channels with 8 duty settings and a sawtooth, https://www.nesdev.org/wiki/VRC6_audio
+*/
+
+const PRG_BANK_16K: usize = 0x4000;
+const PRG_BANK_8K: usize = 0x2000;
+const CHR_BANK_1K: usize = 0x400;
+
+// Swaps A0/A1 for mapper 26 and drops the mirrored address bits
+fn register_address(address: u16, swapped_lines: bool) -> u16 {
+    let address = address & 0xF003;
+    if swapped_lines {
+        (address & 0xF000) | ((address & 0x01) << 1) | ((address & 0x02) >> 1)
+    } else {
+        address
+    }
+}
+
+pub struct Vrc6 {
+    swapped_lines: bool,
+    prg_size: usize,
+    prg_bank_16k: u8, // $8000-$BFFF
+    prg_bank_8k: u8,  // $C000-$DFFF, $E000 is fixed to the last bank
+    chr_banks: [u8; 8],
+    banking_mode: u8, // $B003
+    mirroring: Mirroring,
+    irq: VrcIrq,
+}
+
+impl Vrc6 {
+    pub fn new(prg_size: usize, swapped_lines: bool) -> Self {
+        Self {
+            swapped_lines,
+            prg_size,
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_banks: [0; 8],
+            banking_mode: 0,
+            mirroring: Mirroring::VERTICAL,
+            irq: VrcIrq::new(),
+        }
+    }
+
+    fn write_banking_mode(&mut self, value: u8) {
+        self.banking_mode = value;
+        self.mirroring = match (value >> 2) & 0x03 {
+            0 => Mirroring::VERTICAL,
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        };
+    }
+
+    // 1KB CHR bank for an address. Modes 1-3 use 2KB banks for some of the pattern tables, where
+    // the lowest bank bit comes from either the register or PPU A10.
+    fn chr_bank(&self, address: u16) -> u8 {
+        let slot = ((address >> 10) & 0x07) as usize;
+        let two_kb_slot = match self.banking_mode & 0x03 {
+            0 => None,
+            1 => Some(slot / 2),
+            _ if slot < 4 => None,
+            _ => Some(4 + (slot - 4) / 2),
+        };
+
+        match two_kb_slot {
+            None => self.chr_banks[slot],
+            Some(register) => {
+                let bank = self.chr_banks[register];
+                if self.banking_mode & 0x20 != 0 {
+                    (bank & 0xFE) | (slot as u8 & 0x01)
+                } else {
+                    bank
+                }
+            }
+        }
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn map_prg(&self, address: u16) -> usize {
+        let offset = (address & 0x1FFF) as usize;
+        match address {
+            0x8000..=0xBFFF => {
+                self.prg_bank_16k as usize * PRG_BANK_16K + (address & 0x3FFF) as usize
+            }
+            0xC000..=0xDFFF => self.prg_bank_8k as usize * PRG_BANK_8K + offset,
+            _ => self.prg_size - PRG_BANK_8K + offset,
+        }
+    }
+
+    fn map_chr(&self, address: u16) -> usize {
+        self.chr_bank(address) as usize * CHR_BANK_1K + (address & 0x3FF) as usize
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match register_address(address, self.swapped_lines) {
+            0x8000..=0x8003 => self.prg_bank_16k = value & 0x0F,
+            0xB003 => self.write_banking_mode(value),
+            0xC000..=0xC003 => self.prg_bank_8k = value & 0x1F,
+            register @ 0xD000..=0xD003 => self.chr_banks[(register & 0x03) as usize] = value,
+            register @ 0xE000..=0xE003 => self.chr_banks[4 + (register & 0x03) as usize] = value,
+            0xF000 => self.irq.write_latch(value),
+            0xF001 => self.irq.write_control(value),
+            0xF002 => self.irq.acknowledge(),
+            _ => {} // $9000-$B002 is the audio, the APU gets those writes
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn clock(&mut self) {
+        self.irq.clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn expansion_audio(&self) -> Option<Box<dyn ExpansionAudio>> {
+        Some(Box::new(Vrc6Audio::new(self.swapped_lines)))
+    }
+}
+
+// 12 bit period, $x001 holds the low 8 bits and $x002 the enable flag and high 4 bits
+#[derive(Copy, Clone)]
+struct Vrc6Timer {
+    enabled: bool,
+    period: u16,
+    counter: u16,
+}
+
+impl Vrc6Timer {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            period: 0,
+            counter: 0,
+        }
+    }
+
+    fn write_period_low(&mut self, value: u8) {
+        self.period = (self.period & 0x0F00) | value as u16;
+    }
+
+    fn write_period_high(&mut self, value: u8) {
+        self.period = (self.period & 0x00FF) | (((value & 0x0F) as u16) << 8);
+        self.enabled = value & 0x80 != 0;
+    }
+
+    // True when the counter reloads, `shift` is the $9003 frequency scaling
+    fn clock(&mut self, shift: u8) -> bool {
+        if self.counter == 0 {
+            self.counter = self.period >> shift;
+            true
+        } else {
+            self.counter -= 1;
+            false
+        }
+    }
+}
+
+struct Vrc6Pulse {
+    timer: Vrc6Timer,
+    volume: u8,
+    duty: u8,
+    ignore_duty: bool, // constant output at the volume, used for PCM-like effects
+    step: u8,          // counts down 15 to 0
+}
+
+impl Vrc6Pulse {
+    fn new() -> Self {
+        Self {
+            timer: Vrc6Timer::new(),
+            volume: 0,
+            duty: 0,
+            ignore_duty: false,
+            step: 15,
+        }
+    }
+
+    fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.ignore_duty = value & 0x80 != 0;
+                self.duty = (value >> 4) & 0x07;
+                self.volume = value & 0x0F;
+            }
+            1 => self.timer.write_period_low(value),
+            2 => {
+                self.timer.write_period_high(value);
+                if !self.timer.enabled {
+                    self.step = 15;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if self.timer.enabled && self.timer.clock(shift) {
+            self.step = self.step.wrapping_sub(1) & 0x0F;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.timer.enabled && (self.ignore_duty || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+struct Vrc6Sawtooth {
+    timer: Vrc6Timer,
+    rate: u8,
+    accumulator: u8,
+    step: u8, // 0-13, the rate is added on every other step and step 14 resets
+}
+
+impl Vrc6Sawtooth {
+    fn new() -> Self {
+        Self {
+            timer: Vrc6Timer::new(),
+            rate: 0,
+            accumulator: 0,
+            step: 0,
+        }
+    }
+
+    fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => self.rate = value & 0x3F,
+            1 => self.timer.write_period_low(value),
+            2 => {
+                self.timer.write_period_high(value);
+                if !self.timer.enabled {
+                    self.accumulator = 0;
+                    self.step = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.timer.enabled || !self.timer.clock(shift) {
+            return;
+        }
+
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    // Top 5 bits of the accumulator, 0-31
+    fn output(&self) -> u8 {
+        if self.timer.enabled {
+            self.accumulator >> 3
+        } else {
+            0
+        }
+    }
+}
+
+pub struct Vrc6Audio {
+    swapped_lines: bool,
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    sawtooth: Vrc6Sawtooth,
+    halt: bool,
+    frequency_shift: u8,
+}
+
+impl Vrc6Audio {
+    pub fn new(swapped_lines: bool) -> Self {
+        Self {
+            swapped_lines,
+            pulse1: Vrc6Pulse::new(),
+            pulse2: Vrc6Pulse::new(),
+            sawtooth: Vrc6Sawtooth::new(),
+            halt: false,
+            frequency_shift: 0,
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc6Audio {
+    fn name(&self) -> &'static str {
+        "VRC6"
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.swapped_lines);
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        if address < 0x8000 {
+            return;
+        }
+
+        let address = register_address(address, self.swapped_lines);
+        let register = address & 0x03;
+        match address {
+            0x9003 => {
+                // Frequency control, bit 2 takes priority over bit 1
+                self.halt = value & 0x01 != 0;
+                self.frequency_shift = if value & 0x04 != 0 {
+                    8
+                } else if value & 0x02 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0x9000..=0x9002 => self.pulse1.write_register(register, value),
+            0xA000..=0xA002 => self.pulse2.write_register(register, value),
+            0xB000..=0xB002 => self.sawtooth.write_register(register, value),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.pulse1.clock(self.frequency_shift);
+        self.pulse2.clock(self.frequency_shift);
+        self.sawtooth.clock(self.frequency_shift);
+    }
+
+    // The pulses at full volume match a full volume 2A03 pulse, the sawtooth peaks at twice that
+    fn output(&self) -> f32 {
+        let sum = self.pulse1.output() + self.pulse2.output() + self.sawtooth.output();
+        sum as f32 * (APU_PULSE_LEVEL / 15.0)
+    }
+}
@@ -0,0 +1,92 @@
+/*
+Konami VRC IRQ counter, https://www.nesdev.org/wiki/VRC_IRQ
+Used by the VRC4, VRC6 and VRC7. An 8 bit counter counts up from a reloadable latch and raises
+the IRQ when it overflows, either every CPU cycle or once per scanline. There's no scanline
+input, a prescaler counts CPU cycles in PPU dots instead.
+*/
+
+// 341 PPU dots per scanline in steps of 3 per CPU cycle
+const PRESCALER_RELOAD: i16 = 341;
+
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enabled_after_ack: bool,
+    cycle_mode: bool, // counts every CPU cycle instead of every scanline
+    pending: bool,
+}
+
+impl VrcIrq {
+    pub fn new() -> Self {
+        Self {
+            latch: 0,
+            counter: 0,
+            prescaler: PRESCALER_RELOAD,
+            enabled: false,
+            enabled_after_ack: false,
+            cycle_mode: false,
+            pending: false,
+        }
+    }
+
+    pub fn write_latch(&mut self, value: u8) {
+        self.latch = value;
+    }
+
+    // The VRC4 takes the latch a nibble at a time
+    pub fn write_latch_low(&mut self, value: u8) {
+        self.latch = (self.latch & 0xF0) | (value & 0x0F);
+    }
+
+    pub fn write_latch_high(&mut self, value: u8) {
+        self.latch = (self.latch & 0x0F) | (value << 4);
+    }
+
+    pub fn write_control(&mut self, value: u8) {
+        self.enabled_after_ack = value & 0x01 != 0;
+        self.enabled = value & 0x02 != 0;
+        self.cycle_mode = value & 0x04 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER_RELOAD;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enabled_after_ack;
+    }
+
+    /// Called once per CPU cycle
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.cycle_mode {
+            self.clock_counter();
+        } else {
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += PRESCALER_RELOAD;
+                self.clock_counter();
+            }
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+}
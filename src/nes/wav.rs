@@ -0,0 +1,143 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+/*
+Minimal mono WAV writer. The header is written up front with empty sizes which get patched in
+when the recording is finished.
+*/
+
+const WAV_HEADER_SIZE: u32 = 44;
+
+// The RIFF sizes are 32 bit, the whole file has to stay under 4GB
+const MAX_DATA_SIZE: u32 = u32::MAX - (WAV_HEADER_SIZE - 8);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WavSampleFormat {
+    Pcm16,
+    Float32,
+}
+
+impl WavSampleFormat {
+    pub const ALL: [WavSampleFormat; 2] = [WavSampleFormat::Pcm16, WavSampleFormat::Float32];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WavSampleFormat::Pcm16 => "16-bit PCM",
+            WavSampleFormat::Float32 => "32-bit float",
+        }
+    }
+
+    fn bytes_per_sample(&self) -> u32 {
+        match self {
+            WavSampleFormat::Pcm16 => 2,
+            WavSampleFormat::Float32 => 4,
+        }
+    }
+
+    // WAVE_FORMAT_PCM or WAVE_FORMAT_IEEE_FLOAT
+    fn format_tag(&self) -> u16 {
+        match self {
+            WavSampleFormat::Pcm16 => 1,
+            WavSampleFormat::Float32 => 3,
+        }
+    }
+}
+
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    path: String,
+    format: WavSampleFormat,
+    data_size: u32,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create(path: &str, sample_rate: u32, format: WavSampleFormat) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+        let mut wav = Self {
+            writer: BufWriter::new(file),
+            path: path.to_string(),
+            format,
+            data_size: 0,
+            finished: false,
+        };
+        wav.write_header(sample_rate)?;
+        Ok(wav)
+    }
+
+    fn write_header(&mut self, sample_rate: u32) -> Result<(), String> {
+        let bytes_per_sample = self.format.bytes_per_sample();
+        let mut header = Vec::with_capacity(WAV_HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&0u32.to_le_bytes()); // patched in finish()
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&self.format.format_tag().to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // mono
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * bytes_per_sample).to_le_bytes());
+        header.extend_from_slice(&(bytes_per_sample as u16).to_le_bytes());
+        header.extend_from_slice(&((bytes_per_sample * 8) as u16).to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes()); // patched in finish()
+
+        self.writer
+            .write_all(&header)
+            .map_err(|e| format!("Failed to write {}: {}", self.path, e))
+    }
+
+    /// Samples are -1.0 to 1.0. Fails without writing anything once the file would pass 4GB
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<(), String> {
+        let size = samples.len() as u64 * self.format.bytes_per_sample() as u64;
+        if self.data_size as u64 + size > MAX_DATA_SIZE as u64 {
+            return Err(format!(
+                "{} reached the 4GB WAV size limit, recording stopped",
+                self.path
+            ));
+        }
+
+        for sample in samples {
+            let result = match self.format {
+                WavSampleFormat::Pcm16 => {
+                    let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                    self.writer.write_all(&value.to_le_bytes())
+                }
+                WavSampleFormat::Float32 => self.writer.write_all(&sample.to_le_bytes()),
+            };
+            result.map_err(|e| format!("Failed to write {}: {}", self.path, e))?;
+        }
+        self.data_size += size as u32;
+        Ok(())
+    }
+
+    /// Patches the chunk sizes into the header and flushes the file
+    pub fn finish(mut self) -> Result<(), String> {
+        self.write_sizes()
+    }
+
+    fn write_sizes(&mut self) -> Result<(), String> {
+        self.finished = true;
+        let path = self.path.clone();
+        let error = |e: std::io::Error| format!("Failed to finish {}: {}", path, e);
+
+        self.writer.seek(SeekFrom::Start(4)).map_err(error)?;
+        self.writer
+            .write_all(&(WAV_HEADER_SIZE - 8 + self.data_size).to_le_bytes())
+            .map_err(error)?;
+        self.writer.seek(SeekFrom::Start(40)).map_err(error)?;
+        self.writer
+            .write_all(&self.data_size.to_le_bytes())
+            .map_err(error)?;
+        self.writer.flush().map_err(error)
+    }
+}
+
+// Still leaves a playable file if the recording is never stopped, e.g. the app is closed
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.write_sizes();
+        }
+    }
+}
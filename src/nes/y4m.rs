@@ -0,0 +1,79 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/*
+Minimal YUV4MPEG2 (.y4m) writer for video recordings. It's raw 4:4:4 frames behind a one line
+header, so nothing gets lost to chroma subsampling and ffmpeg, mpv and VLC read it directly:
+
+    ffmpeg -i recording.y4m -i recording.wav -c:v libx264 -crf 0 recording.mp4
+
+Pixels are converted to BT.601 limited range YCbCr, which is what players assume for y4m.
+*/
+
+pub struct Y4mWriter {
+    writer: BufWriter<File>,
+    path: String,
+    pub width: usize,
+    pub height: usize,
+    plane: Vec<u8>,
+}
+
+impl Y4mWriter {
+    /// `frame_rate` is in frames per second, it's stored as a fraction of 1/1000ths
+    pub fn create(
+        path: &str,
+        width: usize,
+        height: usize,
+        frame_rate: f64,
+    ) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+        let mut y4m = Self {
+            writer: BufWriter::new(file),
+            path: path.to_string(),
+            width,
+            height,
+            plane: Vec::with_capacity(width * height * 3),
+        };
+        let header = format!(
+            "YUV4MPEG2 W{} H{} F{}:1000 Ip A1:1 C444\n",
+            width,
+            height,
+            (frame_rate * 1000.0).round() as u32
+        );
+        y4m.writer
+            .write_all(header.as_bytes())
+            .map_err(|e| format!("Failed to write {}: {}", y4m.path, e))?;
+        Ok(y4m)
+    }
+
+    /// `rgba` must be the size the recording was started with
+    pub fn write_frame(&mut self, rgba: &[u8], width: usize, height: usize) -> Result<(), String> {
+        if width != self.width || height != self.height {
+            return Err(format!(
+                "Recording {} stopped, the picture changed size from {}x{} to {}x{}",
+                self.path, self.width, self.height, width, height
+            ));
+        }
+
+        // Planar, all of Y then Cb then Cr
+        let pixels = width * height;
+        self.plane.resize(pixels * 3, 0);
+        for (i, pixel) in rgba.chunks_exact(4).take(pixels).enumerate() {
+            let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+            self.plane[i] = (16.0 + (65.481 * r + 128.553 * g + 24.966 * b) / 255.0) as u8;
+            self.plane[pixels + i] = (128.0 + (-37.797 * r - 74.203 * g + 112.0 * b) / 255.0) as u8;
+            self.plane[pixels * 2 + i] =
+                (128.0 + (112.0 * r - 93.786 * g - 18.214 * b) / 255.0) as u8;
+        }
+
+        let error = |e: std::io::Error| format!("Failed to write {}: {}", self.path, e);
+        self.writer.write_all(b"FRAME\n").map_err(error)?;
+        self.writer.write_all(&self.plane).map_err(error)
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        self.writer
+            .flush()
+            .map_err(|e| format!("Failed to finish {}: {}", self.path, e))
+    }
+}